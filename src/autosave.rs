@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use crate::cart::Cart;
+
+// How often cart RAM gets flushed while running, in frames. About five seconds of emulated time
+pub const AUTOSAVE_INTERVAL_FRAMES: u64 = 300;
+
+// Keeps the .sav file caught up with cart RAM, so a crash only loses the last few seconds of progress.
+// Nothing gets written unless the game has touched RAM since the last flush
+pub struct Autosave {
+    path: PathBuf,
+    interval_frames: u64,
+    last_flush_frame: u64
+}
+
+impl Autosave {
+    pub fn new(path: PathBuf, interval_frames: u64) -> Autosave {
+        Autosave {
+            path,
+            interval_frames,
+            last_flush_frame: 0
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Call once a frame with the frame count, flushes when the interval has gone by
+    pub fn on_frame(&mut self, frame: u64, cart: &mut Cart) -> std::io::Result<bool> {
+        if frame.saturating_sub(self.last_flush_frame) < self.interval_frames {
+            return Ok(false)
+        }
+        self.last_flush_frame = frame;
+        self.flush(cart)
+    }
+
+    // Writes RAM out if it has changed, reporting whether it did. A failed write leaves RAM dirty so the next flush tries again
+    pub fn flush(&mut self, cart: &mut Cart) -> std::io::Result<bool> {
+        if !cart.ram_dirty() {
+            return Ok(false)
+        }
+        std::fs::write(&self.path, cart.ram())?;
+        cart.mark_ram_saved();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::gameboy::Gameboy;
+    use crate::input::Joypad;
+    use crate::memory_gb::{Byte, MemoryMap, MemoryRegion};
+
+    // MBC1 with 8KiB of battery RAM, running the given program
+    fn battery_cart(program: &[Byte]) -> Cart {
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        rom[0x0100..(0x0100 + program.len())].copy_from_slice(program);
+        Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid")
+    }
+
+    fn save_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gbzd_{}_{}.sav", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn ram_writes_are_flushed_once_the_interval_passes() {
+        let path = save_path("autosave_interval");
+        let mut cart = battery_cart(&[]);
+        let mut autosave = Autosave::new(path.clone(), 10);
+        // Writes with RAM disabled never reach the chip, so there's nothing to save
+        cart.write(0x42 as Byte, 0xA000);
+        assert!(!cart.ram_dirty());
+        assert!(!autosave.flush(&mut cart).expect("Nothing to write"));
+        assert!(!path.exists());
+
+        cart.write(0x0A as Byte, 0x0000);
+        cart.write(0x42 as Byte, 0xA001);
+        assert!(cart.ram_dirty());
+        assert!(!autosave.on_frame(5, &mut cart).expect("Too early to write"));
+        assert!(cart.ram_dirty());
+        assert!(autosave.on_frame(10, &mut cart).expect("Save file should be writable"));
+        assert!(!cart.ram_dirty());
+        let saved = std::fs::read(&path).expect("Save file should have been written");
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!(saved[1], 0x42);
+        // Clean RAM isn't written again
+        std::fs::remove_file(&path).expect("Save file should be removable");
+        assert!(!autosave.on_frame(30, &mut cart).expect("Nothing to write"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn shutdown_flushes_ram_written_since_the_last_autosave() {
+        let path = save_path("autosave_shutdown");
+        // LD A,0x0A; LD (0x0000),A; LD A,0x5A; LD (0xA000),A; JR -2
+        let mut memory_data = MemoryMap::allocate(battery_cart(&[0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x3E, 0x5A, 0xEA, 0x00, 0xA0, 0x18, 0xFE]), Joypad::new());
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        gameboy.set_autosave(Some(Autosave::new(path.clone(), AUTOSAVE_INTERVAL_FRAMES)));
+        gameboy.run_until_vblank();
+        assert!(!path.exists());
+        assert!(gameboy.shutdown().expect("Save file should be writable"));
+        let saved = std::fs::read(&path).expect("Save file should have been written");
+        assert_eq!(saved[0], 0x5A);
+        std::fs::remove_file(&path).expect("Save file should be removable");
+    }
+}
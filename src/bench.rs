@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use gbzd::cart::Cart;
+use gbzd::clock::ManualClock;
+use gbzd::gameboy::{Gameboy, FRAME_DURATION};
+use gbzd::input::Joypad;
+use gbzd::memory_gb::{Address, Byte, MemoryMap, MemoryRegion};
+use gbzd::tracer::{CpuState, Tracer};
+
+const CPU_BENCH_INSTRUCTIONS: u64 = 20_000_000;
+const PPU_BENCH_FRAMES: u32 = 600;
+
+// Counts instructions as the CPU runs them
+struct InstructionCounter(Arc<AtomicU64>);
+
+impl Tracer for InstructionCounter {
+    fn on_instruction(&mut self, _state: &CpuState) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Runs both benchmarks with synthetic ROMs, so numbers are comparable between builds without needing any game around
+pub fn run() {
+    bench_cpu();
+    bench_ppu();
+}
+
+// Builds a 32KiB no-MBC ROM with the program at the 0x0100 entry point
+fn synthetic_rom(program: &[Byte]) -> Cart {
+    let mut rom = vec![0 as Byte; 0x8000];
+    rom[0x0100..(0x0100 + program.len())].copy_from_slice(program);
+    Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("Synthetic ROM should always be valid")
+}
+
+// Turns the LCD off and spins on arithmetic and WRAM stores, so the time is all CPU
+fn bench_cpu() {
+    let program = [
+        0xAF,               // XOR A
+        0xE0, 0x40,         // LDH (0x40),A     LCD off
+        0x21, 0x00, 0xC0,   // LD HL,0xC000
+        0x3C,               // INC A            <- loop
+        0x80,               // ADD A,B
+        0x22,               // LD (HL+),A
+        0xCB, 0xAC,         // RES 5,H          keeps HL inside WRAM
+        0x4F,               // LD C,A
+        0x05,               // DEC B
+        0x18, 0xF7          // JR loop
+    ];
+    let mut memory_data = MemoryMap::allocate(synthetic_rom(&program), Joypad::new());
+    let mut gameboy = Gameboy::new(&mut memory_data, true);
+    let instructions = Arc::new(AtomicU64::new(0));
+    gameboy.set_cpu_tracer(Some(Box::new(InstructionCounter(instructions.clone()))));
+
+    let start = Instant::now();
+    while instructions.load(Ordering::Relaxed) < CPU_BENCH_INSTRUCTIONS {
+        gameboy.step();
+    }
+    let elapsed = start.elapsed();
+    println!("CPU: {} instructions in {:?}, {:.2} million instructions/second",
+        instructions.load(Ordering::Relaxed), elapsed, instructions.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64() / 1_000_000.0);
+}
+
+// Fills VRAM and OAM so the PPU has background, window, and objects to draw on every frame while the CPU idles in a loop
+fn bench_ppu() {
+    let program = [
+        0x18, 0xFE          // JR -2
+    ];
+    let mut memory_data = MemoryMap::allocate(synthetic_rom(&program), Joypad::new());
+    let mut gameboy = Gameboy::new(&mut memory_data, true);
+    {
+        let memory = gameboy.memory_mut();
+        // Tile data with every color index showing up, and tile maps that cycle through all of it
+        let tile_data = (0..0x1800).map(|i| (i * 37 + (i >> 4)) as Byte).collect::<Vec<Byte>>();
+        memory.load_region(0x8000, &tile_data);
+        let tile_maps = (0..0x800).map(|i| (i * 7) as Byte).collect::<Vec<Byte>>();
+        memory.load_region(0x9800, &tile_maps);
+        // 40 objects in four bands of ten, so the lines they cover hit the 10 object limit
+        let oam = (0..40).flat_map(|i: Address| {
+            let band = i / 10;
+            let column = i % 10;
+            [(16 + band * 36) as Byte, (8 + column * 16) as Byte, i as Byte, ((i & 1) << 4) as Byte]
+        }).collect::<Vec<Byte>>();
+        memory.load_region(0xFE00, &oam);
+        memory.write(0x72 as Byte, 0xFF4A);    // WY
+        memory.write(0x57 as Byte, 0xFF4B);    // WX
+        memory.write(0xF3 as Byte, 0xFF40);    // LCD, window on 0x9C00, window, objects, background all on
+    }
+
+    let start = Instant::now();
+    let mut frames = 0;
+    while frames < PPU_BENCH_FRAMES {
+        gameboy.step();
+        if gameboy.frame_is_ready() {
+            frames += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    let frames_per_second = frames as f64 / elapsed.as_secs_f64();
+    println!("PPU: {} frames in {:?}, {:.1} frames/second ({:.1}x hardware speed)",
+        frames, elapsed, frames_per_second, frames_per_second * FRAME_DURATION.as_secs_f64());
+}
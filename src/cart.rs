@@ -1,5 +1,7 @@
 
 
+use crate::cheats::GameGenieCode;
+use crate::clock::Clock;
 use crate::memory_gb;
 use crate::memory_gb::Address;
 use crate::memory_gb::Byte;
@@ -11,14 +13,98 @@ use crate::memory_gb::MemoryUnit;
 const CART_BASE_ADDRESS: usize = 0x0000;
 const ROM_BANK_WIDTH: usize = 0x4000;
 const RAM_BANK_WIDTH: usize = 0x2000;
+// A 2KiB RAM chip only decodes the low 11 address lines, so it shows up four times over in the 8KiB window
+const SMALL_RAM_WIDTH: usize = 0x0800;
+
+// Carts that declare no RAM still let games flip the RAM enable, and games can select more RAM banks than the cart has,
+// so external RAM access has to cope with addresses that land outside the buffer.
+// With nothing behind them, reads float high and writes go nowhere
+fn read_ram<T: MemoryUnit>(ram_banks: &[Byte], address: usize) -> T {
+    read_or_float(ram_banks, mirror_small_ram(ram_banks, address))
+}
+
+// Reports whether the write actually landed in RAM, so the cart knows its save has changed
+fn write_ram<T: MemoryUnit>(ram_banks: &mut [Byte], value: T, address: usize) -> bool {
+    let address = mirror_small_ram(ram_banks, address);
+    if address + std::mem::size_of::<T>() <= ram_banks.len() {
+        memory_gb::write_to_buffer_extended(ram_banks, value, address);
+        true
+    }
+    else {
+        false
+    }
+}
+
+// RAM smaller than a bank repeats through the whole window instead of running out
+fn mirror_small_ram(ram_banks: &[Byte], address: usize) -> usize {
+    if ram_banks.len() == SMALL_RAM_WIDTH {
+        address % SMALL_RAM_WIDTH
+    }
+    else {
+        address
+    }
+}
+
+// Same deal for ROM, a bank select past the end of a badly sized image reads open bus instead of taking the emulator down
+fn read_rom<T: MemoryUnit>(data: &[Byte], address: usize) -> T {
+    read_or_float(data, address)
+}
+
+fn read_or_float<T: MemoryUnit>(buffer: &[Byte], address: usize) -> T {
+    if address + std::mem::size_of::<T>() <= buffer.len() {
+        memory_gb::read_from_buffer_extended(buffer, address)
+    }
+    else {
+        T::promote(Byte::invalid_read_value())
+    }
+}
+
+// Ways a ROM image can fail to load
+#[derive(Debug)]
+pub enum CartError {
+    Io(std::io::Error),
+    // The image is smaller than its header, or smaller than the ROM size the header declares
+    TruncatedRom { expected: usize, actual: usize },
+    // A header field holds a value no real cart uses
+    BadHeader(String),
+    UnsupportedMapper(Byte),
+    // A RAM image handed to set_ram that isn't the size the header declared
+    RamSizeMismatch { expected: usize, actual: usize }
+}
+
+impl std::fmt::Display for CartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartError::Io(error) => write!(f, "Couldn't read ROM file: {}", error),
+            CartError::TruncatedRom { expected, actual } => write!(f, "ROM is {} bytes but should be at least {} bytes", actual, expected),
+            CartError::BadHeader(reason) => write!(f, "Bad cart header: {}", reason),
+            CartError::UnsupportedMapper(mapper) => write!(f, "Bad or unsupported MBC mapper: {:x}", mapper),
+            CartError::RamSizeMismatch { expected, actual } => write!(f, "RAM image is {} bytes but the cart has {} bytes of RAM", actual, expected)
+        }
+    }
+}
+
+impl std::error::Error for CartError {}
+
+impl From<std::io::Error> for CartError {
+    fn from(error: std::io::Error) -> CartError {
+        CartError::Io(error)
+    }
+}
 
 struct NoMBC {
     data: Vec<Byte>
 }
 
+impl NoMBC {
+    fn rom_offset(&self, address: Address) -> usize {
+        address as usize + CART_BASE_ADDRESS
+    }
+}
+
 impl MemoryRegion for NoMBC {
     fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
-        memory_gb::read_from_buffer(&self.data, address + CART_BASE_ADDRESS as Address)
+        read_rom(&self.data, self.rom_offset(address))
     }
 
     fn write<T: MemoryUnit>(&mut self, _: T, _: Address) -> () {
@@ -39,70 +125,85 @@ struct MBC1 {
     ram_enabled: bool,
     active_ram_bank: u8,
     ram_banks: Vec<Byte>,
-    banking_mode: BankingModeMBC1
+    // Set by any write to RAM since the last save
+    ram_dirty: bool,
+    banking_mode: BankingModeMBC1,
+    // Multicart boards wire the ROM bank register's top bit to nothing, so the upper bank bits pick one of four 256KiB games
+    multicart: bool
 }
 
-impl MemoryRegion for MBC1 {
-    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+impl MBC1 {
+    // Multicarts are all 1MiB with each game starting on a multiple of 16 banks.
+    // The real giveaway is a second game's header, Nintendo logo included, at the start of bank 0x10
+    fn is_multicart(data: &[Byte]) -> bool {
+        const SECOND_GAME_LOGO_LOCATION: usize = 0x10 * ROM_BANK_WIDTH + LOGO_LOCATION;
+        data.len() == 0x100000 && data[SECOND_GAME_LOGO_LOCATION..(SECOND_GAME_LOGO_LOCATION + NINTENDO_LOGO.len())] == NINTENDO_LOGO
+    }
+
+    // Where a ROM address (0x0000-0x7FFF) lands in the ROM image under the current banking
+    fn rom_offset(&self, address: Address) -> usize {
         const SWAPPABLE_BASE_ADDRESS: usize = 0x4000;
+        let rom_bank_bits = if self.multicart { 4 } else { 5 };
+        let upper_bank_shift = 14 + rom_bank_bits;
         // ROM bank 0
         if address < SWAPPABLE_BASE_ADDRESS as Address {
-            
             match self.banking_mode {
                 BankingModeMBC1::Simple => {
-                    memory_gb::read_from_buffer(&self.data, address)
+                    address as usize
                 }
                 BankingModeMBC1::Advanced => {
-                    memory_gb::read_from_buffer_extended(&self.data, ((address) as usize) + ((self.active_ram_bank as usize) << 19))
+                    ((address) as usize) + ((self.active_ram_bank as usize) << upper_bank_shift)
                 }
             }
-            
-            // memory_gb::read_from_buffer(&self.data, address)
         }
         // Swappable ROM bank
-        else if (address >= 0x4000) && (address < 0x8000) {
-            
+        else {
             // active_bank 0 and 1 are both treated as a 0 offset, active_bank 2 as a 1 offset, continued...
-            let bank = (std::cmp::max(self.active_rom_bank, 1) as usize) & 0x1F;
-            let bank_adjusted_address = ((address & 0x3FFF) as usize) + (bank << 14) + ((self.active_ram_bank as usize) << 19);
-            memory_gb::read_from_buffer_extended(&self.data, bank_adjusted_address)
+            // The zero check sees all 5 bits even on multicarts, so selecting 0x10 there really does map a game's bank 0
+            let bank = (std::cmp::max(self.active_rom_bank, 1) as usize) & ((1 << rom_bank_bits) - 1);
+            ((address & 0x3FFF) as usize) + (bank << 14) + ((self.active_ram_bank as usize) << upper_bank_shift)
+        }
+    }
+
+    // Where an external RAM address (0xA000-0xBFFF) lands in the RAM image under the current banking
+    fn ram_offset(&self, address: Address) -> usize {
+        match self.banking_mode {
+            BankingModeMBC1::Simple => {
+                (address as usize) & 0x1FFF
+            }
+            BankingModeMBC1::Advanced => {
+                ((address as usize) & 0x1FFF) | ((self.active_ram_bank as usize) << 13)
+            }
+        }
+    }
+}
+
+impl MemoryRegion for MBC1 {
+    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+        // ROM banks
+        if address < 0x8000 {
+            read_rom(&self.data, self.rom_offset(address))
         }
         else if (address >= 0xA000) && (address < 0xC000) {
-            // let bank_adjusted_address = (address as usize - 0xA000) + (self.active_ram_bank as usize * RAM_BANK_WIDTH);
-            let bank_adjusted_address = match self.banking_mode {
-                BankingModeMBC1::Simple => {
-                    (address as usize) & 0x1FFF
-                }
-                BankingModeMBC1::Advanced => {
-                    ((address as usize) & 0x1FFF) | ((self.active_ram_bank as usize) << 13)
-                }
-            };
+            let bank_adjusted_address = self.ram_offset(address);
             if self.ram_enabled {
-                memory_gb::read_from_buffer_extended(&self.ram_banks, bank_adjusted_address)
+                read_ram(&self.ram_banks, bank_adjusted_address)
             }
             else {
                 T::promote(Byte::invalid_read_value())
             }
         }
-        // Let it panic if out of bounds somehow, probably indicates a mistake
+        // The memory map never sends anything else here, but float high rather than panic if it ever does
         else {
-            panic!("Invalid cart read address");
+            T::promote(Byte::invalid_read_value())
         }
-        // Let it panic if out of bounds somehow, probably indicates a mistake or exram access which is unsupported
     }
 
     fn write<T: MemoryUnit>(&mut self, value: T, address: Address) -> () {
         // RAM address space
         if (address >= 0xA000) && (address < 0xC000) {
-            let bank_adjusted_address = match self.banking_mode {
-                BankingModeMBC1::Simple => {
-                    (address as usize) & 0x1FFF
-                }
-                BankingModeMBC1::Advanced => {
-                    ((address as usize) & 0x1FFF) | ((self.active_ram_bank as usize) << 13)
-                }
-            };
-            if self.ram_enabled { memory_gb::write_to_buffer_extended(&mut self.ram_banks, value, bank_adjusted_address) };
+            let bank_adjusted_address = self.ram_offset(address);
+            if self.ram_enabled { self.ram_dirty |= write_ram(&mut self.ram_banks, value, bank_adjusted_address) };
         }
         // RAM enable register
         else if address < 0x2000 {
@@ -139,31 +240,123 @@ struct MBC3 {
     active_rom_bank: u8,
     ram_enabled: bool,
     active_ram_bank: u8,
-    ram_banks: Vec<Byte>
+    ram_banks: Vec<Byte>,
+    ram_dirty: bool,
+    rtc: Rtc
 }
 
-impl MemoryRegion for MBC3 {
-    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+// Real time clock on board some MBC3 carts
+// Time is kept as an offset from the clock, and only turned into register values when the game latches it
+struct Rtc {
+    clock: Box<dyn Clock>,
+    // Clock reading at which the RTC counted zero
+    base_seconds: u64,
+    // Where the count stopped while the halt bit is set. The clock is ignored until it's cleared
+    halted_seconds: Option<u64>,
+    // Set once the day counter overflows, and stays set until the game writes it clear
+    day_carry: bool,
+    // Seconds, minutes, hours, day counter low, day counter high/halt/carry as of the last latch
+    latched: [Byte; 5],
+    // Latching happens on a write of 0x00 followed by 0x01
+    latch_armed: bool
+}
+
+impl Rtc {
+    fn new(clock: Box<dyn Clock>) -> Rtc {
+        let base_seconds = clock.now_seconds();
+        Rtc { clock, base_seconds, halted_seconds: None, day_carry: false, latched: [0; 5], latch_armed: false }
+    }
+
+    fn elapsed_seconds(&self) -> u64 {
+        match self.halted_seconds {
+            Some(seconds) => seconds,
+            // Wrapping to match write(), which can push the base past the current reading when the written time is ahead of it
+            None => self.clock.now_seconds().wrapping_sub(self.base_seconds)
+        }
+    }
+
+    fn latch(&mut self) {
+        let elapsed = self.elapsed_seconds();
+        let days = elapsed / 86400;
+        // The day counter is 9 bits wide, anything past that sets the carry bit
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        let halt = if self.halted_seconds.is_some() { 0x40 } else { 0x00 };
+        let day_carry = if self.day_carry { 0x80 } else { 0x00 };
+        self.latched = [
+            (elapsed % 60) as Byte,
+            ((elapsed / 60) % 60) as Byte,
+            ((elapsed / 3600) % 24) as Byte,
+            (days & 0xFF) as Byte,
+            (((days >> 8) & 0x1) as Byte) | halt | day_carry
+        ];
+    }
+
+    // Register select values 0x08-0x0C map onto the latched registers in order
+    fn read(&self, register: u8) -> Byte {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    // Writing a register rewinds the base so the running time picks up from the written value.
+    // With the halt bit set the written time is held instead
+    fn write(&mut self, register: u8, value: Byte) {
+        self.latched[(register - 0x08) as usize] = value;
+        let days = (self.latched[3] as u64) | (((self.latched[4] & 0x1) as u64) << 8);
+        let seconds = (self.latched[0] as u64) + (self.latched[1] as u64 * 60) + (self.latched[2] as u64 * 3600) + (days * 86400);
+        if self.latched[4] & 0x40 != 0 {
+            self.halted_seconds = Some(seconds);
+        }
+        else {
+            self.halted_seconds = None;
+            self.base_seconds = self.clock.now_seconds().wrapping_sub(seconds);
+        }
+        self.day_carry = self.latched[4] & 0x80 != 0;
+    }
+}
+
+impl MBC3 {
+    // Where a ROM address (0x0000-0x7FFF) lands in the ROM image under the current banking
+    fn rom_offset(&self, address: Address) -> usize {
         // ROM bank 0
         if address < 0x4000 as Address {
-            memory_gb::read_from_buffer(&self.data, address)
+            address as usize
         }
         // Swappable ROM bank
-        else if (address >= 0x4000) && (address < 0x8000) {
+        else {
             // active_bank 0 and 1 are both treated as a 0 offset, active_bank 2 as a 1 offset, continued...
             let bank_offset = (std::cmp::max(self.active_rom_bank, 1) - 1) as usize;
-            let bank_adjusted_address = address as usize + (bank_offset * ROM_BANK_WIDTH);
-            memory_gb::read_from_buffer_extended(&self.data, bank_adjusted_address)
+            address as usize + (bank_offset * ROM_BANK_WIDTH)
+        }
+    }
+
+    // Where an external RAM address (0xA000-0xBFFF) lands in the RAM image under the current banking
+    fn ram_offset(&self, address: Address) -> usize {
+        ((address as usize) & 0x1FFF) + (self.active_ram_bank as usize * RAM_BANK_WIDTH)
+    }
+}
+
+impl MemoryRegion for MBC3 {
+    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+        // ROM banks
+        if address < 0x8000 {
+            read_rom(&self.data, self.rom_offset(address))
         } 
         // RTC Registers or RAM
         else if (address >= 0xA000) && (address < 0xC000) {
-            // TODO: RTC unsupported, just return 0xFF and cross fingers
             match self.active_ram_bank {
-                0x08 | 0x09 | 0x0A | 0x0B | 0x0C => T::promote(0xFF),
+                0x08 | 0x09 | 0x0A | 0x0B | 0x0C => {
+                    if self.ram_enabled {
+                        T::promote(self.rtc.read(self.active_ram_bank))
+                    }
+                    else {
+                        T::promote(Byte::invalid_read_value())
+                    }
+                }
                 _ => {
-                    let bank_adjusted_address = (address as usize - 0xA000) + (self.active_ram_bank as usize * RAM_BANK_WIDTH);
+                    let bank_adjusted_address = self.ram_offset(address);
                     if self.ram_enabled {
-                        memory_gb::read_from_buffer_extended(&self.ram_banks, bank_adjusted_address)
+                        read_ram(&self.ram_banks, bank_adjusted_address)
                     }
                     else {
                         T::promote(Byte::invalid_read_value())
@@ -171,21 +364,22 @@ impl MemoryRegion for MBC3 {
                 }
             }
         }
-        // Let it panic if out of bounds somehow, probably indicates a mistake
+        // The memory map never sends anything else here, but float high rather than panic if it ever does
         else {
-            panic!("Invalid cart read address");
+            T::promote(Byte::invalid_read_value())
         }
     }
 
     fn write<T: MemoryUnit>(&mut self, value: T, address: Address) -> () {
         // RAM address space
         if (address >= 0xA000) && (address < 0xC000) {
-            // TODO: RTC unsupported, just do nothing and cross fingers
             match self.active_ram_bank {
-                0x08 | 0x09 | 0x0A | 0x0B | 0x0C => (),
+                0x08 | 0x09 | 0x0A | 0x0B | 0x0C => {
+                    if self.ram_enabled { self.rtc.write(self.active_ram_bank, value.demote()) }
+                }
                 _ => {
-                    let bank_adjusted_address = (address as usize - 0xA000) + (self.active_ram_bank as usize * RAM_BANK_WIDTH);
-                    if self.ram_enabled { memory_gb::write_to_buffer_extended(&mut self.ram_banks, value, bank_adjusted_address) }
+                    let bank_adjusted_address = self.ram_offset(address);
+                    if self.ram_enabled { self.ram_dirty |= write_ram(&mut self.ram_banks, value, bank_adjusted_address) }
                 }
             }
         }
@@ -209,7 +403,16 @@ impl MemoryRegion for MBC3 {
         }
         // Latch Clock Data
         else if (address >= 0x6000) && (address < 0x8000) {
-            // TODO: Unstub
+            match value.demote() {
+                0x00 => self.rtc.latch_armed = true,
+                0x01 => {
+                    if self.rtc.latch_armed {
+                        self.rtc.latch();
+                    }
+                    self.rtc.latch_armed = false;
+                }
+                _ => self.rtc.latch_armed = false
+            }
         }
         else {}
     }
@@ -221,48 +424,60 @@ struct MBC5 {
     active_rom_bank: u16,
     ram_enabled: bool,
     active_ram_bank: u8,
-    ram_banks: Vec<Byte>
+    ram_banks: Vec<Byte>,
+    ram_dirty: bool
 }
 
-impl MemoryRegion for MBC5 {
-    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+impl MBC5 {
+    // Where a ROM address (0x0000-0x7FFF) lands in the ROM image under the current banking
+    fn rom_offset(&self, address: Address) -> usize {
         // ROM bank 0
         if address < 0x4000 as Address {
-            memory_gb::read_from_buffer(&self.data, address)
+            address as usize
         }
         // Swappable ROM bank
-        else if (address >= 0x4000) && (address < 0x8000) {
-            let bank_offset = (self.active_rom_bank.wrapping_sub(1)) as usize;
-            let bank_adjusted_address = if self.active_rom_bank > 0 {
-                address as usize + (bank_offset * ROM_BANK_WIDTH)
-            }
-            else {
-                // Edge case where the bank is mirroring ROM bank 0. Need to 'reach back' to mirror it.
-                (address as usize) - ROM_BANK_WIDTH
-            };
-            memory_gb::read_from_buffer_extended(&self.data, bank_adjusted_address)
+        else {
+            // Banks past the end of the ROM wrap around, like the unconnected upper bank lines on hardware.
+            // Bank 0 is allowed here too and just mirrors ROM bank 0
+            let rom_bank_count = std::cmp::max(self.data.len() / ROM_BANK_WIDTH, 1);
+            let bank = (self.active_rom_bank as usize) % rom_bank_count;
+            ((address & 0x3FFF) as usize) + (bank * ROM_BANK_WIDTH)
+        }
+    }
+
+    // Where an external RAM address (0xA000-0xBFFF) lands in the RAM image under the current banking
+    fn ram_offset(&self, address: Address) -> usize {
+        ((address as usize) & 0x1FFF) + (self.active_ram_bank as usize * RAM_BANK_WIDTH)
+    }
+}
+
+impl MemoryRegion for MBC5 {
+    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+        // ROM banks
+        if address < 0x8000 {
+            read_rom(&self.data, self.rom_offset(address))
         } 
         // RAM
         else if (address >= 0xA000) && (address < 0xC000) {
-            let bank_adjusted_address = (address as usize - 0xA000) + (self.active_ram_bank as usize * RAM_BANK_WIDTH);
+            let bank_adjusted_address = self.ram_offset(address);
             if self.ram_enabled {
-                memory_gb::read_from_buffer_extended(&self.ram_banks, bank_adjusted_address)
+                read_ram(&self.ram_banks, bank_adjusted_address)
             }
             else {
                 T::promote(Byte::invalid_read_value())
             }
         }
-        // Let it panic if out of bounds somehow, probably indicates a mistake
+        // The memory map never sends anything else here, but float high rather than panic if it ever does
         else {
-            panic!("Invalid cart read address");
+            T::promote(Byte::invalid_read_value())
         }
     }
 
     fn write<T: MemoryUnit>(&mut self, value: T, address: Address) -> () {
         // RAM address space
         if (address >= 0xA000) && (address < 0xC000) {
-            let bank_adjusted_address = (address as usize - 0xA000) + (self.active_ram_bank as usize * RAM_BANK_WIDTH);
-            if self.ram_enabled { memory_gb::write_to_buffer_extended(&mut self.ram_banks, value, bank_adjusted_address) }
+            let bank_adjusted_address = self.ram_offset(address);
+            if self.ram_enabled { self.ram_dirty |= write_ram(&mut self.ram_banks, value, bank_adjusted_address) }
         }
         // RAM enable register
         else if address < 0x2000 {
@@ -292,6 +507,185 @@ impl MemoryRegion for MBC5 {
 
 // End cart types
 
+// What the header at 0x0143 says about Game Boy Color support
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CgbSupport {
+    None,
+    Enhanced,
+    Only
+}
+
+impl CgbSupport {
+    pub fn from_header_flag(flag: Byte) -> CgbSupport {
+        match flag {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None
+        }
+    }
+}
+
+const LOGO_LOCATION: usize = 0x0104;
+const TITLE_LOCATION: usize = 0x0134;
+const NEW_LICENSEE_LOCATION: usize = 0x0144;
+const CGB_FLAG_LOCATION: usize = 0x0143;
+const SGB_FLAG_LOCATION: usize = 0x0146;
+const MAPPER_TYPE_LOCATION: usize = 0x0147;
+const ROM_SIZE_LOCATION: usize = 0x0148;
+const RAM_SIZE_LOCATION: usize = 0x0149;
+const OLD_LICENSEE_LOCATION: usize = 0x014B;
+const VERSION_LOCATION: usize = 0x014C;
+const HEADER_CHECKSUM_LOCATION: usize = 0x014D;
+const GLOBAL_CHECKSUM_LOCATION: usize = 0x014E;
+const HEADER_END: usize = 0x0150;
+
+// Every licensed cart carries this at 0x0104, the boot ROM won't start one without it
+const NINTENDO_LOGO: [Byte; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E
+];
+
+// ROM size is 32KiB shifted left by the header value
+fn rom_size_from_code(size_code: Byte) -> Option<usize> {
+    match size_code {
+        0x00..=0x08 => Some((2 * ROM_BANK_WIDTH) << size_code),
+        _ => None
+    }
+}
+
+fn ram_size_from_code(size_code: Byte) -> Option<usize> {
+    match size_code {
+        0x00 => Some(0),
+        // Not in the official list, but a handful of early carts shipped a 2KiB chip and marked it like this
+        0x01 => Some(SMALL_RAM_WIDTH),
+        0x02 => Some(RAM_BANK_WIDTH),
+        0x03 => Some(4 * RAM_BANK_WIDTH),
+        0x04 => Some(16 * RAM_BANK_WIDTH),
+        0x05 => Some(8 * RAM_BANK_WIDTH),
+        _ => None
+    }
+}
+
+// Everything the header at 0x0134-0x014F says about a cart, read as-is without judging whether the emulator can run it
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartHeader {
+    pub title: String,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub cart_type: Byte,
+    // None when the size code isn't one real carts use
+    pub rom_size: Option<usize>,
+    pub ram_size: Option<usize>,
+    // Two ASCII characters for newer carts, or the old one byte code in hex
+    pub licensee: String,
+    pub version: Byte,
+    pub header_checksum: Byte,
+    // The boot ROM refuses to start a cart whose header checksum is off
+    pub header_checksum_valid: bool,
+    pub global_checksum: memory_gb::Word,
+    // Nothing checks this one on hardware, but a mismatch usually means a bad dump or a hacked ROM
+    pub global_checksum_valid: bool
+}
+
+impl CartHeader {
+    pub fn parse(rom: &[Byte]) -> Result<CartHeader, CartError> {
+        if rom.len() < HEADER_END {
+            return Err(CartError::TruncatedRom { expected: HEADER_END, actual: rom.len() })
+        }
+        let cgb_flag = rom[CGB_FLAG_LOCATION];
+        // CGB carts gave the last bytes of the title over to the CGB flag
+        let title_end = if (cgb_flag & 0x80) > 0 { CGB_FLAG_LOCATION } else { NEW_LICENSEE_LOCATION };
+        let title = rom[TITLE_LOCATION..title_end].iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' })
+            .collect::<String>();
+        let old_licensee = rom[OLD_LICENSEE_LOCATION];
+        let licensee = if old_licensee == 0x33 {
+            String::from_utf8_lossy(&rom[NEW_LICENSEE_LOCATION..NEW_LICENSEE_LOCATION + 2]).into_owned()
+        }
+        else {
+            format!("{:02X}", old_licensee)
+        };
+        let header_checksum = rom[TITLE_LOCATION..=VERSION_LOCATION].iter()
+            .fold(0 as Byte, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1));
+        let global_checksum = ((rom[GLOBAL_CHECKSUM_LOCATION] as memory_gb::Word) << 8) | rom[GLOBAL_CHECKSUM_LOCATION + 1] as memory_gb::Word;
+        let global_sum = rom.iter()
+            .enumerate()
+            .filter(|(address, _)| *address != GLOBAL_CHECKSUM_LOCATION && *address != GLOBAL_CHECKSUM_LOCATION + 1)
+            .fold(0 as memory_gb::Word, |sum, (_, &byte)| sum.wrapping_add(byte as memory_gb::Word));
+        Ok(CartHeader {
+            title,
+            cgb_support: CgbSupport::from_header_flag(cgb_flag),
+            sgb_support: rom[SGB_FLAG_LOCATION] == 0x03,
+            cart_type: rom[MAPPER_TYPE_LOCATION],
+            rom_size: rom_size_from_code(rom[ROM_SIZE_LOCATION]),
+            ram_size: ram_size_from_code(rom[RAM_SIZE_LOCATION]),
+            licensee,
+            version: rom[VERSION_LOCATION],
+            header_checksum: rom[HEADER_CHECKSUM_LOCATION],
+            header_checksum_valid: header_checksum == rom[HEADER_CHECKSUM_LOCATION],
+            global_checksum,
+            global_checksum_valid: global_sum == global_checksum
+        })
+    }
+
+    // What's on the cart board according to the type byte, supported by the emulator or not
+    pub fn cart_type_name(&self) -> &'static str {
+        match self.cart_type {
+            0x00 => "ROM only",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "unknown"
+        }
+    }
+}
+
+impl std::fmt::Display for CartHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size_text = |size: Option<usize>| match size {
+            Some(size) => format!("{} KiB", size / 1024),
+            None => String::from("invalid")
+        };
+        let validity_text = |valid: bool| if valid { "ok" } else { "BAD" };
+        writeln!(f, "Title:           {}", self.title)?;
+        writeln!(f, "Cart type:       {:#04x} ({})", self.cart_type, self.cart_type_name())?;
+        writeln!(f, "ROM size:        {}", size_text(self.rom_size))?;
+        writeln!(f, "RAM size:        {}", size_text(self.ram_size))?;
+        writeln!(f, "CGB support:     {:?}", self.cgb_support)?;
+        writeln!(f, "SGB support:     {}", self.sgb_support)?;
+        writeln!(f, "Licensee:        {}", self.licensee)?;
+        writeln!(f, "Version:         {}", self.version)?;
+        writeln!(f, "Header checksum: {:#04x} ({})", self.header_checksum, validity_text(self.header_checksum_valid))?;
+        write!(f, "Global checksum: {:#06x} ({})", self.global_checksum, validity_text(self.global_checksum_valid))
+    }
+}
+
 enum Mapper {
     NoMBC(NoMBC),
     MBC1(MBC1),
@@ -299,28 +693,40 @@ enum Mapper {
     MBC5(MBC5)
 }
 pub struct Cart {
-    data: Mapper
+    data: Mapper,
+    cgb_support: CgbSupport,
+    game_genie_codes: Vec<GameGenieCode>
 }
 
 impl Cart {
-    pub fn load_from_file(path: &str) -> Result<Cart, std::io::Error> {
-        const MAPPER_TYPE_LOCATION: usize = 0x0147;
-        const RAM_SIZE_LOCATION: usize = 0x0149;
+    pub fn load_from_file(path: &str, clock: Box<dyn Clock>) -> Result<Cart, CartError> {
         let contents = std::fs::read(path)?;
-        let calc_ram = | bank_count: usize | {
-            let mut ram_banks = Vec::<Byte>::with_capacity(bank_count*RAM_BANK_WIDTH);
+        Cart::from_bytes(contents, clock)
+    }
+
+    // Builds a cart from a ROM image already in memory, checking the header before trusting anything in it.
+    // The clock is where an RTC gets its time. Carts without one drop it
+    pub fn from_bytes(contents: Vec<Byte>, clock: Box<dyn Clock>) -> Result<Cart, CartError> {
+        if contents.len() < HEADER_END {
+            return Err(CartError::TruncatedRom { expected: HEADER_END, actual: contents.len() })
+        }
+        let rom_size = match rom_size_from_code(contents[ROM_SIZE_LOCATION]) {
+            Some(rom_size) => rom_size,
+            None => return Err(CartError::BadHeader(format!("impossible ROM size {:#04x}", contents[ROM_SIZE_LOCATION])))
+        };
+        if contents.len() < rom_size {
+            return Err(CartError::TruncatedRom { expected: rom_size, actual: contents.len() })
+        }
+        let cgb_support = CgbSupport::from_header_flag(contents[CGB_FLAG_LOCATION]);
+        let calc_ram = | size: usize | {
+            let mut ram_banks = Vec::<Byte>::with_capacity(size);
             ram_banks.resize_with(ram_banks.capacity(), || Byte::invalid_read_value());
-            (bank_count, ram_banks)
+            ram_banks
         };
-        let (_, ram_banks) = match contents[RAM_SIZE_LOCATION] {
-            0x00 => calc_ram(0),
-            0x01 => calc_ram(0),
-            0x02 => calc_ram(1),
-            0x03 => calc_ram(4),
-            0x04 => calc_ram(16),
-            0x05 => calc_ram(8),
-            _ => {
-                panic!("Cartridge reports impossible RAM bank count")
+        let ram_banks = match ram_size_from_code(contents[RAM_SIZE_LOCATION]) {
+            Some(ram_size) => calc_ram(ram_size),
+            None => {
+                return Err(CartError::BadHeader(format!("impossible RAM size {:#04x}", contents[RAM_SIZE_LOCATION])))
             }
         };
         let mapper = match contents[MAPPER_TYPE_LOCATION] {
@@ -329,14 +735,17 @@ impl Cart {
                 Ok(Mapper::NoMBC(NoMBC { data: contents }))
             }
             0x01 | 0x02 | 0x03 => {
-                println!("Loaded MBC1");
+                let multicart = MBC1::is_multicart(&contents);
+                println!("Loaded MBC1{}", if multicart { " multicart" } else { "" });
                 Ok(Mapper::MBC1(MBC1 { 
                     data: contents,
                     active_rom_bank: 1,
                     ram_enabled: false,
                     active_ram_bank: 0,
                     ram_banks,
-                    banking_mode: BankingModeMBC1::Simple }))
+                    ram_dirty: false,
+                    banking_mode: BankingModeMBC1::Simple,
+                    multicart }))
             }
             0x0F | 0x10 | 0x11 | 0x12 | 0x13 => {
                 println!("Loaded MBC3");
@@ -345,7 +754,9 @@ impl Cart {
                     active_rom_bank: 1, 
                     ram_enabled: false, 
                     active_ram_bank: 0, 
-                    ram_banks }))
+                    ram_banks,
+                    ram_dirty: false,
+                    rtc: Rtc::new(clock) }))
             }
             0x19 | 0x1A | 0x1B => {
                 println!("Loaded MBC5");
@@ -354,19 +765,160 @@ impl Cart {
                     active_rom_bank: 1, 
                     ram_enabled: false, 
                     active_ram_bank: 0, 
-                    ram_banks }))
+                    ram_banks,
+                    ram_dirty: false }))
             }
-            _ => {
-                println!("Bad or unsupported MBC mapper: {:x}", contents[MAPPER_TYPE_LOCATION]);
-                Err(std::io::ErrorKind::InvalidData)
+            mapper_code => {
+                Err(CartError::UnsupportedMapper(mapper_code))
             }
         }?;
-        Ok( Cart { data: mapper } )
+        Ok( Cart { data: mapper, cgb_support, game_genie_codes: vec![] } )
+    }
+
+    pub fn cgb_support(&self) -> CgbSupport {
+        self.cgb_support
+    }
+
+    pub fn header(&self) -> CartHeader {
+        CartHeader::parse(self.rom()).expect("Cart was already checked to have a whole header")
+    }
+
+    fn rom(&self) -> &[Byte] {
+        match self.data {
+            Mapper::NoMBC(ref no_mbc_cart) => &no_mbc_cart.data,
+            Mapper::MBC1(ref mbc1_cart) => &mbc1_cart.data,
+            Mapper::MBC3(ref mbc3_cart) => &mbc3_cart.data,
+            Mapper::MBC5(ref mbc5_cart) => &mbc5_cart.data
+        }
+    }
+
+    // Battery backed RAM as currently held by the cart, for front-ends that manage save storage themselves
+    pub fn ram(&self) -> &[Byte] {
+        match self.data {
+            Mapper::NoMBC(_) => &[],
+            Mapper::MBC1(ref mbc1_cart) => &mbc1_cart.ram_banks,
+            Mapper::MBC3(ref mbc3_cart) => &mbc3_cart.ram_banks,
+            Mapper::MBC5(ref mbc5_cart) => &mbc5_cart.ram_banks
+        }
+    }
+
+    // Replaces the cart RAM with a saved image. The image has to be exactly the size the header declared
+    pub fn set_ram(&mut self, bytes: &[Byte]) -> Result<(), CartError> {
+        let ram: &mut [Byte] = match self.data {
+            Mapper::NoMBC(_) => &mut [],
+            Mapper::MBC1(ref mut mbc1_cart) => &mut mbc1_cart.ram_banks,
+            Mapper::MBC3(ref mut mbc3_cart) => &mut mbc3_cart.ram_banks,
+            Mapper::MBC5(ref mut mbc5_cart) => &mut mbc5_cart.ram_banks
+        };
+        if bytes.len() != ram.len() {
+            return Err(CartError::RamSizeMismatch { expected: ram.len(), actual: bytes.len() })
+        }
+        ram.copy_from_slice(bytes);
+        self.mark_ram_saved();
+        Ok(())
+    }
+
+    // Overwrites the ROM byte currently mapped at a ROM address, instead of treating the write as an MBC register write.
+    // Writes that land past the end of the ROM image are dropped
+    pub fn patch_rom(&mut self, address: Address, value: Byte) {
+        let (data, offset) = match self.data {
+            Mapper::NoMBC(ref mut no_mbc_cart) => { let offset = no_mbc_cart.rom_offset(address); (&mut no_mbc_cart.data, offset) }
+            Mapper::MBC1(ref mut mbc1_cart) => { let offset = mbc1_cart.rom_offset(address); (&mut mbc1_cart.data, offset) }
+            Mapper::MBC3(ref mut mbc3_cart) => { let offset = mbc3_cart.rom_offset(address); (&mut mbc3_cart.data, offset) }
+            Mapper::MBC5(ref mut mbc5_cart) => { let offset = mbc5_cart.rom_offset(address); (&mut mbc5_cart.data, offset) }
+        };
+        if let Some(byte) = data.get_mut(offset) {
+            *byte = value;
+        }
+    }
+
+    // Overwrites the RAM byte currently mapped at an external RAM address (0xA000-0xBFFF), whether or not the game has RAM
+    // enabled. Nothing happens with an MBC3 clock register mapped instead of RAM
+    pub fn patch_ram(&mut self, address: Address, value: Byte) {
+        let offset = match self.data {
+            Mapper::NoMBC(_) => return,
+            Mapper::MBC1(ref mbc1_cart) => mbc1_cart.ram_offset(address),
+            Mapper::MBC3(ref mbc3_cart) => match mbc3_cart.active_ram_bank {
+                0x08..=0x0C => return,
+                _ => mbc3_cart.ram_offset(address)
+            }
+            Mapper::MBC5(ref mbc5_cart) => mbc5_cart.ram_offset(address)
+        };
+        self.patch_ram_offset(offset, value);
+    }
+
+    // Same as patch_ram, but into the given RAM bank instead of the mapped one. Banks past the end of the RAM wrap around
+    pub fn patch_ram_bank(&mut self, bank: Byte, address: Address, value: Byte) {
+        let bank_count = std::cmp::max(self.ram().len() / RAM_BANK_WIDTH, 1);
+        self.patch_ram_offset(((address as usize) & 0x1FFF) + ((bank as usize) % bank_count) * RAM_BANK_WIDTH, value);
+    }
+
+    // Writes into the RAM image directly. Only counts as a change to the save if the byte was actually different.
+    // Offsets past the end of the RAM are dropped
+    fn patch_ram_offset(&mut self, offset: usize, value: Byte) {
+        let (ram, dirty) = match self.data {
+            Mapper::NoMBC(_) => return,
+            Mapper::MBC1(ref mut mbc1_cart) => (&mut mbc1_cart.ram_banks, &mut mbc1_cart.ram_dirty),
+            Mapper::MBC3(ref mut mbc3_cart) => (&mut mbc3_cart.ram_banks, &mut mbc3_cart.ram_dirty),
+            Mapper::MBC5(ref mut mbc5_cart) => (&mut mbc5_cart.ram_banks, &mut mbc5_cart.ram_dirty)
+        };
+        let offset = mirror_small_ram(ram, offset);
+        if let Some(byte) = ram.get_mut(offset) {
+            if *byte != value {
+                *byte = value;
+                *dirty = true;
+            }
+        }
+    }
+
+    // Whether RAM has been written since it was loaded or last marked saved
+    pub fn ram_dirty(&self) -> bool {
+        match self.data {
+            Mapper::NoMBC(_) => false,
+            Mapper::MBC1(ref mbc1_cart) => mbc1_cart.ram_dirty,
+            Mapper::MBC3(ref mbc3_cart) => mbc3_cart.ram_dirty,
+            Mapper::MBC5(ref mbc5_cart) => mbc5_cart.ram_dirty
+        }
+    }
+
+    // For whoever persists RAM to say it's caught up
+    pub fn mark_ram_saved(&mut self) {
+        match self.data {
+            Mapper::NoMBC(_) => (),
+            Mapper::MBC1(ref mut mbc1_cart) => mbc1_cart.ram_dirty = false,
+            Mapper::MBC3(ref mut mbc3_cart) => mbc3_cart.ram_dirty = false,
+            Mapper::MBC5(ref mut mbc5_cart) => mbc5_cart.ram_dirty = false
+        }
+    }
+
+    // Mapper registers back to how they power on, with RAM locked away and the first switchable bank mapped.
+    // RAM and the RTC's running time are battery backed so they carry on, but a latch that was only half done is forgotten
+    pub fn reset_registers(&mut self) {
+        match self.data {
+            Mapper::NoMBC(_) => (),
+            Mapper::MBC1(ref mut mbc1_cart) => {
+                mbc1_cart.active_rom_bank = 1;
+                mbc1_cart.ram_enabled = false;
+                mbc1_cart.active_ram_bank = 0;
+                mbc1_cart.banking_mode = BankingModeMBC1::Simple;
+            }
+            Mapper::MBC3(ref mut mbc3_cart) => {
+                mbc3_cart.active_rom_bank = 1;
+                mbc3_cart.ram_enabled = false;
+                mbc3_cart.active_ram_bank = 0;
+                mbc3_cart.rtc.latch_armed = false;
+            }
+            Mapper::MBC5(ref mut mbc5_cart) => {
+                mbc5_cart.active_rom_bank = 1;
+                mbc5_cart.ram_enabled = false;
+                mbc5_cart.active_ram_bank = 0;
+            }
+        }
     }
 }
 
-impl MemoryRegion for Cart {
-    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+impl Cart {
+    fn mapper_read<T: MemoryUnit>(&mut self, address: Address) -> T {
         match self.data {
             Mapper::NoMBC(ref mut no_mbc_cart) => {
                 no_mbc_cart.read(address)
@@ -383,6 +935,29 @@ impl MemoryRegion for Cart {
         }
     }
 
+    pub fn add_game_genie_code(&mut self, code: GameGenieCode) {
+        self.game_genie_codes.push(code);
+    }
+}
+
+impl MemoryRegion for Cart {
+    fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+        // Game Genie codes only patch ROM. Go byte by byte so a word read straddling a patched address still sees it
+        if self.game_genie_codes.is_empty() || address >= 0x8000 {
+            self.mapper_read(address)
+        }
+        else {
+            let mut bytes = [0 as Byte; std::mem::size_of::<memory_gb::Word>()];
+            let width = std::mem::size_of::<T>();
+            for (offset, byte) in bytes[..width].iter_mut().enumerate() {
+                let byte_address = address.wrapping_add(offset as Address);
+                let original: Byte = self.mapper_read(byte_address);
+                *byte = self.game_genie_codes.iter().fold(original, |value, code| code.apply(byte_address, value));
+            }
+            T::from_le_bytes(&bytes[..width])
+        }
+    }
+
     fn write<T: MemoryUnit>(&mut self, value: T, address: Address) -> () {
         match self.data {
             Mapper::NoMBC(ref mut no_mbc_cart) => {
@@ -400,3 +975,249 @@ impl MemoryRegion for Cart {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::input::Joypad;
+    use crate::memory_gb::MemoryMap;
+
+    // A blank ROM of the given number of banks with a mapper and RAM size code in the header.
+    // Each bank holds its own number at offset 0x0100, so reads show which bank is mapped
+    fn rom(mapper: Byte, ram_size_code: Byte, banks: usize) -> Vec<Byte> {
+        let mut rom = vec![0 as Byte; banks * ROM_BANK_WIDTH];
+        for bank in 1..banks {
+            rom[bank * ROM_BANK_WIDTH + 0x0100] = bank as Byte;
+        }
+        rom[MAPPER_TYPE_LOCATION] = mapper;
+        rom[ROM_SIZE_LOCATION] = (banks / 2).trailing_zeros() as Byte;
+        rom[RAM_SIZE_LOCATION] = ram_size_code;
+        rom
+    }
+
+    #[test]
+    fn set_ram_shows_through_the_ram_window_and_exports_unchanged() {
+        // MBC5 with four 8KiB banks
+        let mut cart = Cart::from_bytes(rom(0x1B, 0x03, 2), Box::new(ManualClock::default())).expect("MBC5 test ROM should be valid");
+        assert!(matches!(cart.set_ram(&[0; 10]), Err(CartError::RamSizeMismatch { expected: 0x8000, actual: 10 })));
+        let image = (0..0x8000).map(|i| (i * 7 + i / 0x2000) as Byte).collect::<Vec<Byte>>();
+        cart.set_ram(&image).expect("Image is the declared size");
+        assert!(!cart.ram_dirty());
+        let mut memory_data = MemoryMap::allocate(cart, Joypad::new());
+        {
+            let mut memory = MemoryMap::new(&mut memory_data);
+            memory.write(0x0A as Byte, 0x0000);
+            for bank in 0..4 {
+                memory.write(bank as Byte, 0x4000);
+                for address in [0xA000, 0xA123, 0xBFFF] {
+                    let offset = bank * RAM_BANK_WIDTH + (address as usize - 0xA000);
+                    assert_eq!(memory.read::<Byte>(address), image[offset], "bank {} address {:04X}", bank, address);
+                }
+            }
+        }
+        assert!(memory_data.cart().ram() == image);
+        assert!(!memory_data.cart().ram_dirty());
+    }
+
+    #[test]
+    fn malformed_roms_give_matching_errors() {
+        assert!(matches!(Cart::from_bytes(vec![0; 0x100], Box::new(ManualClock::default())), Err(CartError::TruncatedRom { expected: 0x150, actual: 0x100 })));
+        let mut short = rom(0x00, 0x00, 2);
+        short.truncate(0x5000);
+        assert!(matches!(Cart::from_bytes(short, Box::new(ManualClock::default())), Err(CartError::TruncatedRom { expected: 0x8000, actual: 0x5000 })));
+        let mut bad_rom_size = rom(0x00, 0x00, 2);
+        bad_rom_size[ROM_SIZE_LOCATION] = 0x52;
+        assert!(matches!(Cart::from_bytes(bad_rom_size, Box::new(ManualClock::default())), Err(CartError::BadHeader(reason)) if reason.contains("ROM size")));
+        let mut bad_ram_size = rom(0x00, 0x00, 2);
+        bad_ram_size[RAM_SIZE_LOCATION] = 0x09;
+        assert!(matches!(Cart::from_bytes(bad_ram_size, Box::new(ManualClock::default())), Err(CartError::BadHeader(reason)) if reason.contains("RAM size")));
+        assert!(matches!(Cart::from_bytes(rom(0xFC, 0x00, 2), Box::new(ManualClock::default())), Err(CartError::UnsupportedMapper(0xFC))));
+        assert!(matches!(Cart::load_from_file("/nonexistent/game.gb", Box::new(ManualClock::default())), Err(CartError::Io(_))));
+    }
+
+    #[test]
+    fn out_of_range_reads_float_high() {
+        // MBC3 with two ROM banks and one RAM bank
+        let mut cart = Cart::from_bytes(rom(0x13, 0x02, 2), Box::new(ManualClock::default())).expect("MBC3 test ROM should be valid");
+        cart.write(0x7F as Byte, 0x2000);
+        assert_eq!(cart.read::<Byte>(0x4100), 0xFF);
+        cart.write(0x0A as Byte, 0x0000);
+        cart.write(0x03 as Byte, 0x4000);
+        cart.write(0x12 as Byte, 0xA000);
+        assert_eq!(cart.read::<Byte>(0xA000), 0xFF);
+        assert!(!cart.ram_dirty());
+        // Nothing past the RAM window belongs to the cart
+        assert_eq!(cart.read::<Byte>(0xC000), 0xFF);
+    }
+
+    #[test]
+    fn mbc1_multicart_maps_the_second_game_from_its_upper_bits() {
+        // A 1 MiB MBC1 image with a second copy of the logo at bank 0x10 is wired as a multicart,
+        // where the upper bank bits move over by one and pick the game instead
+        let mut contents = rom(0x01, 0x00, 64);
+        contents[LOGO_LOCATION..(LOGO_LOCATION + NINTENDO_LOGO.len())].copy_from_slice(&NINTENDO_LOGO);
+        let second_logo = 0x10 * ROM_BANK_WIDTH + LOGO_LOCATION;
+        contents[second_logo..(second_logo + NINTENDO_LOGO.len())].copy_from_slice(&NINTENDO_LOGO);
+        let mut cart = Cart::from_bytes(contents.clone(), Box::new(ManualClock::default())).expect("multicart test ROM should be valid");
+        cart.write(0x01 as Byte, 0x4000);
+        cart.write(0x01 as Byte, 0x6000);
+        assert_eq!(cart.read::<Byte>(0x0100), 0x10);
+        cart.write(0x02 as Byte, 0x2000);
+        assert_eq!(cart.read::<Byte>(0x4100), 0x12);
+        cart.write(0x10 as Byte, 0x2000);
+        assert_eq!(cart.read::<Byte>(0x4100), 0x10);
+
+        // Without the second logo the same writes bank like any other large MBC1 cart
+        contents[second_logo] = 0x00;
+        let mut cart = Cart::from_bytes(contents, Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid");
+        cart.write(0x01 as Byte, 0x4000);
+        cart.write(0x01 as Byte, 0x6000);
+        assert_eq!(cart.read::<Byte>(0x0100), 0x20);
+        cart.write(0x02 as Byte, 0x2000);
+        assert_eq!(cart.read::<Byte>(0x4100), 0x22);
+    }
+
+    #[test]
+    fn small_ram_mirrors_across_the_window() {
+        // A 2KiB chip only decodes 11 address lines, so it shows up four times over 0xA000-0xBFFF
+        let mut cart = Cart::from_bytes(rom(0x03, 0x01, 2), Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid");
+        assert_eq!(cart.ram().len(), 0x800);
+        cart.write(0x0A as Byte, 0x0000);
+        cart.write(0x5A as Byte, 0xA812);
+        assert_eq!(cart.read::<Byte>(0xA012), 0x5A);
+        assert_eq!(cart.read::<Byte>(0xB812), 0x5A);
+        assert_eq!(cart.ram()[0x12], 0x5A);
+    }
+
+    #[test]
+    fn ram_writes_without_ram_are_dropped() {
+        // Header RAM size 0x00 means no chip, even with the enable register set
+        for mapper in [0x01, 0x11, 0x19] {
+            let mut cart = Cart::from_bytes(rom(mapper, 0x00, 4), Box::new(ManualClock::default())).expect("RAM-less test ROM should be valid");
+            cart.write(0x0A as Byte, 0x0000);
+            cart.write(0x42 as Byte, 0xA000);
+            assert_eq!(cart.read::<Byte>(0xA000), 0xFF);
+            assert!(!cart.ram_dirty());
+        }
+    }
+
+    #[test]
+    fn mbc5_bank_numbers_wrap_to_the_rom_size() {
+        let mut cart = Cart::from_bytes(rom(0x19, 0x00, 4), Box::new(ManualClock::default())).expect("MBC5 test ROM should be valid");
+        // Unlike MBC1, MBC5 really maps bank 0 when 0 is selected
+        for (selected, mapped) in [(10, 2), (3, 3), (0, 0), (1, 1), (4, 0)] {
+            cart.write(selected as Byte, 0x2000);
+            assert_eq!(cart.read::<Byte>(0x4100), mapped);
+        }
+        // The ninth bank bit is masked off too
+        cart.write(0x01 as Byte, 0x3000);
+        cart.write(0xFF as Byte, 0x2000);
+        assert_eq!(cart.read::<Byte>(0x4100), 3);
+    }
+
+    // Latches the RTC and reads back all five registers
+    fn latch_rtc(cart: &mut Cart) -> [Byte; 5] {
+        cart.write(0x00 as Byte, 0x6000);
+        cart.write(0x01 as Byte, 0x6000);
+        let mut registers = [0; 5];
+        for (register, value) in registers.iter_mut().enumerate() {
+            cart.write(0x08 + register as Byte, 0x4000);
+            *value = cart.read::<Byte>(0xA000);
+        }
+        registers
+    }
+
+    #[test]
+    fn rtc_latches_clock_time_honours_halt_and_clears_the_day_carry() {
+        let clock = ManualClock::new(1000);
+        // MBC3+TIMER+RAM+BATTERY
+        let mut cart = Cart::from_bytes(rom(0x10, 0x02, 2), Box::new(clock.clone())).expect("MBC3 test ROM should be valid");
+        cart.write(0x0A as Byte, 0x0000);
+        clock.advance(86400 + 3600 * 2 + 60 * 3 + 4);
+        // Nothing moves until the next latch
+        assert_eq!(latch_rtc(&mut cart), [4, 3, 2, 1, 0x00]);
+        clock.advance(10);
+        cart.write(0x08 as Byte, 0x4000);
+        assert_eq!(cart.read::<Byte>(0xA000), 4);
+        assert_eq!(latch_rtc(&mut cart), [14, 3, 2, 1, 0x00]);
+        // Halted, the written time holds however far the clock runs
+        cart.write(0x0C as Byte, 0x4000);
+        cart.write(0x40 as Byte, 0xA000);
+        clock.advance(500);
+        assert_eq!(latch_rtc(&mut cart), [14, 3, 2, 1, 0x40]);
+        // And picks up from there once the halt bit is cleared
+        cart.write(0x0C as Byte, 0x4000);
+        cart.write(0x00 as Byte, 0xA000);
+        clock.advance(6);
+        assert_eq!(latch_rtc(&mut cart), [20, 3, 2, 1, 0x00]);
+        // Running past day 511 sets the carry, which sticks across latches until written clear
+        clock.advance(511 * 86400);
+        assert_eq!(latch_rtc(&mut cart), [20, 3, 2, 0, 0x80]);
+        clock.advance(1);
+        assert_eq!(latch_rtc(&mut cart), [21, 3, 2, 0, 0x80]);
+        cart.write(0x0C as Byte, 0x4000);
+        cart.write(0x00 as Byte, 0xA000);
+        assert_eq!(latch_rtc(&mut cart), [21, 3, 2, 0, 0x00]);
+    }
+
+    // Fills in both header checksums the way the ROM's build tools would have
+    fn fix_checksums(contents: &mut [Byte]) {
+        contents[HEADER_CHECKSUM_LOCATION] = contents[TITLE_LOCATION..=VERSION_LOCATION].iter()
+            .fold(0 as Byte, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1));
+        let global_sum = contents.iter()
+            .enumerate()
+            .filter(|(address, _)| *address != GLOBAL_CHECKSUM_LOCATION && *address != GLOBAL_CHECKSUM_LOCATION + 1)
+            .fold(0 as memory_gb::Word, |sum, (_, &byte)| sum.wrapping_add(byte as memory_gb::Word));
+        contents[GLOBAL_CHECKSUM_LOCATION] = (global_sum >> 8) as Byte;
+        contents[GLOBAL_CHECKSUM_LOCATION + 1] = global_sum as Byte;
+    }
+
+    #[test]
+    fn header_info_for_a_known_rom() {
+        // MBC3+RAM+BATTERY, 64 KiB of ROM and 32 KiB of RAM, CGB enhanced with SGB functions and a new licensee code
+        let mut contents = rom(0x13, 0x03, 4);
+        contents[TITLE_LOCATION..(TITLE_LOCATION + 6)].copy_from_slice(b"POKEMO");
+        contents[CGB_FLAG_LOCATION] = 0x80;
+        contents[NEW_LICENSEE_LOCATION..(NEW_LICENSEE_LOCATION + 2)].copy_from_slice(b"01");
+        contents[SGB_FLAG_LOCATION] = 0x03;
+        contents[OLD_LICENSEE_LOCATION] = 0x33;
+        contents[VERSION_LOCATION] = 2;
+        fix_checksums(&mut contents);
+        let header = Cart::from_bytes(contents.clone(), Box::new(ManualClock::default())).expect("MBC3 test ROM should be valid").header();
+        assert_eq!(header.title, "POKEMO");
+        assert_eq!(header.cart_type_name(), "MBC3+RAM+BATTERY");
+        assert_eq!(header.rom_size, Some(0x10000));
+        assert_eq!(header.ram_size, Some(0x8000));
+        assert_eq!(header.cgb_support, CgbSupport::Enhanced);
+        assert!(header.sgb_support);
+        assert_eq!(header.licensee, "01");
+        assert_eq!(header.version, 2);
+        assert!(header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+        let info = header.to_string();
+        assert!(info.contains("Title:           POKEMO"), "{}", info);
+        assert!(info.contains("64 KiB") && info.contains("32 KiB") && info.contains("(ok)"), "{}", info);
+        // Touching the title breaks both checksums
+        contents[TITLE_LOCATION] = b'X';
+        let header = CartHeader::parse(&contents).expect("Header should still parse");
+        assert!(!header.header_checksum_valid);
+        assert!(!header.global_checksum_valid);
+        // Old licensee codes come out in hex
+        contents[OLD_LICENSEE_LOCATION] = 0x01;
+        assert_eq!(CartHeader::parse(&contents).expect("Header should still parse").licensee, "01");
+        assert!(CartHeader::parse(&contents[..0x100]).is_err());
+    }
+
+    #[test]
+    fn header_title_stops_at_the_cgb_flag_or_a_zero() {
+        let mut contents = rom(0x00, 0x00, 2);
+        contents[TITLE_LOCATION..NEW_LICENSEE_LOCATION].copy_from_slice(b"SIXTEEN LETTERS!");
+        assert_eq!(CartHeader::parse(&contents).expect("Header should parse").title, "SIXTEEN LETTERS!");
+        // The 16th byte is the CGB flag on CGB carts
+        contents[CGB_FLAG_LOCATION] = 0xC0;
+        assert_eq!(CartHeader::parse(&contents).expect("Header should parse").title, "SIXTEEN LETTERS");
+        contents[TITLE_LOCATION + 7] = 0x00;
+        assert_eq!(CartHeader::parse(&contents).expect("Header should parse").title, "SIXTEEN");
+    }
+}
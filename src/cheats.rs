@@ -0,0 +1,93 @@
+use crate::memory_gb::Address;
+use crate::memory_gb::Byte;
+
+// Game Genie codes sit between the cart and the bus, swapping out the byte read from one ROM address
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GameGenieCode {
+    pub address: Address,
+    pub replacement: Byte,
+    // When present, the patch only applies if the ROM holds this byte, so it doesn't fire on the wrong bank
+    pub compare: Option<Byte>
+}
+
+impl GameGenieCode {
+    // Codes look like ABC-DEF or ABC-DEF-GHI. AB is the new byte, FCDE the address with F inverted,
+    // and G/I the compare byte, scrambled by a rotate and XOR. H is a check digit and is ignored
+    pub fn parse(code: &str) -> Option<GameGenieCode> {
+        let digits = hex_digits(code)?;
+        if digits.len() != 6 && digits.len() != 9 {
+            return None
+        }
+        let replacement = (digits[0] << 4) | digits[1];
+        let address = (((digits[5] ^ 0xF) as Address) << 12)
+            | ((digits[2] as Address) << 8)
+            | ((digits[3] as Address) << 4)
+            | (digits[4] as Address);
+        let compare = if digits.len() == 9 {
+            Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA)
+        }
+        else {
+            None
+        };
+        Some(GameGenieCode { address, replacement, compare })
+    }
+
+    // The byte the bus sees at an address, given what the ROM actually holds there
+    pub fn apply(&self, address: Address, original: Byte) -> Byte {
+        let compare_matches = match self.compare {
+            Some(compare) => compare == original,
+            None => true
+        };
+        if address == self.address && compare_matches { self.replacement } else { original }
+    }
+}
+
+// GameShark codes poke a value into RAM once every frame
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GameSharkCode {
+    // External RAM bank the code was written for, usually 01. Only matters for addresses in cart RAM
+    pub bank: Byte,
+    pub address: Address,
+    pub value: Byte
+}
+
+impl GameSharkCode {
+    // Codes are 8 hex digits: bank, value, then the address low byte first
+    pub fn parse(code: &str) -> Option<GameSharkCode> {
+        let digits = hex_digits(code)?;
+        if digits.len() != 8 {
+            return None
+        }
+        let byte_at = |i: usize| (digits[i] << 4) | digits[i + 1];
+        Some(GameSharkCode {
+            bank: byte_at(0),
+            value: byte_at(2),
+            address: ((byte_at(6) as Address) << 8) | (byte_at(4) as Address)
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Cheat {
+    GameGenie(GameGenieCode),
+    GameShark(GameSharkCode)
+}
+
+impl Cheat {
+    // Tells the two formats apart by length, 8 digits is a GameShark code and 6 or 9 is a Game Genie code
+    pub fn parse(code: &str) -> Option<Cheat> {
+        match hex_digits(code)?.len() {
+            8 => GameSharkCode::parse(code).map(Cheat::GameShark),
+            _ => GameGenieCode::parse(code).map(Cheat::GameGenie)
+        }
+    }
+}
+
+// Hex digit values of a code, skipping the dashes. None if anything else shows up
+fn hex_digits(code: &str) -> Option<Vec<Byte>> {
+    code.trim()
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_digit(16).map(|digit| digit as Byte))
+        .collect::<Option<Vec<Byte>>>()
+}
@@ -0,0 +1,383 @@
+use std::path::{Path, PathBuf};
+
+use crate::display::window_scale;
+use gbzd::gameboy::CYCLES_PER_SECOND;
+use gbzd::memory_gb::RamInit;
+use gbzd::palette::{named_palette, Palette, Rgba, GREEN_PALETTE};
+use gbzd::processor::cpu::BootState;
+
+pub const USAGE: &str = "\
+Usage: gbzd [options] <ROM file> [Gameboy Doctor log]
+       gbzd --serial-test <ROM file>...
+       gbzd --info <ROM file>
+       gbzd --bench
+       gbzd --fuzz <runs>[:<first seed>]
+
+Options:
+  --scale <1|2|4|8|16|32>   Window scale factor (default 1)
+  --palette <name|colors>   green, gray, or pocket, or four comma separated hex colors lightest first (default green)
+  --save-dir <directory>    Where battery saves are kept (default next to the ROM)
+  --boot-rom <file>         Boot through a DMG or CGB boot ROM instead of skipping straight to the game
+  --ram-init <pattern>      What RAM holds at power on: zeros, ones, or random with an optional seed like random:1234 (default zeros)
+  --dmg                     Run Color-enhanced games as a plain Game Boy, which they do anyway until Color banking and palettes exist. Color-only games ignore it
+  --model <revision>        Start with the registers dmg0, dmg, mgb, sgb, cgb, or agb hardware leaves, for games that check (default fits the mode)
+  --no-audio                Accepted for compatibility, there's no audio output yet
+  --cycles-per-second <n>   Run faster or slower than hardware, which does 1048576 (default)
+  --turbo                   Start unthrottled
+  --ghosting                Blend frames together like the DMG's slow LCD
+  --terminal                Draw frames as text in the terminal instead of opening a window
+  --cheat <code>            Game Genie or GameShark code, can be given more than once
+  --link-listen <address>   Wait for another gbzd to plug into the link cable at an address like 0.0.0.0:5000
+  --link-connect <address>  Plug the link cable into another gbzd listening at an address like 192.168.1.2:5000
+  --trace                   Print every instruction, interrupt, and PPU mode change. Very slow
+  --debugger                Open the game paused in a debugger window instead. Only in builds with --features debugger
+  --symbols <file>          RGBDS .sym file, so the debugger shows labels instead of addresses for jumps and calls
+  --serial-test             Run test ROMs like blargg's cpu_instrs without a window until each reports Passed or Failed over serial
+  --info                    Print what the ROM's header says and exit
+  --bench                   Run the CPU and PPU benchmarks and exit
+  --fuzz <runs>             Run the CPU on that many random ROMs looking for panics, optionally starting from a seed like 100:1234";
+
+// What the binary was asked to do
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(Box<Options>),
+    // Print the header of a ROM without running it
+    Info(PathBuf),
+    Bench,
+    // Run the CPU over random ROMs, one per seed
+    Fuzz { runs: u64, first_seed: u64 }
+}
+
+// Which end of a TCP link cable this instance is
+#[derive(Debug, PartialEq)]
+pub enum LinkCable {
+    Listen(String),
+    Connect(String)
+}
+
+// Everything that can be set from the command line for a normal run
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub rom_path: PathBuf,
+    // Run headless against a Gameboy Doctor log instead of opening a window
+    pub log_path: Option<PathBuf>,
+    pub scale: usize,
+    pub palette: Palette,
+    pub save_dir: Option<PathBuf>,
+    pub boot_rom: Option<PathBuf>,
+    pub ram_init: RamInit,
+    // Take the DMG path on carts that would otherwise run in Color mode
+    pub force_dmg: bool,
+    // Hardware revision to report through the boot registers, if not the usual one for the mode
+    pub model: Option<BootState>,
+    pub cycles_per_second: u64,
+    pub turbo: bool,
+    pub ghosting: bool,
+    // Draw to the terminal instead of a window
+    pub terminal: bool,
+    pub cheats: Vec<String>,
+    pub link: Option<LinkCable>,
+    // Print every CPU and PPU event as it happens
+    pub trace: bool,
+    // Run inside the debugger window
+    pub debugger: bool,
+    // Labels for the debugger's disassembly
+    pub symbols: Option<PathBuf>,
+    // Run headless and exit with the result a test ROM prints over serial
+    pub serial_test: bool,
+    // Test ROMs to swap in one after another once the first has reported, for --serial-test
+    pub more_roms: Vec<PathBuf>
+}
+
+impl Options {
+    // The ROM's name with a .sav extension, in the save directory if one was given or next to the ROM otherwise
+    pub fn save_path(&self) -> PathBuf {
+        let file_name = self.rom_path.with_extension("sav");
+        let file_name = file_name.file_name().unwrap_or_default();
+        match self.save_dir {
+            Some(ref save_dir) => save_dir.join(file_name),
+            None => self.rom_path.parent().unwrap_or(Path::new("")).join(file_name)
+        }
+    }
+}
+
+// Reads the arguments after the program name. Options and positional arguments can come in any order
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let mut positional = vec![];
+    let mut scale = None;
+    let mut palette = None;
+    let mut save_dir = None;
+    let mut boot_rom = None;
+    let mut ram_init = RamInit::Zeros;
+    let mut force_dmg = false;
+    let mut model = None;
+    let mut cycles_per_second = CYCLES_PER_SECOND;
+    let mut turbo = false;
+    let mut ghosting = false;
+    let mut terminal = false;
+    let mut cheats = vec![];
+    let mut link = None;
+    let mut trace = false;
+    let mut debugger = false;
+    let mut symbols = None;
+    let mut serial_test = false;
+    let mut bench = false;
+    let mut fuzz = None;
+    let mut info = false;
+
+    while let Some(arg) = args.next() {
+        let mut value_for = |flag: &str| -> Result<String, String> {
+            args.next().ok_or_else(|| format!("{} needs a value", flag))
+        };
+        match arg.as_str() {
+            "--scale" => {
+                let value = value_for("--scale")?;
+                scale = match value.parse::<usize>().ok().filter(|&factor| window_scale(factor).is_some()) {
+                    Some(factor) => Some(factor),
+                    None => return Err(format!("--scale has to be 1, 2, 4, 8, 16, or 32, not {}", value))
+                };
+            }
+            "--palette" => {
+                let value = value_for("--palette")?;
+                palette = Some(parse_palette(&value)?);
+            }
+            "--save-dir" => save_dir = Some(PathBuf::from(value_for("--save-dir")?)),
+            "--boot-rom" => boot_rom = Some(PathBuf::from(value_for("--boot-rom")?)),
+            "--symbols" => symbols = Some(PathBuf::from(value_for("--symbols")?)),
+            "--cheat" => cheats.push(value_for("--cheat")?),
+            "--link-listen" | "--link-connect" => {
+                let value = value_for(&arg)?;
+                if link.is_some() {
+                    return Err(String::from("Only one of --link-listen and --link-connect can be given"))
+                }
+                link = Some(if arg == "--link-listen" { LinkCable::Listen(value) } else { LinkCable::Connect(value) });
+            }
+            "--ram-init" => {
+                let value = value_for("--ram-init")?;
+                ram_init = parse_ram_init(&value)?;
+            }
+            "--model" => {
+                let value = value_for("--model")?;
+                model = Some(parse_model(&value)?);
+            }
+            "--cycles-per-second" => {
+                let value = value_for("--cycles-per-second")?;
+                cycles_per_second = match value.parse::<u64>() {
+                    Ok(cycles) if cycles > 0 => cycles,
+                    _ => return Err(format!("--cycles-per-second has to be a number above 0, not {}", value))
+                };
+            }
+            // Nothing to switch off until there's an APU
+            "--no-audio" => (),
+            "--dmg" => force_dmg = true,
+            "--turbo" => turbo = true,
+            "--ghosting" => ghosting = true,
+            "--terminal" => terminal = true,
+            "--trace" => trace = true,
+            "--debugger" => debugger = true,
+            "--serial-test" => serial_test = true,
+            "--bench" => bench = true,
+            "--fuzz" => {
+                let value = value_for("--fuzz")?;
+                fuzz = Some(parse_fuzz(&value)?);
+            }
+            "--info" => info = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            _ => positional.push(arg)
+        }
+    }
+
+    if bench {
+        return Ok(Command::Bench)
+    }
+    if let Some((runs, first_seed)) = fuzz {
+        return Ok(Command::Fuzz { runs, first_seed })
+    }
+    let mut positional = positional.into_iter();
+    let rom_path = positional.next().ok_or_else(|| String::from("No ROM file given"))?;
+    if info {
+        return match positional.next() {
+            Some(extra) => Err(format!("Unexpected argument {}", extra)),
+            None => Ok(Command::Info(PathBuf::from(rom_path)))
+        }
+    }
+    // A serial test takes any number of ROMs, otherwise the only thing after the ROM is a log to check against
+    let (log_path, more_roms) = if serial_test {
+        (None, positional.map(PathBuf::from).collect::<Vec<PathBuf>>())
+    }
+    else {
+        let log_path = positional.next();
+        if let Some(extra) = positional.next() {
+            return Err(format!("Unexpected argument {}", extra))
+        }
+        (log_path.map(PathBuf::from), vec![])
+    };
+    let options = Options {
+        rom_path: PathBuf::from(rom_path),
+        log_path,
+        scale: scale.unwrap_or(1),
+        palette: palette.unwrap_or(GREEN_PALETTE),
+        save_dir,
+        boot_rom,
+        ram_init,
+        force_dmg,
+        model,
+        cycles_per_second,
+        turbo,
+        ghosting,
+        terminal,
+        cheats,
+        link,
+        trace,
+        debugger,
+        symbols,
+        serial_test,
+        more_roms
+    };
+    Ok(Command::Run(Box::new(options)))
+}
+
+fn parse_ram_init(value: &str) -> Result<RamInit, String> {
+    match value.split_once(':') {
+        None if value == "zeros" => Ok(RamInit::Zeros),
+        None if value == "ones" => Ok(RamInit::Ones),
+        None if value == "random" => Ok(RamInit::Random(0)),
+        Some(("random", seed)) => seed.parse::<u64>()
+            .map(RamInit::Random)
+            .map_err(|_| format!("--ram-init seed has to be a number, not {}", seed)),
+        _ => Err(format!("--ram-init has to be zeros, ones, or random, not {}", value))
+    }
+}
+
+fn parse_model(value: &str) -> Result<BootState, String> {
+    match value {
+        "dmg0" => Ok(BootState::Dmg0),
+        "dmg" => Ok(BootState::Dmg),
+        "mgb" => Ok(BootState::Mgb),
+        "sgb" => Ok(BootState::Sgb),
+        "cgb" => Ok(BootState::Cgb),
+        "agb" => Ok(BootState::Agb),
+        _ => Err(format!("--model has to be dmg0, dmg, mgb, sgb, cgb, or agb, not {}", value))
+    }
+}
+
+// A run count, with the first seed after a colon like 100:1234. Seeds start from 0 when it's left off
+fn parse_fuzz(value: &str) -> Result<(u64, u64), String> {
+    let (runs, first_seed) = value.split_once(':').unwrap_or((value, "0"));
+    match (runs.parse::<u64>(), first_seed.parse::<u64>()) {
+        (Ok(runs), Ok(first_seed)) => Ok((runs, first_seed)),
+        _ => Err(format!("--fuzz has to be a number of runs, optionally followed by a seed like 100:1234, not {}", value))
+    }
+}
+
+// Either a palette name or four hex colors like e0f8d0,88c070,346856,081820
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    if let Some(palette) = named_palette(value) {
+        return Ok(palette)
+    }
+    let colors = value.split(',')
+        .map(|color| u32::from_str_radix(color.trim().trim_start_matches('#'), 16).ok().filter(|&color| color <= 0xFFFFFF))
+        .collect::<Option<Vec<u32>>>();
+    match colors {
+        Some(colors) if colors.len() == 4 => Ok([Rgba::from_0rgb(colors[0]), Rgba::from_0rgb(colors[1]), Rgba::from_0rgb(colors[2]), Rgba::from_0rgb(colors[3])]),
+        _ => Err(format!("--palette has to be green, gray, pocket, or four comma separated hex colors, not {}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gbzd::palette::{GRAY_PALETTE, POCKET_PALETTE};
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_run(args: &[&str]) -> Options {
+        match parse(args) {
+            Ok(Command::Run(options)) => *options,
+            other => panic!("{:?} didn't parse as a run: {:?}", args, other)
+        }
+    }
+
+    #[test]
+    fn flags_map_onto_options() {
+        let options = parse_run(&[
+            "--scale", "4", "game.gb", "--palette", "pocket", "--save-dir", "/saves", "--boot-rom", "dmg.bin", "--no-audio", "--turbo",
+            "--ram-init", "random:42", "--dmg", "--model", "mgb", "--cycles-per-second", "2000000", "--terminal", "--trace", "--debugger",
+            "--symbols", "game.sym", "--cheat", "010238CD", "--cheat", "00A-17B-C49", "--link-connect", "localhost:5000"
+        ]);
+        assert_eq!(options.rom_path, PathBuf::from("game.gb"));
+        assert_eq!(options.log_path, None);
+        assert_eq!(options.scale, 4);
+        assert_eq!(options.palette, POCKET_PALETTE);
+        assert_eq!(options.save_dir, Some(PathBuf::from("/saves")));
+        assert_eq!(options.boot_rom, Some(PathBuf::from("dmg.bin")));
+        assert_eq!(options.ram_init, RamInit::Random(42));
+        assert!(options.force_dmg);
+        assert_eq!(options.model, Some(BootState::Mgb));
+        assert_eq!(options.cycles_per_second, 2_000_000);
+        assert!(options.turbo);
+        assert!(!options.ghosting);
+        assert!(options.terminal);
+        assert!(options.trace);
+        assert!(options.debugger);
+        assert_eq!(options.symbols, Some(PathBuf::from("game.sym")));
+        assert!(!options.serial_test);
+        assert!(options.more_roms.is_empty());
+        assert_eq!(options.cheats, vec!["010238CD".to_string(), "00A-17B-C49".to_string()]);
+        assert_eq!(options.link, Some(LinkCable::Connect("localhost:5000".to_string())));
+        assert_eq!(options.save_path(), PathBuf::from("/saves/game.sav"));
+    }
+
+    #[test]
+    fn defaults_and_positional_log() {
+        let options = parse_run(&["roms/game.gbc", "log.txt"]);
+        assert_eq!(options.log_path, Some(PathBuf::from("log.txt")));
+        assert_eq!(options.scale, 1);
+        assert_eq!(options.palette, GREEN_PALETTE);
+        assert_eq!(options.ram_init, RamInit::Zeros);
+        assert_eq!(options.model, None);
+        assert_eq!(options.cycles_per_second, CYCLES_PER_SECOND);
+        assert_eq!(options.link, None);
+        assert_eq!(options.symbols, None);
+        assert_eq!(options.save_path(), PathBuf::from("roms/game.sav"));
+        assert_eq!(parse_run(&["game.gb", "--link-listen", "0.0.0.0:5000"]).link, Some(LinkCable::Listen("0.0.0.0:5000".to_string())));
+        assert_eq!(parse_run(&["game.gb", "--palette", "#ffffff,aaaaaa,555555,000000"]).palette, GRAY_PALETTE);
+        let options = parse_run(&["--serial-test", "01.gb", "02.gb", "03.gb"]);
+        assert!(options.serial_test);
+        assert_eq!(options.log_path, None);
+        assert_eq!(options.more_roms, vec![PathBuf::from("02.gb"), PathBuf::from("03.gb")]);
+    }
+
+    #[test]
+    fn other_commands() {
+        assert_eq!(parse(&["--bench"]), Ok(Command::Bench));
+        assert_eq!(parse(&["--info", "game.gb"]), Ok(Command::Info(PathBuf::from("game.gb"))));
+        assert_eq!(parse(&["--fuzz", "100"]), Ok(Command::Fuzz { runs: 100, first_seed: 0 }));
+        assert_eq!(parse(&["--fuzz", "1:1234"]), Ok(Command::Fuzz { runs: 1, first_seed: 1234 }));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        for args in [
+            &[][..],
+            &["--scale", "3", "game.gb"],
+            &["--scale"],
+            &["--palette", "1,2,3", "game.gb"],
+            &["--ram-init", "random:x", "game.gb"],
+            &["--model", "gbc", "game.gb"],
+            &["--cycles-per-second", "0", "game.gb"],
+            &["--fuzz", "many"],
+            &["--link-listen"],
+            &["game.gb", "--symbols"],
+            &["--link-listen", ":5000", "--link-connect", "localhost:5000", "game.gb"],
+            &["--info"],
+            &["--wat", "game.gb"],
+            &["game.gb", "log.txt", "extra"]
+        ] {
+            assert!(parse(args).is_err(), "{:?} should have been rejected", args);
+        }
+    }
+}
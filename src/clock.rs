@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Source of wall-clock time for anything in the machine that tracks real time (the MBC3 RTC for now)
+// Keeping it behind a trait means tests and replays can pin time down instead of depending on the host
+pub trait Clock: Send {
+    // Whole seconds since some fixed point. Only differences between readings matter
+    fn now_seconds(&self) -> u64;
+}
+
+// Reads the host's clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_seconds(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+    }
+}
+
+// Clock that only moves when told to. Clones share the same time, so one handle can be kept to advance a clock
+// that has been handed off to a cart
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    seconds: Arc<AtomicU64>
+}
+
+impl ManualClock {
+    pub fn new(seconds: u64) -> ManualClock {
+        ManualClock { seconds: Arc::new(AtomicU64::new(seconds)) }
+    }
+
+    pub fn set(&self, seconds: u64) {
+        self.seconds.store(seconds, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.seconds.fetch_add(seconds, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_seconds(&self) -> u64 {
+        self.seconds.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::disassembler::{disassemble_range, Instruction};
+use crate::gameboy::Gameboy;
+use crate::memory_gb::{Address, Byte};
+use crate::ppu::DOTS_PER_FRAME;
+use crate::symbols::SymbolTable;
+use crate::tracer::CpuState;
+
+// Instructions shown from PC on
+pub const DISASSEMBLY_LINES: usize = 16;
+pub const MEMORY_VIEW_ROWS: usize = 16;
+pub const MEMORY_VIEW_COLUMNS: usize = 16;
+// How far a running machine gets each time the front end updates, a normal speed frame
+const CYCLES_PER_UPDATE: u32 = DOTS_PER_FRAME / 4;
+
+// Everything a debugger front end shows, kept apart from the drawing so it can be driven and checked without a window.
+// refresh copies what's needed out of the machine, so nothing here holds on to it between updates
+pub struct Debugger {
+    // Free running until a breakpoint, or paused waiting on step and continue
+    pub running: bool,
+    pub registers: CpuState,
+    pub disassembly: Vec<Instruction>,
+    // Where the hex view starts, the front end scrolls it around
+    pub memory_start: Address,
+    pub memory: Vec<Byte>,
+    // The breakpoint the last continue stopped on
+    pub hit_breakpoint: Option<Address>,
+    // Labels to show in the disassembly, empty unless a .sym file was loaded
+    pub symbols: SymbolTable
+}
+
+impl Debugger {
+    // Starts paused, so there's a chance to set breakpoints before anything runs
+    pub fn new(gameboy: &mut Gameboy) -> Debugger {
+        let mut debugger = Debugger {
+            running: false,
+            registers: gameboy.cpu_state(),
+            disassembly: vec![],
+            memory_start: 0xC000,
+            memory: vec![],
+            hit_breakpoint: None,
+            symbols: SymbolTable::default()
+        };
+        debugger.refresh(gameboy);
+        debugger
+    }
+
+    pub fn refresh(&mut self, gameboy: &mut Gameboy) {
+        self.registers = gameboy.cpu_state();
+        self.disassembly = disassemble_range(|address| gameboy.peek(address), self.registers.pc, DISASSEMBLY_LINES, &self.symbols);
+        let memory_start = self.memory_start;
+        self.memory = (0..MEMORY_VIEW_ROWS * MEMORY_VIEW_COLUMNS)
+            .map(|offset| gameboy.peek(memory_start.wrapping_add(offset as Address)))
+            .collect();
+    }
+
+    // Called once per front end frame. A running machine gets a frame's worth of cycles, stopping early at a breakpoint
+    pub fn update(&mut self, gameboy: &mut Gameboy) {
+        if self.running && gameboy.run_to_breakpoint(CYCLES_PER_UPDATE) {
+            self.running = false;
+            self.hit_breakpoint = Some(gameboy.cpu_state().pc);
+        }
+        self.refresh(gameboy);
+    }
+
+    // Runs one instruction, pausing first if need be
+    pub fn step(&mut self, gameboy: &mut Gameboy) {
+        self.running = false;
+        self.hit_breakpoint = None;
+        gameboy.debug_step();
+        self.refresh(gameboy);
+    }
+
+    pub fn resume(&mut self) {
+        self.running = true;
+        self.hit_breakpoint = None;
+    }
+
+    pub fn pause(&mut self) {
+        self.running = false;
+    }
+
+    pub fn toggle_breakpoint(&mut self, gameboy: &mut Gameboy, address: Address) {
+        if !gameboy.remove_breakpoint(address) {
+            gameboy.add_breakpoint(address);
+        }
+    }
+
+    pub fn set_symbols(&mut self, gameboy: &mut Gameboy, symbols: SymbolTable) {
+        self.symbols = symbols;
+        self.refresh(gameboy);
+    }
+
+    pub fn show_memory_at(&mut self, gameboy: &mut Gameboy, address: Address) {
+        self.memory_start = address;
+        self.refresh(gameboy);
+    }
+
+    // Z, N, H and C in F's bit order, with a dash for each one that's clear
+    pub fn flags(&self) -> String {
+        ['Z', 'N', 'H', 'C'].iter()
+            .enumerate()
+            .map(|(bit, &flag)| if self.registers.f & (0x80 >> bit) != 0 { flag } else { '-' })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_gb::tests::test_memory;
+
+    #[test]
+    fn stepping_moves_the_registers_disassembly_and_memory_view_along() {
+        // LD A,0x42; LD (0xC000),A; JR -2
+        let mut memory_data = test_memory(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let mut debugger = Debugger::new(&mut gameboy);
+        assert!(!debugger.running);
+        assert_eq!(debugger.registers.pc, 0x0100);
+        assert_eq!(debugger.disassembly[0].text, "LD A,$42");
+        assert_eq!(debugger.disassembly[1].text, "LD ($C000),A");
+        assert_eq!(debugger.disassembly.len(), DISASSEMBLY_LINES);
+        assert_eq!(debugger.memory[0], 0x00);
+
+        debugger.step(&mut gameboy);
+        assert_eq!(debugger.registers.pc, 0x0102);
+        assert_eq!(debugger.registers.a, 0x42);
+        assert_eq!(debugger.disassembly[0].text, "LD ($C000),A");
+        debugger.step(&mut gameboy);
+        assert_eq!(debugger.registers.pc, 0x0105);
+        assert_eq!(debugger.memory[0], 0x42);
+        // Post-boot DMG F has Z, H and C set
+        assert_eq!(debugger.flags(), "Z-HC");
+
+        // Paused, nothing moves
+        debugger.update(&mut gameboy);
+        assert_eq!(debugger.registers.pc, 0x0105);
+    }
+
+    #[test]
+    fn running_stops_at_a_breakpoint() {
+        // NOP; NOP; LD B,B; JR -2
+        let mut memory_data = test_memory(&[0x00, 0x00, 0x40, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let mut debugger = Debugger::new(&mut gameboy);
+        debugger.toggle_breakpoint(&mut gameboy, 0x0102);
+        debugger.resume();
+        debugger.update(&mut gameboy);
+        assert!(!debugger.running);
+        assert_eq!(debugger.hit_breakpoint, Some(0x0102));
+        assert_eq!(debugger.registers.pc, 0x0102);
+
+        // Toggling again clears it, and continuing runs the whole frame in the JR loop
+        debugger.toggle_breakpoint(&mut gameboy, 0x0102);
+        assert!(gameboy.breakpoints().is_empty());
+        debugger.resume();
+        debugger.update(&mut gameboy);
+        assert!(debugger.running);
+        assert_eq!(debugger.hit_breakpoint, None);
+        assert_eq!(debugger.registers.pc, 0x0103);
+    }
+
+    #[test]
+    fn loaded_symbols_show_up_in_the_disassembly() {
+        // CALL 0x0105; JR -2; RET
+        let mut memory_data = test_memory(&[0xCD, 0x05, 0x01, 0x18, 0xFE, 0xC9]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let mut debugger = Debugger::new(&mut gameboy);
+        assert_eq!(debugger.disassembly[0].text, "CALL $0105");
+        debugger.set_symbols(&mut gameboy, SymbolTable::parse("00:0105 Subroutine").unwrap());
+        assert_eq!(debugger.disassembly[0].text, "CALL Subroutine");
+    }
+}
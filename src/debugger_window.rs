@@ -0,0 +1,195 @@
+use std::path::{Path, PathBuf};
+
+use eframe::egui;
+use gbzd::debugger::{Debugger, MEMORY_VIEW_COLUMNS};
+use gbzd::gameboy::Gameboy;
+use gbzd::input::InputHandler;
+use gbzd::memory_gb::Address;
+use gbzd::palette::{resolve_shade, Palette};
+use gbzd::symbols::SymbolTable;
+
+// The debugger window. Every drawing concern lives here, what's shown and what the buttons do is in gbzd::debugger
+struct DebuggerApp {
+    gameboy: Gameboy<'static>,
+    input_handler: InputHandler,
+    debugger: Debugger,
+    palette: Palette,
+    save_path: PathBuf,
+    screen: Option<egui::TextureHandle>,
+    // What's typed into the breakpoint and memory address boxes, as hex
+    breakpoint_text: String,
+    memory_text: String
+}
+
+// Takes over the main thread until the window closes. The game runs inside it, paused to start with
+pub fn run(gameboy: Gameboy<'static>, input_handler: InputHandler, palette: Palette, save_path: PathBuf, symbols_path: Option<PathBuf>) {
+    let symbols = symbols_path.map(|path| load_symbols(&path)).unwrap_or_default();
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([900.0, 620.0]),
+        ..Default::default()
+    };
+    let result = eframe::run_native("gbzd debugger", options, Box::new(move |_| {
+        let mut gameboy = gameboy;
+        let mut debugger = Debugger::new(&mut gameboy);
+        debugger.set_symbols(&mut gameboy, symbols);
+        let memory_text = format!("{:04X}", debugger.memory_start);
+        Box::new(DebuggerApp {
+            gameboy,
+            input_handler,
+            debugger,
+            palette,
+            save_path,
+            screen: None,
+            breakpoint_text: String::new(),
+            memory_text
+        })
+    }));
+    if let Err(error) = result {
+        println!("Couldn't open the debugger window. {}", error);
+    }
+}
+
+// A file that can't be read or parsed leaves the disassembly showing plain addresses
+fn load_symbols(path: &Path) -> SymbolTable {
+    let symbols = std::fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| SymbolTable::parse(&text));
+    match symbols {
+        Ok(symbols) => {
+            println!("Loaded {} labels from {}", symbols.len(), path.display());
+            symbols
+        }
+        Err(error) => {
+            println!("Couldn't load symbols from {}. {}", path.display(), error);
+            SymbolTable::default()
+        }
+    }
+}
+
+fn parse_address(text: &str) -> Option<Address> {
+    Address::from_str_radix(text.trim().trim_start_matches('$').trim_start_matches("0x"), 16).ok()
+}
+
+impl DebuggerApp {
+    fn update_screen(&mut self, ctx: &egui::Context) {
+        let pixels = self.gameboy.display_handle()
+            .into_iter()
+            .map(|color| {
+                let rgba = resolve_shade(color, &self.palette);
+                egui::Color32::from_rgb(rgba.r, rgba.g, rgba.b)
+            })
+            .collect();
+        let image = egui::ColorImage { size: [160, 144], pixels };
+        match self.screen {
+            Some(ref mut screen) => screen.set(image, egui::TextureOptions::NEAREST),
+            None => self.screen = Some(ctx.load_texture("screen", image, egui::TextureOptions::NEAREST))
+        }
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Step").clicked() {
+                self.debugger.step(&mut self.gameboy);
+            }
+            if self.debugger.running {
+                if ui.button("Pause").clicked() {
+                    self.debugger.pause();
+                }
+            }
+            else if ui.button("Continue").clicked() {
+                self.debugger.resume();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Breakpoint");
+            ui.add(egui::TextEdit::singleline(&mut self.breakpoint_text).desired_width(48.0));
+            if ui.button("Toggle").clicked() {
+                if let Some(address) = parse_address(&self.breakpoint_text) {
+                    self.debugger.toggle_breakpoint(&mut self.gameboy, address);
+                }
+            }
+        });
+        if let Some(address) = self.debugger.hit_breakpoint {
+            ui.label(format!("Stopped at breakpoint {:04X}", address));
+        }
+    }
+
+    fn registers(&self, ui: &mut egui::Ui) {
+        let state = &self.debugger.registers;
+        ui.monospace(format!("AF {:02X}{:02X}  BC {:02X}{:02X}", state.a, state.f, state.b, state.c));
+        ui.monospace(format!("DE {:02X}{:02X}  HL {:02X}{:02X}", state.d, state.e, state.h, state.l));
+        ui.monospace(format!("SP {:04X}  PC {:04X}", state.sp, state.pc));
+        ui.monospace(format!("Flags {}", self.debugger.flags()));
+    }
+
+    // Clicking a line toggles a breakpoint on it. Labelled addresses get the label on a line of its own, like in the source
+    fn disassembly(&mut self, ui: &mut egui::Ui) {
+        let mut toggled = None;
+        for instruction in self.debugger.disassembly.iter() {
+            if let Some(label) = self.debugger.symbols.label(instruction.address) {
+                ui.monospace(format!("{}:", label));
+            }
+            let marker = if self.gameboy.breakpoints().contains(&instruction.address) { "●" } else { " " };
+            let bytes = instruction.bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(" ");
+            let line = format!("{} {:04X}  {:<9} {}", marker, instruction.address, bytes, instruction.text);
+            if ui.add(egui::Label::new(egui::RichText::new(line).monospace()).sense(egui::Sense::click())).clicked() {
+                toggled = Some(instruction.address);
+            }
+        }
+        if let Some(address) = toggled {
+            self.debugger.toggle_breakpoint(&mut self.gameboy, address);
+        }
+    }
+
+    fn memory_view(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Memory at");
+            let response = ui.add(egui::TextEdit::singleline(&mut self.memory_text).desired_width(48.0));
+            if response.lost_focus() {
+                if let Some(address) = parse_address(&self.memory_text) {
+                    self.debugger.show_memory_at(&mut self.gameboy, address);
+                }
+            }
+        });
+        for (row, bytes) in self.debugger.memory.chunks(MEMORY_VIEW_COLUMNS).enumerate() {
+            let address = self.debugger.memory_start.wrapping_add((row * MEMORY_VIEW_COLUMNS) as Address);
+            let hex = bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(" ");
+            ui.monospace(format!("{:04X}  {}", address, hex));
+        }
+    }
+}
+
+impl eframe::App for DebuggerApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.input_handler.poll(self.gameboy.memory_mut());
+        self.debugger.update(&mut self.gameboy);
+        self.update_screen(ctx);
+
+        egui::SidePanel::left("cpu").show(ctx, |ui| {
+            self.controls(ui);
+            ui.separator();
+            self.registers(ui);
+            ui.separator();
+            self.disassembly(ui);
+        });
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if let Some(ref screen) = self.screen {
+                ui.image((screen.id(), egui::vec2(320.0, 288.0)));
+            }
+            ui.separator();
+            self.memory_view(ui);
+        });
+        // Keep frames coming while the game runs, a paused one only needs redrawing when something's clicked
+        if self.debugger.running {
+            ctx.request_repaint();
+        }
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        match self.gameboy.shutdown() {
+            Ok(true) => println!("Saved to {}", self.save_path.display()),
+            Ok(false) => (),
+            Err(error) => println!("Couldn't save to {}. {}", self.save_path.display(), error)
+        }
+    }
+}
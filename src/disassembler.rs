@@ -0,0 +1,197 @@
+use crate::memory_gb::{Address, Byte, Word};
+use crate::symbols::SymbolTable;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const REGISTER_PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+// PUSH and POP swap SP for AF
+const STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP "];
+const ROTATES: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ACCUMULATOR_OPS: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+// One decoded instruction, as it sits in memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub address: Address,
+    pub bytes: Vec<Byte>,
+    pub text: String
+}
+
+impl Instruction {
+    pub fn next_address(&self) -> Address {
+        self.address.wrapping_add(self.bytes.len() as Address)
+    }
+}
+
+// Decodes the instruction at address, fetching bytes through read so it works on live memory or a plain buffer.
+// Opcodes the CPU doesn't have come out as a single DB byte
+pub fn disassemble<F: FnMut(Address) -> Byte>(read: F, address: Address) -> Instruction {
+    disassemble_with_symbols(read, address, &SymbolTable::default())
+}
+
+// Same as disassemble, with jumps and calls to a labelled address showing the label instead
+pub fn disassemble_with_symbols<F: FnMut(Address) -> Byte>(mut read: F, address: Address, symbols: &SymbolTable) -> Instruction {
+    let opcode = read(address);
+    let byte_at = |read: &mut F, offset: Address| read(address.wrapping_add(offset));
+    let (text, length) = if opcode == 0xCB {
+        (decode_cb(byte_at(&mut read, 1)), 2)
+    }
+    else {
+        let immediate_byte = byte_at(&mut read, 1);
+        let immediate_word = Word::from_le_bytes([immediate_byte, byte_at(&mut read, 2)]);
+        // Relative jumps show where they land rather than the offset
+        let relative_target = address.wrapping_add(2).wrapping_add(immediate_byte as i8 as Address);
+        let name = |target: Address| symbols.label(target).map(String::from).unwrap_or_else(|| format!("${:04X}", target));
+        decode(opcode, immediate_byte, immediate_word, &name(immediate_word), &name(relative_target))
+    };
+    Instruction {
+        address,
+        bytes: (0..length).map(|offset| byte_at(&mut read, offset)).collect(),
+        text
+    }
+}
+
+// Decodes count instructions back to back, starting at address
+pub fn disassemble_range<F: FnMut(Address) -> Byte>(mut read: F, address: Address, count: usize, symbols: &SymbolTable) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let instruction = disassemble_with_symbols(&mut read, address, symbols);
+        address = instruction.next_address();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// The opcode split into its octal fields, x = bits 7-6, y = bits 5-3, z = bits 2-0, as the instruction table is laid out.
+// jump_target and relative_target are already formatted, as a label or an address
+fn decode(opcode: Byte, d8: Byte, a16: Word, jump_target: &str, relative_target: &str) -> (String, Address) {
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x7) as usize;
+    let z = opcode & 0x7;
+    let p = y >> 1;
+    let q = y & 1;
+    let e8 = d8 as i8;
+    let signed = if e8 < 0 { format!("-${:02X}", e8.unsigned_abs()) } else { format!("+${:02X}", e8) };
+    match (x, z) {
+        (0, 0) => match y {
+            0 => (String::from("NOP"), 1),
+            1 => (format!("LD (${:04X}),SP", a16), 3),
+            2 => (String::from("STOP"), 2),
+            3 => (format!("JR {}", relative_target), 2),
+            _ => (format!("JR {},{}", CONDITIONS[y - 4], relative_target), 2)
+        },
+        (0, 1) if q == 0 => (format!("LD {},${:04X}", REGISTER_PAIRS[p], a16), 3),
+        (0, 1) => (format!("ADD HL,{}", REGISTER_PAIRS[p]), 1),
+        (0, 2) => {
+            let pointer = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 { (format!("LD {},A", pointer), 1) } else { (format!("LD A,{}", pointer), 1) }
+        }
+        (0, 3) => (format!("{} {}", if q == 0 { "INC" } else { "DEC" }, REGISTER_PAIRS[p]), 1),
+        (0, 4) => (format!("INC {}", REGISTERS[y]), 1),
+        (0, 5) => (format!("DEC {}", REGISTERS[y]), 1),
+        (0, 6) => (format!("LD {},${:02X}", REGISTERS[y], d8), 2),
+        (0, _) => (String::from(ACCUMULATOR_OPS[y]), 1),
+        (1, _) if opcode == 0x76 => (String::from("HALT"), 1),
+        (1, _) => (format!("LD {},{}", REGISTERS[y], REGISTERS[z as usize]), 1),
+        (2, _) => (format!("{}{}", ALU[y], REGISTERS[z as usize]), 1),
+        (_, 0) => match y {
+            0..=3 => (format!("RET {}", CONDITIONS[y]), 1),
+            4 => (format!("LDH (${:02X}),A", d8), 2),
+            5 => (format!("ADD SP,{}", signed), 2),
+            6 => (format!("LDH A,(${:02X})", d8), 2),
+            _ => (format!("LD HL,SP{}", signed), 2)
+        },
+        (_, 1) if q == 0 => (format!("POP {}", STACK_PAIRS[p]), 1),
+        (_, 1) => (String::from(["RET", "RETI", "JP HL", "LD SP,HL"][p]), 1),
+        (_, 2) => match y {
+            0..=3 => (format!("JP {},{}", CONDITIONS[y], jump_target), 3),
+            4 => (String::from("LD (C),A"), 1),
+            5 => (format!("LD (${:04X}),A", a16), 3),
+            6 => (String::from("LD A,(C)"), 1),
+            _ => (format!("LD A,(${:04X})", a16), 3)
+        },
+        (_, 3) => match y {
+            0 => (format!("JP {}", jump_target), 3),
+            6 => (String::from("DI"), 1),
+            7 => (String::from("EI"), 1),
+            _ => (format!("DB ${:02X}", opcode), 1)
+        },
+        (_, 4) if y < 4 => (format!("CALL {},{}", CONDITIONS[y], jump_target), 3),
+        (_, 5) if q == 0 => (format!("PUSH {}", STACK_PAIRS[p]), 1),
+        (_, 5) if p == 0 => (format!("CALL {}", jump_target), 3),
+        (_, 6) => (format!("{}${:02X}", ALU[y], d8), 2),
+        (_, 7) => (format!("RST ${:02X}", y * 8), 1),
+        _ => (format!("DB ${:02X}", opcode), 1)
+    }
+}
+
+fn decode_cb(opcode: Byte) -> String {
+    let y = ((opcode >> 3) & 0x7) as usize;
+    let register = REGISTERS[(opcode & 0x7) as usize];
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATES[y], register),
+        1 => format!("BIT {},{}", y, register),
+        2 => format!("RES {},{}", y, register),
+        _ => format!("SET {},{}", y, register)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_of(program: &[Byte]) -> Vec<String> {
+        let mut texts = vec![];
+        let mut address = 0;
+        while (address as usize) < program.len() {
+            let instruction = disassemble(|at| program.get(at as usize).copied().unwrap_or(0), address);
+            address = instruction.next_address();
+            texts.push(instruction.text);
+        }
+        texts
+    }
+
+    #[test]
+    fn decodes_each_kind_of_operand() {
+        assert_eq!(text_of(&[
+            0x00, 0x08, 0x34, 0x12, 0x01, 0xCD, 0xAB, 0x09, 0x2A, 0x3A, 0x13, 0x35, 0x3E, 0x42, 0x17, 0x76, 0x78, 0x46, 0xA8, 0xBE,
+            0xE0, 0x44, 0xF0, 0x0F, 0xE8, 0xFE, 0xF8, 0x05, 0xF1, 0xE9, 0xE2, 0xFA, 0x00, 0xC0, 0xC0, 0xCA, 0x50, 0x01, 0xCD, 0x00, 0x40,
+            0xC5, 0xFE, 0x90, 0xFF, 0xD3, 0xCB, 0x37, 0xCB, 0x7E, 0xCB, 0x87, 0xCB, 0xFF, 0x10, 0x00
+        ]), vec![
+            "NOP", "LD ($1234),SP", "LD BC,$ABCD", "ADD HL,BC", "LD A,(HL+)", "LD A,(HL-)", "INC DE", "DEC (HL)", "LD A,$42", "RLA",
+            "HALT", "LD A,B", "LD B,(HL)", "XOR B", "CP (HL)", "LDH ($44),A", "LDH A,($0F)", "ADD SP,-$02", "LD HL,SP+$05", "POP AF",
+            "JP HL", "LD (C),A", "LD A,($C000)", "RET NZ", "JP Z,$0150", "CALL $4000", "PUSH BC", "CP $90", "RST $38", "DB $D3",
+            "SWAP A", "BIT 7,(HL)", "RES 0,A", "SET 7,A", "STOP"
+        ]);
+    }
+
+    #[test]
+    fn relative_jumps_show_their_target_and_lengths_add_up() {
+        // JR -2 at 0x0100 jumps to itself, JR NZ,+5 at 0x0102 lands on 0x0109
+        let mut memory = vec![0 as Byte; 0x10000];
+        memory[0x0100..0x0104].copy_from_slice(&[0x18, 0xFE, 0x20, 0x05]);
+        let instructions = disassemble_range(|at| memory[at as usize], 0x0100, 2, &SymbolTable::default());
+        assert_eq!(instructions[0], Instruction { address: 0x0100, bytes: vec![0x18, 0xFE], text: String::from("JR $0100") });
+        assert_eq!(instructions[1].text, "JR NZ,$0109");
+        assert_eq!(instructions[1].next_address(), 0x0104);
+        // Wraps at the top of memory rather than running off the end
+        memory[0xFFFF] = 0xC3;
+        assert_eq!(disassemble(|at| memory[at as usize], 0xFFFF).next_address(), 0x0002);
+    }
+
+    #[test]
+    fn jumps_and_calls_to_labelled_addresses_show_the_label() {
+        let symbols = SymbolTable::parse("00:0150 Main\n00:0200 DrawSprites\n00:c000 wCounter").unwrap();
+        // CALL 0x0200; CALL NZ,0x0300; JP 0x0150; JR -2; LD A,(0xC000)
+        let program = [0xCD, 0x00, 0x02, 0xC4, 0x00, 0x03, 0xC3, 0x50, 0x01, 0x18, 0xFE, 0xFA, 0x00, 0xC0];
+        let mut memory = vec![0 as Byte; 0x10000];
+        memory[0x0150..(0x0150 + program.len())].copy_from_slice(&program);
+        let texts = disassemble_range(|at| memory[at as usize], 0x0150, 5, &symbols).into_iter()
+            .map(|instruction| instruction.text)
+            .collect::<Vec<String>>();
+        // Unlabelled targets keep their address, and data accesses aren't jumps so they stay as they are
+        assert_eq!(texts, ["CALL DrawSprites", "CALL NZ,$0300", "JP Main", "JR $0159", "LD A,($C000)"]);
+    }
+}
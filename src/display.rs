@@ -1,16 +1,34 @@
+use std::io::Write;
 use std::str::FromStr;
 
-use minifb::{Icon, Key, ScaleMode, Window, WindowOptions};
+use minifb::{Icon, Key, Scale, ScaleMode, Window, WindowOptions};
 
+use gbzd::ppu::{Color, ScreenRect};
+
+
+// minifb only scales the window by powers of two
+pub fn window_scale(factor: usize) -> Option<Scale> {
+    match factor {
+        1 => Some(Scale::X1),
+        2 => Some(Scale::X2),
+        4 => Some(Scale::X4),
+        8 => Some(Scale::X8),
+        16 => Some(Scale::X16),
+        32 => Some(Scale::X32),
+        _ => None
+    }
+}
 
 pub struct DisplayMiniFB {
     pub width: usize,
     pub height: usize,
-    window: Window
+    window: Window,
+    // Blend each frame with the one before it to mimic the slow response of the DMG LCD
+    ghosting: Option<FrameBlender>
 }
 
 impl DisplayMiniFB {
-    pub fn new() -> Self {
+    pub fn new(scale: Scale) -> Self {
         const WIDTH: usize = 160;
         const HEIGHT: usize = 144;
 
@@ -20,6 +38,7 @@ impl DisplayMiniFB {
             HEIGHT,
             WindowOptions {
                 resize: true,
+                scale,
                 scale_mode: ScaleMode::UpperLeft,
                 ..WindowOptions::default()
             },
@@ -34,11 +53,196 @@ impl DisplayMiniFB {
         DisplayMiniFB {
             width: WIDTH,
             height: HEIGHT,
-            window
+            window,
+            ghosting: None
         }
     }
 
+    // False once the user closes the window
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    // Switching it on starts over from whatever frame comes next
+    pub fn set_ghosting(&mut self, enabled: bool) {
+        self.ghosting = if enabled { Some(FrameBlender::default()) } else { None };
+    }
+
     pub fn update(&mut self, color_buffer: &Vec<u32>) {
-        self.window.update_with_buffer(color_buffer, self.width, self.height).unwrap();
+        if let Some(blender) = self.ghosting.as_mut() {
+            let blended = blender.blend(color_buffer);
+            self.window.update_with_buffer(&blended, self.width, self.height).unwrap();
+        }
+        else {
+            self.window.update_with_buffer(color_buffer, self.width, self.height).unwrap();
+        }
+    }
+}
+
+// Average two 0RGB frames channel by channel.
+// Games that flicker a sprite on alternating frames for fake transparency rely on the LCD smearing them together like this
+pub fn blend_frames(current: &[u32], previous: &[u32]) -> Vec<u32> {
+    current.iter()
+        .zip(previous.iter())
+        .map(|(&a, &b)| {
+            let blend_channel = |shift: u32| -> u32 {
+                let channel_a = (a >> shift) & 0xFF;
+                let channel_b = (b >> shift) & 0xFF;
+                ((channel_a + channel_b) / 2) << shift
+            };
+            blend_channel(16) | blend_channel(8) | blend_channel(0)
+        })
+        .collect::<Vec<u32>>()
+}
+
+// Blends each frame with the one shown before it. The first frame has nothing before it, so it goes through as is
+// instead of fading in from black
+#[derive(Default)]
+pub struct FrameBlender {
+    previous_frame: Option<Vec<u32>>
+}
+
+impl FrameBlender {
+    pub fn blend(&mut self, current: &[u32]) -> Vec<u32> {
+        let previous = self.previous_frame.get_or_insert_with(|| current.to_vec());
+        let blended = blend_frames(current, previous);
+        previous.copy_from_slice(current);
+        blended
+    }
+}
+
+// Outline colors for the debug overlay, picked to stand out against any of the green shades
+pub const OVERLAY_VIEWPORT_COLOR: u32 = 0xFF0000;
+pub const OVERLAY_WINDOW_COLOR: u32 = 0x0080FF;
+pub const OVERLAY_OBJECT_COLOR: u32 = 0xFF00FF;
+
+// Outlines every object, the window, and the edge of the viewport on a finished 0RGB frame.
+// Handy for spotting things drawn in the wrong place, whether that's the game's fault or the emulator's
+pub fn draw_debug_overlay(frame: &mut [u32], width: usize, objects: &[ScreenRect], window: Option<ScreenRect>) {
+    for object in objects {
+        draw_outline(frame, width, *object, OVERLAY_OBJECT_COLOR);
+    }
+    if let Some(window) = window {
+        draw_outline(frame, width, window, OVERLAY_WINDOW_COLOR);
+    }
+    let viewport = ScreenRect {
+        x: 0,
+        y: 0,
+        width: width as i16,
+        height: (frame.len() / width) as i16
+    };
+    draw_outline(frame, width, viewport, OVERLAY_VIEWPORT_COLOR);
+}
+
+// Draws the one pixel border of a rectangle, skipping whatever falls outside the frame
+fn draw_outline(frame: &mut [u32], width: usize, rect: ScreenRect, color: u32) {
+    let height = (frame.len() / width) as i16;
+    let mut plot = |x: i16, y: i16| {
+        if x >= 0 && y >= 0 && x < width as i16 && y < height {
+            frame[y as usize * width + x as usize] = color;
+        }
+    };
+    let right = rect.x + rect.width - 1;
+    let bottom = rect.y + rect.height - 1;
+    for x in rect.x..=right {
+        plot(x, rect.y);
+        plot(x, bottom);
+    }
+    for y in rect.y..=bottom {
+        plot(rect.x, y);
+        plot(right, y);
+    }
+}
+
+// Draws frames as shaded characters in the terminal, for headless machines or poking at things over SSH
+pub struct DisplayTerminal {
+    pub width: usize
+}
+
+impl DisplayTerminal {
+    pub fn new() -> Self {
+        DisplayTerminal {
+            width: 160
+        }
+    }
+
+    pub fn update(&mut self, frame: &[Color]) {
+        let text = render_frame_text(frame, self.width);
+        let mut stdout = std::io::stdout().lock();
+        // Jump back to the top left so each frame draws over the last one instead of scrolling
+        let _ = write!(stdout, "\x1b[H{}", text);
+        let _ = stdout.flush();
+    }
+}
+
+// Turns a frame into lines of shade characters, lightest to darkest.
+// Terminal cells are about twice as tall as they are wide, so each character covers two rows and shows the darker of the pair
+pub fn render_frame_text(frame: &[Color], width: usize) -> String {
+    const SHADES: [char; 4] = [' ', '░', '▒', '█'];
+    let rows = frame.chunks(width).collect::<Vec<&[Color]>>();
+    let mut text = String::with_capacity((width + 1) * (rows.len() + 1) / 2 * 3);
+    for row_pair in rows.chunks(2) {
+        for x in 0..width {
+            let darkest = row_pair.iter()
+                .map(|row| row[x].to_value())
+                .max()
+                .unwrap_or(0);
+            text.push(SHADES[darkest as usize]);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_frame_is_half_height_with_darker_of_each_row_pair() {
+        let text = render_frame_text(&[Color::D; 160 * 144], 160);
+        assert_eq!(text.lines().count(), 72);
+        assert!(text.lines().all(|line| line.chars().count() == 160 && line.chars().all(|shade| shade == '█')));
+        let text = render_frame_text(&[Color::A; 160 * 144], 160);
+        assert!(text.lines().all(|line| line.chars().all(|shade| shade == ' ')));
+        // One light row over one mid row draws as the mid shade
+        let mut frame = vec![Color::A; 4];
+        frame.extend([Color::B; 4]);
+        assert_eq!(render_frame_text(&frame, 4), "░░░░\n");
+    }
+
+    #[test]
+    fn ghosting_shows_the_first_frame_as_is_then_blends_to_the_midpoint() {
+        let mut blender = FrameBlender::default();
+        let light = vec![0xE0F8D0; 4];
+        let dark = vec![0x081820; 4];
+        assert_eq!(blender.blend(&light), light);
+        let midpoint = (((0xE0 + 0x08) / 2) << 16) | (((0xF8 + 0x18) / 2) << 8) | ((0xD0 + 0x20) / 2);
+        assert_eq!(blender.blend(&dark), vec![midpoint; 4]);
+        assert_eq!(blender.blend(&dark), dark);
+    }
+
+    #[test]
+    fn overlay_outlines_the_viewport_window_and_objects() {
+        let mut frame = vec![0; 160 * 144];
+        // An object hanging off the left edge, and a window in the bottom right
+        let object = ScreenRect { x: -4, y: 10, width: 8, height: 16 };
+        let window = ScreenRect { x: 100, y: 50, width: 60, height: 94 };
+        draw_debug_overlay(&mut frame, 160, &[object], Some(window));
+        // The viewport border goes on last, so it's on top all the way round
+        for x in 0..160 {
+            assert_eq!(frame[x], OVERLAY_VIEWPORT_COLOR);
+            assert_eq!(frame[143 * 160 + x], OVERLAY_VIEWPORT_COLOR);
+        }
+        for y in 0..144 {
+            assert_eq!(frame[y * 160], OVERLAY_VIEWPORT_COLOR);
+            assert_eq!(frame[y * 160 + 159], OVERLAY_VIEWPORT_COLOR);
+        }
+        assert_eq!(frame[50 * 160 + 100], OVERLAY_WINDOW_COLOR);
+        assert_eq!(frame[10 * 160 + 3], OVERLAY_OBJECT_COLOR);
+        assert_eq!(frame[25 * 160 + 3], OVERLAY_OBJECT_COLOR);
+        // Only outlines, the insides are left alone
+        assert_eq!(frame[12 * 160 + 2], 0);
+        assert_eq!(frame[60 * 160 + 110], 0);
     }
 }
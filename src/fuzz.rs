@@ -0,0 +1,77 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use gbzd::cart::Cart;
+use gbzd::clock::ManualClock;
+use gbzd::gameboy::Gameboy;
+use gbzd::input::Joypad;
+use gbzd::memory_gb::{Address, Byte, MemoryMap, Xorshift64};
+
+// Long enough to get through a few frames, so interrupts and the PPU get a say too
+const FUZZ_STEPS: u32 = 50_000;
+const ROM_SIZE: usize = 0x8000;
+
+// Programs that have caught out emulators before. Each one goes at the entry point of a run with noise everywhere else,
+// and the first runs use them before the harness falls back to pure noise
+const SEED_PROGRAMS: [&[Byte]; 14] = [
+    &[0xFF],                                    // RST 38 into a ROM full of RST 38, recursing until SP wraps
+    &[0x31, 0xFF, 0xFF, 0xF1, 0xF1],            // LD SP,0xFFFF, POP AF twice so SP wraps past the top
+    &[0x31, 0x01, 0x00, 0xC5, 0xC5],            // LD SP,0x0001, PUSH BC twice so SP wraps past the bottom
+    &[0xF8, 0x7F, 0xF8, 0x80, 0xE8, 0x80],      // LD HL,SP+127, LD HL,SP-128, ADD SP,-128
+    &[0xC3, 0xFD, 0xFF],                        // JP 0xFFFD, PC runs off the top of the address space
+    &[0xC3, 0xFF, 0x7F],                        // JP 0x7FFF, an instruction straddling ROM and VRAM
+    &[0x3E, 0x99, 0xC6, 0x99, 0x27, 0xD6, 0xFF, 0x27],  // DAA after adds and subtracts that carry out
+    &[0xF3, 0x3E, 0x1F, 0xE0, 0xFF, 0xE0, 0x0F, 0x76],  // DI, every interrupt pending and enabled, HALT
+    &[0xFB, 0x76, 0x76, 0x10, 0x00],            // EI, HALT, HALT, STOP
+    &[0x3E, 0xFE, 0xE0, 0x46, 0x3E, 0xDF, 0xE0, 0x46],  // OAM DMA from 0xFE00 and from the echo RAM mirror
+    &[0xAF, 0xE0, 0x40, 0x3E, 0x80, 0xE0, 0x40, 0xE0, 0x44],  // LCD off and back on, then a write to LY
+    &[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00],      // Arm KEY1 and STOP for a speed switch
+    &[0x21, 0x00, 0xFE, 0x36, 0xFF, 0x2C, 0x20, 0xFB],  // Fill OAM with 0xFF, objects off every edge
+    &[0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD]  // Every opcode that doesn't exist
+];
+
+// Throws random ROMs at the CPU, one per seed starting from first_seed, and reports every seed that panicked.
+// A run is the seed's ROM from power on for a fixed number of steps, so any failing seed can be replayed on its own
+pub fn run(runs: u64, first_seed: u64) -> bool {
+    let mut memory_data = MemoryMap::allocate(Cart::from_bytes(vec![0 as Byte; ROM_SIZE], Box::new(ManualClock::default())).expect("Blank ROM should always be valid"), Joypad::new());
+    let mut gameboy = Gameboy::new(&mut memory_data, true);
+    let mut failures = vec![];
+    for seed in first_seed..first_seed.saturating_add(runs) {
+        let rom = fuzz_rom(seed);
+        {
+            let memory = gameboy.memory_mut();
+            for (address, byte) in rom.iter().enumerate() {
+                memory.cart_mut().patch_rom(address as Address, *byte);
+            }
+        }
+        gameboy.reset();
+        let outcome = catch_unwind(AssertUnwindSafe(|| {
+            for _ in 0..FUZZ_STEPS {
+                gameboy.step();
+            }
+        }));
+        if outcome.is_err() {
+            println!("Seed {} panicked", seed);
+            failures.push(seed);
+        }
+    }
+    match failures.len() {
+        0 => println!("All {} runs finished without a panic", runs),
+        count => println!("{} of {} runs panicked. Replay one with --fuzz 1:<seed>. Seeds: {:?}", count, runs, failures)
+    }
+    failures.is_empty()
+}
+
+// Noise for the whole ROM, interrupt vectors included, with a seed program over the entry point for the first few seeds.
+// The header isn't looked at again once the cart is loaded, so it's fair game too
+fn fuzz_rom(seed: u64) -> Vec<Byte> {
+    let mut noise = Xorshift64::new(seed);
+    let mut rom = (0..ROM_SIZE).map(|_| noise.next_byte()).collect::<Vec<Byte>>();
+    if let Some(program) = SEED_PROGRAMS.get(seed as usize) {
+        rom[0x0100..(0x0100 + program.len())].copy_from_slice(program);
+        // RST 38 only keeps recursing if there's another one waiting at 0x0038
+        if program == &[0xFF] {
+            rom[0x0038] = 0xFF;
+        }
+    }
+    rom
+}
@@ -0,0 +1,1150 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::autosave::Autosave;
+use crate::cart::{Cart, CgbSupport};
+use crate::cheats::{Cheat, GameSharkCode};
+use crate::memory_gb::{Address, Byte, MemoryMap, MemoryMapData, MemoryRegion};
+use crate::input::update_buttons;
+use crate::oam::OamEntry;
+use crate::palette::{Palette, Rgba};
+use crate::ppu::{Color, ColorIndex, Ppu, ScreenRect, DOTS_PER_FRAME, DOTS_PER_LINE};
+use crate::processor::cpu::{BootState, Cpu, WordRegisterName};
+use crate::serial::{CollectingSink, SerialLink, SerialSink};
+use crate::tracer::{CpuState, Tracer};
+
+// Dots tick at the 4.194304 MHz master clock, regardless of CPU speed
+const DOTS_PER_SECOND: u64 = 4_194_304;
+// M-cycles a second at normal speed, one for every 4 dots
+pub const CYCLES_PER_SECOND: u64 = DOTS_PER_SECOND / 4;
+// Wall time one frame takes on hardware, a little under 16.743ms (59.73Hz)
+pub const FRAME_DURATION: Duration = Duration::from_nanos((DOTS_PER_FRAME as u64 * 1_000_000_000) / DOTS_PER_SECOND);
+
+// Which hardware the system is pretending to be
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GameboyMode {
+    Dmg,
+    Cgb
+}
+
+impl GameboyMode {
+    // Pick the hardware a cart wants to run on. Color-enhanced carts take their DMG path for now, since without WRAM banking
+    // or color palette RAM their Color path can't draw properly. Color-only carts get Color mode even if DMG is forced
+    pub fn select(cgb_support: CgbSupport, force_dmg: bool) -> GameboyMode {
+        match (cgb_support, force_dmg) {
+            (CgbSupport::None, _) => GameboyMode::Dmg,
+            (CgbSupport::Enhanced, _) => GameboyMode::Dmg,
+            (CgbSupport::Only, _) => GameboyMode::Cgb
+        }
+    }
+
+    // Why select went against what was asked for, if it did, for the front end to pass on
+    pub fn select_warning(cgb_support: CgbSupport, force_dmg: bool) -> Option<&'static str> {
+        match (cgb_support, force_dmg) {
+            (CgbSupport::Only, true) => Some("Cart only runs on a Game Boy Color, ignoring request to force DMG mode"),
+            _ => None
+        }
+    }
+}
+
+// Owns the CPU, PPU and the memory map between them, lending the memory to each in turn and keeping them in step with each other.
+// The whole machine is Send so it can run on a thread of its own, which is why everything plugged into it has to be Send as well
+pub struct Gameboy<'a> {
+    pub mode: GameboyMode,
+    // Kept so a cart loaded later picks its mode the same way the first one did
+    force_dmg: bool,
+    // Hardware revision the registers start out as, when it isn't the usual one for the mode
+    boot_state: Option<BootState>,
+    cpu: Cpu,
+    ppu: Ppu,
+    memory: MemoryMap<'a>,
+    // Debt represents the timing balance between cpu and ppu.
+    // The cpu runs up the debt (positive)
+    // The ppu pays down the debt (negative)
+    // The ppu only has to do work if its debt is greater than 0
+    debt: i16,
+    cpu_locked: bool,
+    // When false, frames are run as fast as the host allows
+    throttled: bool,
+    // How fast the whole machine runs when throttled, in normal speed M-cycles a second
+    cycles_per_second: u64,
+    // RAM pokes applied at the start of every VBlank
+    gameshark_codes: Vec<GameSharkCode>,
+    was_in_vblank: bool,
+    // Frames that have reached VBlank since power on
+    frame_count: u64,
+    // Called with the frame number each time the PPU enters VBlank
+    on_vblank: Option<Box<dyn FnMut(u64) + Send>>,
+    // Keeps battery RAM flushed to disk while running and once more at shutdown
+    autosave: Option<Autosave>,
+    // PCs run_to_breakpoint stops at. They belong to whoever is debugging, so they outlast resets and cart swaps
+    breakpoints: BTreeSet<Address>
+}
+
+impl<'a> Gameboy<'a> {
+    pub fn new(memory_data: &'a mut MemoryMapData, force_dmg: bool) -> Gameboy<'a> {
+        let mode = GameboyMode::select(memory_data.cgb_support(), force_dmg);
+        let mut memory = MemoryMap::new(memory_data);
+        memory.mode = mode;
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.set_boot_state(BootState::for_mode(mode));
+        let ppu = Ppu::new();
+        Gameboy {
+            mode,
+            force_dmg,
+            boot_state: None,
+            cpu,
+            ppu,
+            memory,
+            debt: 0,
+            cpu_locked: false,
+            throttled: true,
+            cycles_per_second: CYCLES_PER_SECOND,
+            gameshark_codes: vec![],
+            was_in_vblank: false,
+            frame_count: 0,
+            on_vblank: None,
+            autosave: None,
+            breakpoints: BTreeSet::new()
+        }
+    }
+
+    // The memory map, for devices like input that live outside the core
+    pub fn memory_mut(&mut self) -> &mut MemoryMap<'a> {
+        &mut self.memory
+    }
+
+    // Run whichever of the cpu or ppu has fallen behind the other by one unit of work
+    pub fn step(&mut self) {
+        self.step_cycles();
+    }
+
+    // Same as step, reporting how many M-cycles the cpu spent. PPU steps cost nothing
+    fn step_cycles(&mut self) -> u32 {
+        if self.debt <= 0 && !self.cpu_locked {
+            let cycles = self.cpu.run(&mut self.memory);
+            self.memory.poll_serial_link();
+            let payment = cycles as i16 * self.dots_per_cycle() as i16;
+            self.debt += payment;
+            if payment == 0 {
+                self.cpu_locked = true;
+                // A halted or stopped cpu still burns an M-cycle waiting around
+                return 1
+            }
+            cycles as u32
+        }
+        else {
+            if self.cpu_locked {
+                self.ppu.run(&mut self.memory);
+                self.cpu_locked = false
+            }
+            else {
+                self.debt -= self.ppu.run(&mut self.memory);
+            }
+            self.check_vblank_start();
+            0
+        }
+    }
+
+    // An M-cycle is 4 dots, or 2 when running at double speed. The PPU is tied to dots so it keeps the same pace either way
+    fn dots_per_cycle(&self) -> u32 {
+        if self.cpu.double_speed { 2 } else { 4 }
+    }
+
+    // Things that happen once a frame as the PPU enters VBlank
+    fn check_vblank_start(&mut self) {
+        let in_vblank = self.ppu.in_vblank();
+        if in_vblank && !self.was_in_vblank {
+            self.frame_count += 1;
+            for code in self.gameshark_codes.iter() {
+                self.memory.apply_gameshark_code(code);
+            }
+            if let Some(on_vblank) = self.on_vblank.as_mut() {
+                on_vblank(self.frame_count);
+            }
+            if let Some(autosave) = self.autosave.as_mut() {
+                if let Err(error) = autosave.on_frame(self.frame_count, self.memory.cart_mut()) {
+                    println!("Couldn't autosave to {}. {}", autosave.path().display(), error);
+                }
+            }
+        }
+        self.was_in_vblank = in_vblank;
+    }
+
+    // Anything the front end should tell the user about the mode the cart ended up running in
+    pub fn mode_warning(&self) -> Option<&'static str> {
+        GameboyMode::select_warning(self.memory.cart().cgb_support(), self.force_dmg)
+    }
+
+    // How many frames have reached VBlank so far. The first one is frame 1
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Run something once a frame as VBlank starts, like an FPS counter or autosave. Replaces any earlier callback
+    pub fn set_on_vblank(&mut self, on_vblank: Option<Box<dyn FnMut(u64) + Send>>) {
+        self.on_vblank = on_vblank;
+    }
+
+    // Flush battery RAM to a save file every so often while running. Replaces any earlier autosave
+    pub fn set_autosave(&mut self, autosave: Option<Autosave>) {
+        self.autosave = autosave;
+    }
+
+    // Wrap things up before the process goes away, writing battery RAM out one last time if it changed.
+    // Reports whether anything was saved
+    pub fn shutdown(&mut self) -> std::io::Result<bool> {
+        match self.autosave.as_mut() {
+            Some(autosave) => autosave.flush(self.memory.cart_mut()),
+            None => Ok(false)
+        }
+    }
+
+    // Swap in a different game without tearing everything down. Battery RAM is saved first, and nothing changes if that fails.
+    // The machine then starts over from power on with the new cart. Autosave is switched off since its file belongs to the
+    // old game, and cheats go with the old game too
+    pub fn load_cart(&mut self, cart: Cart) -> std::io::Result<()> {
+        self.shutdown()?;
+        self.autosave = None;
+        self.gameshark_codes.clear();
+        self.memory.swap_cart(cart);
+        self.reset();
+        Ok(())
+    }
+
+    // Power cycle with the same cart in. Tracers, serial output, and speed settings stay as they were
+    pub fn reset(&mut self) {
+        let mode = GameboyMode::select(self.memory.cart_mut().cgb_support(), self.force_dmg);
+        self.memory.reset();
+        self.memory.mode = mode;
+        let cpu_tracer = self.cpu.tracer.take();
+        let ppu_tracer = self.ppu.tracer.take();
+        self.cpu = Cpu::new(&mut self.memory);
+        self.cpu.set_boot_state(self.boot_state.unwrap_or(BootState::for_mode(mode)));
+        self.cpu.tracer = cpu_tracer;
+        self.ppu = Ppu::new();
+        self.ppu.tracer = ppu_tracer;
+        self.mode = mode;
+        self.debt = 0;
+        self.cpu_locked = false;
+        self.was_in_vblank = false;
+        self.frame_count = 0;
+    }
+
+    // Run until the PPU enters VBlank, stopping right on the step that got it there.
+    // Gives up after two frames worth of cycles, which only happens if the LCD is off
+    pub fn run_until_vblank(&mut self) -> bool {
+        let max_cycles = 2 * DOTS_PER_FRAME / self.dots_per_cycle();
+        let mut cycles = 0;
+        while cycles < max_cycles {
+            let was_in_vblank = self.was_in_vblank;
+            cycles += self.step_cycles();
+            if self.was_in_vblank && !was_in_vblank {
+                return true
+            }
+        }
+        false
+    }
+
+    // Run until the PPU moves on to the next line, handing back the pixels of the line it just finished.
+    // VBlank lines have no pixels. Neither does a line cut short by the LCD switching off, or the wait while it's off and LY never moves
+    pub fn step_scanline(&mut self) -> Option<Vec<Color>> {
+        let line = self.ppu.current_scanline();
+        let next_line = ((line as u32 + 1) % (DOTS_PER_FRAME / DOTS_PER_LINE)) as u8;
+        let max_cycles = 2 * DOTS_PER_LINE / self.dots_per_cycle();
+        let mut cycles = 0;
+        while cycles < max_cycles {
+            cycles += self.step_cycles();
+            let current_line = self.ppu.current_scanline();
+            if current_line == next_line {
+                return self.ppu.scanline_pixels(line)
+            }
+            else if current_line != line {
+                return None
+            }
+        }
+        None
+    }
+
+    // Run until the cpu is about to execute the instruction at an address, or the M-cycle budget runs out.
+    // Returns right away if the cpu is already sitting there
+    pub fn run_until_pc(&mut self, address: Address, max_cycles: u32) -> bool {
+        let mut cycles = 0;
+        loop {
+            if self.cpu.registers.read_word(WordRegisterName::RegPC) == address {
+                return true
+            }
+            if cycles >= max_cycles {
+                return false
+            }
+            cycles += self.step_cycles();
+        }
+    }
+
+    // Run exactly this many instructions and report where the PC ended up. The PPU keeps pace as usual, and time spent
+    // halted or stopped passes by without counting. A cpu that sits idle for two whole frames isn't coming back, so that gives up early
+    pub fn step_instructions(&mut self, count: u64) -> Address {
+        let target = self.cpu.instructions + count;
+        let max_idle_cycles = 2 * DOTS_PER_FRAME / self.dots_per_cycle();
+        let mut idle_cycles = 0;
+        while self.cpu.instructions < target && idle_cycles < max_idle_cycles {
+            let instructions = self.cpu.instructions;
+            let cycles = self.step_cycles();
+            if self.cpu.instructions == instructions {
+                idle_cycles += cycles;
+            }
+            else {
+                idle_cycles = 0;
+            }
+        }
+        self.cpu.registers.read_word(WordRegisterName::RegPC)
+    }
+
+    // Run a single instruction, for debuggers
+    pub fn debug_step(&mut self) -> Address {
+        self.step_instructions(1)
+    }
+
+    // Run until the cpu is about to execute an instruction at a breakpoint, or the M-cycle budget runs out. The instruction it
+    // starts on always runs, so continuing from a breakpoint doesn't stop where it already is. Returns whether a breakpoint was hit
+    pub fn run_to_breakpoint(&mut self, max_cycles: u32) -> bool {
+        let start = self.cpu.instructions;
+        let mut cycles = 0;
+        loop {
+            let pc = self.cpu.registers.read_word(WordRegisterName::RegPC);
+            if self.cpu.instructions != start && self.breakpoints.contains(&pc) {
+                return true
+            }
+            if cycles >= max_cycles {
+                return false
+            }
+            cycles += self.step_cycles();
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: Address) {
+        self.breakpoints.insert(address);
+    }
+
+    // Returns whether there was one there to remove
+    pub fn remove_breakpoint(&mut self, address: Address) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<Address> {
+        &self.breakpoints
+    }
+
+    // What the cpu would read at an address, without it showing up in memory traces
+    pub fn peek(&mut self, address: Address) -> Byte {
+        self.memory.peek(address)
+    }
+
+    // The registers and the bytes at PC
+    pub fn cpu_state(&mut self) -> CpuState {
+        self.cpu.with_memory(&mut self.memory).state()
+    }
+
+    // Run until a test ROM prints one of the given results over serial, or max_frames frames go by without any of them.
+    // The sink has to be a clone of the one serial output is going to. Hands back the first result in the list that showed up
+    pub fn run_until_serial<'r>(&mut self, sink: &CollectingSink, results: &[&'r str], max_frames: u64) -> Option<&'r str> {
+        let cycles_per_frame = DOTS_PER_FRAME / self.dots_per_cycle();
+        for _ in 0..max_frames {
+            let mut cycles = 0;
+            while cycles < cycles_per_frame {
+                cycles += self.step_cycles();
+            }
+            let text = sink.text();
+            if let Some(result) = results.iter().find(|result| text.contains(*result)) {
+                return Some(result)
+            }
+        }
+        None
+    }
+
+    // Pretend to be a different hardware revision as far as the boot registers go, for games that check. Sticks through resets
+    pub fn set_boot_state(&mut self, boot_state: BootState) {
+        self.boot_state = Some(boot_state);
+        self.cpu.set_boot_state(boot_state);
+    }
+
+    // Boot through a real boot ROM instead of starting from the post-boot state. It sets up the registers and hands over to the cart itself
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<Byte>) -> Result<(), String> {
+        if boot_rom.len() != 0x100 && boot_rom.len() != 0x900 {
+            return Err(format!("boot ROM is {} bytes, expected 256 (DMG) or 2304 (CGB)", boot_rom.len()))
+        }
+        for register in [WordRegisterName::RegAF, WordRegisterName::RegBC, WordRegisterName::RegDE, WordRegisterName::RegHL, WordRegisterName::RegSP, WordRegisterName::RegPC] {
+            self.cpu.registers.write_word(register, 0x0000);
+        }
+        // The boot ROM clears VRAM right away, which only works with the LCD off like it is at power on
+        self.memory.write(0x00 as Byte, 0xFF40);
+        self.memory.set_boot_rom(boot_rom);
+        Ok(())
+    }
+
+    // Start executing somewhere other than the usual 0x0100 entry point, for test ROMs and debugging
+    pub fn set_pc(&mut self, address: Address) {
+        self.cpu.registers.write_word(WordRegisterName::RegPC, address);
+    }
+
+    pub fn set_sp(&mut self, address: Address) {
+        self.cpu.registers.write_word(WordRegisterName::RegSP, address);
+    }
+
+    // Send serial output somewhere other than stdout
+    pub fn set_serial_sink(&mut self, sink: Box<dyn SerialSink>) {
+        self.memory.set_serial_sink(sink);
+    }
+
+    // Plug a link cable into the serial port, or pull it out with None
+    pub fn set_serial_link(&mut self, link: Option<Box<dyn SerialLink>>) {
+        self.memory.set_serial_link(link);
+    }
+
+    // The CPU registers and counters as a JSON object
+    pub fn cpu_state_json(&mut self) -> String {
+        self.cpu.state_json()
+    }
+
+    // Watch every instruction the CPU runs, or stop watching with None
+    pub fn set_cpu_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.cpu.tracer = tracer;
+    }
+
+    // Watch the PPU change modes, or stop watching with None
+    pub fn set_ppu_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.ppu.tracer = tracer;
+    }
+
+    // Hold down exactly the buttons in the mask, one BUTTON_* bit each, and let go of the rest. Skips past any input devices,
+    // for scripted runs like pressing Start on frame 120. Presses raise the joypad interrupt like real ones
+    pub fn set_buttons(&mut self, mask: Byte) {
+        update_buttons(&mut self.memory, !mask);
+    }
+
+    // Report every memory access the CPU makes to the tracer, instruction by instruction. Slows things down a lot
+    pub fn set_memory_trace(&mut self, enabled: bool) {
+        self.memory.access_log = if enabled { Some(vec![]) } else { None };
+    }
+
+    // Takes a Game Genie or GameShark code, reporting whether it could be understood
+    pub fn add_cheat(&mut self, code: &str) -> bool {
+        match Cheat::parse(code) {
+            Some(Cheat::GameGenie(game_genie_code)) => {
+                self.memory.add_game_genie_code(game_genie_code);
+                true
+            }
+            Some(Cheat::GameShark(gameshark_code)) => {
+                self.gameshark_codes.push(gameshark_code);
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn frame_is_ready(&mut self) -> bool {
+        self.ppu.frame_is_ready()
+    }
+
+    // How long a frame should take in real time. Set by the clock speed normally, no wait at all when unthrottled
+    pub fn frame_duration(&self) -> Duration {
+        if self.throttled {
+            Duration::from_nanos((DOTS_PER_FRAME as u64 * 1_000_000_000) / (4 * self.cycles_per_second))
+        }
+        else {
+            Duration::ZERO
+        }
+    }
+
+    pub fn set_throttled(&mut self, throttled: bool) {
+        self.throttled = throttled;
+    }
+
+    // Over or underclock everything, CPU and PPU alike. CYCLES_PER_SECOND is hardware speed
+    pub fn set_cycles_per_second(&mut self, cycles_per_second: u64) {
+        self.cycles_per_second = cycles_per_second.max(1);
+    }
+
+    pub fn display_handle(&self) -> Vec<Color> {
+        self.ppu.display_handle()
+    }
+
+    pub fn index_handle(&self) -> Vec<ColorIndex> {
+        self.ppu.index_handle()
+    }
+
+    // Read-only access to the PPU, for where it is in the frame
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    // The PPU's VRAM/OAM debug views, see Ppu for what each shows
+    pub fn tile_data_view(&mut self, shades: &Palette) -> Vec<Rgba> {
+        self.ppu.tile_data_view(&mut self.memory, shades)
+    }
+
+    pub fn background_map_view(&mut self, shades: &Palette) -> Vec<Rgba> {
+        self.ppu.background_map_view(&mut self.memory, shades)
+    }
+
+    pub fn oam_view(&mut self) -> Vec<OamEntry> {
+        self.ppu.oam_view(&mut self.memory)
+    }
+
+    pub fn object_bounds(&mut self) -> Vec<ScreenRect> {
+        self.ppu.object_bounds(&mut self.memory)
+    }
+
+    pub fn window_bounds(&mut self) -> Option<ScreenRect> {
+        self.ppu.window_bounds(&mut self.memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::input::{Joypad, BUTTON_START, BUTTON_UP};
+    use crate::processor::cpu::ByteRegisterName;
+    use crate::serial::TcpLink;
+    use crate::memory_gb::{RamInit, Word};
+    use crate::memory_gb::tests::{test_cart, test_memory};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use crate::tracer::{AccessKind, CollectingTracer, CpuState, LogComparer, LogComparison, MemoryAccess, StateLogLine, TraceEvent};
+
+    // MBC3 with an RTC. Sets the day counter ahead of the clock, then spins latching the seconds into WRAM at 0xC000
+    const RTC_PROGRAM: [Byte; 36] = [
+        0x3E, 0x0A, 0xEA, 0x00, 0x00,   // LD A,0x0A; LD (0x0000),A   enable RAM and RTC
+        0x3E, 0x0B, 0xEA, 0x00, 0x40,   // LD A,0x0B; LD (0x4000),A   select the day counter
+        0x3E, 0x05, 0xEA, 0x00, 0xA0,   // LD A,0x05; LD (0xA000),A   day 5
+        0x3E, 0x08, 0xEA, 0x00, 0x40,   // LD A,0x08; LD (0x4000),A   select seconds
+        0xAF, 0xEA, 0x00, 0x60,         // XOR A; LD (0x6000),A
+        0x3C, 0xEA, 0x00, 0x60,         // INC A; LD (0x6000),A       latch
+        0xFA, 0x00, 0xA0,               // LD A,(0xA000)
+        0xEA, 0x00, 0xC0,               // LD (0xC000),A
+        0x18, 0xF0                      // JR back to the latch
+    ];
+
+    fn rtc_memory(clock: &ManualClock) -> MemoryMapData {
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0147] = 0x10;
+        rom[0x0149] = 0x02;
+        rom[0x0100..(0x0100 + RTC_PROGRAM.len())].copy_from_slice(&RTC_PROGRAM);
+        MemoryMap::allocate(Cart::from_bytes(rom, Box::new(clock.clone())).expect("MBC3 test ROM should be valid"), Joypad::new())
+    }
+
+    // Everything the CPU can see plus its registers, standing in for a save state
+    fn snapshot(gameboy: &mut Gameboy) -> Vec<Byte> {
+        let mut state = (0..=0xFFFF).map(|address| gameboy.memory.read::<Byte>(address)).collect::<Vec<Byte>>();
+        state.extend((0..12).map(|register| gameboy.cpu.registers.read::<Byte>(register)));
+        state
+    }
+
+    #[test]
+    fn same_clock_and_inputs_give_same_state() {
+        let clock = ManualClock::new(0);
+        let mut memory_a = rtc_memory(&clock);
+        let mut memory_b = rtc_memory(&clock);
+        let mut gameboy_a = Gameboy::new(&mut memory_a, false);
+        let mut gameboy_b = Gameboy::new(&mut memory_b, false);
+        for frame in 0..10 {
+            clock.advance(3);
+            for gameboy in [&mut gameboy_a, &mut gameboy_b] {
+                gameboy.set_buttons(frame as Byte);
+                while !gameboy.frame_is_ready() {
+                    gameboy.step();
+                }
+            }
+        }
+        // The day counter went in ahead of the clock 3 seconds in, and the clock is at 30 now
+        assert_eq!(gameboy_a.memory.read::<Byte>(0xC000), 27);
+        assert!(snapshot(&mut gameboy_a) == snapshot(&mut gameboy_b));
+        assert!(gameboy_a.display_handle() == gameboy_b.display_handle());
+    }
+
+    fn memory_with_cgb_flag(flag: Byte) -> MemoryMapData {
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0143] = flag;
+        MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("Blank ROM should always be valid"), Joypad::new())
+    }
+
+    #[test]
+    fn cgb_flag_picks_the_mode_and_boot_registers() {
+        // Color-enhanced carts stay on their DMG path until there's Color banking and palette RAM
+        for (flag, force_dmg, mode, a, warned) in [
+            (0x00, false, GameboyMode::Dmg, 0x01, false),
+            (0x80, false, GameboyMode::Dmg, 0x01, false),
+            (0x80, true, GameboyMode::Dmg, 0x01, false),
+            (0xC0, false, GameboyMode::Cgb, 0x11, false),
+            (0xC0, true, GameboyMode::Cgb, 0x11, true)
+        ] {
+            let mut memory_data = memory_with_cgb_flag(flag);
+            let mut gameboy = Gameboy::new(&mut memory_data, force_dmg);
+            assert_eq!(gameboy.mode, mode, "flag {:02X}", flag);
+            assert_eq!(gameboy.cpu.registers.read_word(WordRegisterName::RegAF) >> 8, a, "flag {:02X}", flag);
+            assert_eq!(gameboy.mode_warning().is_some(), warned, "flag {:02X}", flag);
+        }
+    }
+
+    #[test]
+    fn ten_scanline_steps_advance_ly_by_ten() {
+        // JR -2
+        let mut memory_data = test_memory(&[0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        gameboy.run_until_vblank();
+        while gameboy.ppu().current_scanline() != 0 {
+            gameboy.step();
+        }
+        let rows = (0..10).map(|_| gameboy.step_scanline()).collect::<Vec<Option<Vec<Color>>>>();
+        assert_eq!(gameboy.ppu().current_scanline(), 10);
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF44), 10);
+        assert!(rows.iter().all(|row| row.as_ref().map(|row| row.len()) == Some(160)));
+    }
+
+    #[test]
+    fn set_buttons_shows_up_in_p1_after_a_frame() {
+        let mut memory_data = test_memory(&[
+            0x3E, 0x10, 0xE0, 0x00,         // LD A,0x10; LDH (0x00),A    select the action buttons
+            0xF0, 0x00, 0xEA, 0x00, 0xC0,   // LDH A,(0x00); LD (0xC000),A
+            0x18, 0xF9                      // JR back to the read
+        ]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        gameboy.run_until_vblank();
+        assert_eq!(gameboy.memory.read::<Byte>(0xC000) & 0x0F, 0x0F);
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF0F) & 0x10, 0);
+        gameboy.set_buttons(BUTTON_START);
+        gameboy.run_until_vblank();
+        // Active low, Start is bit 3 of the action buttons
+        assert_eq!(gameboy.memory.read::<Byte>(0xC000) & 0x0F, 0x07);
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF0F) & 0x10, 0x10);
+    }
+
+    #[test]
+    fn game_genie_code_changes_one_rom_read() {
+        let mut memory_data = test_memory(&[0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        // 0x5A at 0x0150, and 0x77 at 0x0151 but only if the ROM holds 0x01 there, which it doesn't
+        assert!(gameboy.add_cheat("5A1-50F"));
+        assert!(gameboy.add_cheat("771-51F-E6E"));
+        let memory = &mut gameboy.memory;
+        assert_eq!(memory.read::<Byte>(0x014F), 0x00);
+        assert_eq!(memory.read::<Byte>(0x0150), 0x5A);
+        assert_eq!(memory.read::<Byte>(0x0151), 0x00);
+        assert_eq!(memory.read::<Word>(0x014F), 0x5A00);
+    }
+
+    #[test]
+    fn gameshark_codes_force_their_value_every_frame() {
+        // MBC5 with four banks of RAM, left disabled
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0147] = 0x1A;
+        rom[0x0149] = 0x03;
+        rom[0x0100..0x0102].copy_from_slice(&[0x18, 0xFE]);
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("MBC5 test ROM should be valid"), Joypad::new());
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        // 0x42 into WRAM at 0xC0C0, and 0x33 into cart RAM bank 2 at 0xA010
+        assert!(gameboy.add_cheat("0142C0C0"));
+        assert!(gameboy.add_cheat("023310A0"));
+        for _ in 0..3 {
+            gameboy.memory.write(0x00 as Byte, 0xC0C0);
+            gameboy.run_until_vblank();
+            assert_eq!(gameboy.memory.read::<Byte>(0xC0C0), 0x42);
+        }
+        let memory = &mut gameboy.memory;
+        memory.write(0x0A as Byte, 0x0000);
+        assert_eq!(memory.read::<Byte>(0xA010), 0xFF);
+        memory.write(0x02 as Byte, 0x4000);
+        assert_eq!(memory.read::<Byte>(0xA010), 0x33);
+    }
+
+    // Instructions run in one second of wall time, with a pretend clock that moves on by however long the front end would
+    // wait after each frame
+    fn instructions_per_wall_second(cycles_per_second: u64) -> u64 {
+        // JR -2
+        let mut memory_data = test_memory(&[0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        gameboy.set_cycles_per_second(cycles_per_second);
+        let mut wall_time = Duration::ZERO;
+        while wall_time < Duration::from_secs(1) {
+            gameboy.run_until_vblank();
+            wall_time += gameboy.frame_duration();
+        }
+        gameboy.cpu.instructions
+    }
+
+    #[test]
+    fn more_cycles_per_second_runs_more_instructions_per_wall_second() {
+        let normal = instructions_per_wall_second(CYCLES_PER_SECOND);
+        let fast = instructions_per_wall_second(2 * CYCLES_PER_SECOND);
+        let slow = instructions_per_wall_second(CYCLES_PER_SECOND / 2);
+        assert!(fast > normal * 19 / 10 && fast < normal * 21 / 10, "{} at normal speed, {} at double", normal, fast);
+        assert!(slow > normal * 4 / 10 && slow < normal * 6 / 10, "{} at normal speed, {} at half", normal, slow);
+    }
+
+    #[test]
+    fn load_cart_starts_the_new_game_from_power_on() {
+        // LD A,0x11; LD (0xC000),A; JR -2
+        let mut memory_data = test_memory(&[0x3E, 0x11, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        gameboy.run_until_vblank();
+        assert_eq!(gameboy.memory.read::<Byte>(0xC000), 0x11);
+        assert!(gameboy.add_cheat("01FF00C0"));
+        // LD A,0x22; LD (0xC001),A; JR -2
+        gameboy.load_cart(test_cart(&[0x3E, 0x22, 0xEA, 0x01, 0xC0, 0x18, 0xFE])).expect("No autosave to flush");
+        assert_eq!(gameboy.cpu.registers.read_word(WordRegisterName::RegPC), 0x0100);
+        assert_eq!(gameboy.frame_count(), 0);
+        assert_eq!(gameboy.memory.read::<Byte>(0xC000), 0x00);
+        gameboy.run_until_vblank();
+        // The old game's cheat went with it, or 0xC000 would be 0xFF again
+        assert_eq!(gameboy.memory.read::<Byte>(0xC000), 0x00);
+        assert_eq!(gameboy.memory.read::<Byte>(0xC001), 0x22);
+    }
+
+    #[test]
+    fn reset_reapplies_ram_init_and_bank_registers() {
+        // MBC1 with four ROM banks, each marked with its number. Switches to bank 2 and scribbles over WRAM
+        let mut rom = vec![0 as Byte; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        for bank in 1..4 {
+            rom[bank * 0x4000] = bank as Byte;
+        }
+        // LD A,0x02; LD (0x2000),A; LD A,0x33; LD (0xC000),A; JR -2
+        let program = [0x3E, 0x02, 0xEA, 0x00, 0x20, 0x3E, 0x33, 0xEA, 0x00, 0xC0, 0x18, 0xFE];
+        rom[0x0100..(0x0100 + program.len())].copy_from_slice(&program);
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid"), Joypad::new());
+        memory_data.init_ram(RamInit::Random(7));
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        let work_ram = |gameboy: &mut Gameboy| (0xC000..0xE000).map(|address| gameboy.memory.read::<Byte>(address)).collect::<Vec<Byte>>();
+        let power_on_ram = work_ram(&mut gameboy);
+        assert!(power_on_ram.iter().any(|&byte| byte != 0));
+        gameboy.run_until_vblank();
+        assert_eq!(gameboy.memory.read::<Byte>(0x4000), 2);
+        assert_eq!(gameboy.memory.read::<Byte>(0xC000), 0x33);
+        gameboy.reset();
+        assert_eq!(gameboy.memory.read::<Byte>(0x4000), 1);
+        assert!(work_ram(&mut gameboy) == power_on_ram);
+    }
+
+    // Keeps each instruction's bus accesses
+    struct AccessRecorder(Arc<Mutex<Vec<Vec<MemoryAccess>>>>);
+
+    impl Tracer for AccessRecorder {
+        fn on_memory_accesses(&mut self, accesses: &[MemoryAccess]) {
+            self.0.lock().unwrap().push(accesses.to_vec());
+        }
+    }
+
+    #[test]
+    fn memory_trace_reports_accesses_in_order() {
+        // LD HL,0xC123; LD A,0x5A; LD (HL),A; NOP
+        let mut memory_data = test_memory(&[0x21, 0x23, 0xC1, 0x3E, 0x5A, 0x77, 0x00]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        let log = Arc::new(Mutex::new(vec![]));
+        gameboy.set_cpu_tracer(Some(Box::new(AccessRecorder(log.clone()))));
+        gameboy.step_instructions(2);
+        assert!(log.lock().unwrap().is_empty());
+        gameboy.set_memory_trace(true);
+        gameboy.step_instructions(1);
+        assert_eq!(log.lock().unwrap()[0], vec![
+            MemoryAccess { index: 0, kind: AccessKind::Read, address: 0x0105, value: 0x77 },
+            MemoryAccess { index: 1, kind: AccessKind::Write, address: 0xC123, value: 0x5A }
+        ]);
+        gameboy.set_memory_trace(false);
+        gameboy.step_instructions(1);
+        assert_eq!(log.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tracers_see_instructions_interrupts_and_ppu_modes_in_order() {
+        // LD A,0x01; LDH (0xFF),A; LDH (0x0F),A; EI; NOP. VBlank is enabled and already requested, so it's serviced after the NOP
+        let mut memory_data = test_memory(&[0x3E, 0x01, 0xE0, 0xFF, 0xE0, 0x0F, 0xFB, 0x00]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        let tracer = CollectingTracer::new();
+        gameboy.set_cpu_tracer(Some(Box::new(tracer.clone())));
+        gameboy.set_ppu_tracer(Some(Box::new(tracer.clone())));
+        gameboy.run_until_vblank();
+        let events = tracer.events();
+        let cpu_events = events.iter()
+            .filter_map(|event| match event {
+                TraceEvent::Instruction(state) => Some(("instruction", state.pc)),
+                TraceEvent::Interrupt(isr_address) => Some(("interrupt", *isr_address)),
+                TraceEvent::PpuModeChange { .. } => None
+            })
+            .take(7)
+            .collect::<Vec<(&str, Word)>>();
+        assert_eq!(cpu_events, [
+            ("instruction", 0x0100), ("instruction", 0x0102), ("instruction", 0x0104), ("instruction", 0x0106),
+            ("instruction", 0x0107), ("interrupt", 0x0040), ("instruction", 0x0040)
+        ]);
+        let ppu_events = events.iter()
+            .filter_map(|event| match event {
+                TraceEvent::PpuModeChange { mode, ly } => Some((*mode, *ly)),
+                _ => None
+            })
+            .take(4)
+            .collect::<Vec<(u8, Byte)>>();
+        assert_eq!(ppu_events, [(2, 0), (3, 0), (0, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn set_pc_and_sp_start_execution_elsewhere() {
+        let mut memory_data = test_memory(&[]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        // INC A; JR -2 in WRAM
+        gameboy.memory.load_region(0xC100, &[0x3C, 0x18, 0xFE]);
+        gameboy.set_pc(0xC100);
+        gameboy.set_sp(0xDFF0);
+        let a = gameboy.cpu.registers.read_byte(ByteRegisterName::RegA);
+        gameboy.step_instructions(1);
+        assert_eq!(gameboy.cpu.registers.read_byte(ByteRegisterName::RegA), a.wrapping_add(1));
+        assert_eq!(gameboy.cpu.registers.read_word(WordRegisterName::RegPC), 0xC101);
+        assert_eq!(gameboy.cpu.registers.read_word(WordRegisterName::RegSP), 0xDFF0);
+    }
+
+    #[test]
+    fn run_until_stops_on_the_target_pc_and_the_vblank_boundary() {
+        // NOP; NOP; NOP; JR -2
+        let mut memory_data = test_memory(&[0x00, 0x00, 0x00, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        assert!(gameboy.run_until_pc(0x0103, 100));
+        assert_eq!(gameboy.cpu.registers.read_word(WordRegisterName::RegPC), 0x0103);
+        // Already there, so nothing runs
+        let instructions = gameboy.cpu.instructions;
+        assert!(gameboy.run_until_pc(0x0103, 100));
+        assert_eq!(gameboy.cpu.instructions, instructions);
+        assert!(!gameboy.run_until_pc(0x0200, 1000));
+        // Each call stops on the first step of VBlank, with LY just turned over to 144
+        for _ in 0..2 {
+            assert!(gameboy.run_until_vblank());
+            assert!(gameboy.ppu.in_vblank());
+            assert_eq!(gameboy.memory.read::<Byte>(0xFF44), 144);
+        }
+        // With the LCD off VBlank never comes
+        gameboy.memory.write(0x11 as Byte, 0xFF40);
+        assert!(!gameboy.run_until_vblank());
+    }
+
+    // LD A,0x05; DEC A; JR NZ back to the DEC; LD B,0x42; JR -2
+    const COUNTDOWN_PROGRAM: [Byte; 9] = [0x3E, 0x05, 0x3D, 0x20, 0xFD, 0x06, 0x42, 0x18, 0xFE];
+
+    // Runs the countdown against a reference log until the comparer has nothing left to check
+    fn compare_countdown_against(log: &str) -> LogComparison {
+        let mut memory_data = test_memory(&COUNTDOWN_PROGRAM);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let comparer = LogComparer::new(log).expect("Reference log should parse");
+        let results = comparer.results();
+        gameboy.set_cpu_tracer(Some(Box::new(comparer)));
+        while !results.lock().unwrap().is_finished() {
+            gameboy.step();
+        }
+        let comparison = results.lock().unwrap().clone();
+        comparison
+    }
+
+    #[test]
+    fn log_comparer_passes_a_matching_log_and_finds_the_first_divergence() {
+        let mut memory_data = test_memory(&COUNTDOWN_PROGRAM);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let tracer = CollectingTracer::new();
+        gameboy.set_cpu_tracer(Some(Box::new(tracer.clone())));
+        gameboy.step_instructions(20);
+        let mut lines = tracer.events().iter()
+            .filter_map(|event| match event {
+                TraceEvent::Instruction(state) => Some(StateLogLine(*state).to_string()),
+                _ => None
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(lines.len(), 20);
+        assert_eq!(lines[0], "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,05,3D,20");
+        assert_eq!(lines[0].parse::<StateLogLine>().map(|line| line.to_string()), Ok(lines[0].clone()));
+        let matching = compare_countdown_against(&lines.join("\n"));
+        assert_eq!(matching.lines_matched, 20);
+        assert!(matching.divergence.is_none());
+        // Knock A off on the seventh line
+        let expected = lines[6].clone();
+        lines[6] = format!("A:7F {}", &expected[5..]);
+        let diverged = compare_countdown_against(&lines.join("\n"));
+        assert_eq!(diverged.lines_matched, 6);
+        let divergence = diverged.divergence.expect("The corrupted line should diverge");
+        assert_eq!(divergence.line, 7);
+        assert_eq!(divergence.actual.to_string(), expected);
+        assert!(LogComparer::new("A:01 F:ZZ").is_err());
+    }
+
+    #[test]
+    fn collecting_sink_captures_the_bytes_written_to_sb() {
+        // LD A,'H'; LDH (0x01),A; LD A,'i'; LDH (0x01),A; LD A,'\n'; LDH (0x01),A; JR -2
+        let mut memory_data = test_memory(&[0x3E, b'H', 0xE0, 0x01, 0x3E, b'i', 0xE0, 0x01, 0x3E, b'\n', 0xE0, 0x01, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let sink = CollectingSink::new();
+        gameboy.set_serial_sink(Box::new(sink.clone()));
+        assert!(gameboy.run_until_pc(0x010C, 1000));
+        assert_eq!(sink.bytes(), b"Hi\n".to_vec());
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF01), b'\n');
+    }
+
+    #[test]
+    fn tcp_link_swaps_a_byte_between_two_gameboys() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The slave loads 0x99 and waits on the external clock: LD A,0x99; LDH (0x01),A; LD A,0x80; LDH (0x02),A; JR -2
+        let slave = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut memory_data = test_memory(&[0x3E, 0x99, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18, 0xFE]);
+            let mut gameboy = Gameboy::new(&mut memory_data, true);
+            gameboy.set_serial_link(Some(Box::new(TcpLink::new(stream))));
+            for _ in 0..120 {
+                gameboy.run_until_vblank();
+                if (gameboy.memory.read::<Byte>(0xFF02) & 0x80) == 0 {
+                    break;
+                }
+            }
+            let memory = &mut gameboy.memory;
+            (memory.read::<Byte>(0xFF01), memory.read::<Byte>(0xFF02), memory.read::<Byte>(0xFF0F))
+        });
+
+        // The master sends 0x42 on the internal clock: LD A,0x42; LDH (0x01),A; LD A,0x81; LDH (0x02),A; JR -2
+        let mut memory_data = test_memory(&[0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        gameboy.set_serial_link(Some(Box::new(TcpLink::connect(address).unwrap())));
+        gameboy.set_serial_sink(Box::new(CollectingSink::new()));
+        assert!(gameboy.run_until_pc(0x0108, 1000));
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF01), 0x99);
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF02) & 0x80, 0);
+        assert_ne!(gameboy.memory.read::<Byte>(0xFF0F) & 0x08, 0);
+
+        let (slave_data, slave_control, slave_interrupts) = slave.join().unwrap();
+        assert_eq!(slave_data, 0x42);
+        assert_eq!(slave_control & 0x80, 0);
+        assert_ne!(slave_interrupts & 0x08, 0);
+
+        // The slave's side is gone, so the next transfer reads 0xFF
+        gameboy.memory.write::<Byte>(0x81, 0xFF02);
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF01), 0xFF);
+    }
+
+    #[test]
+    fn the_machine_runs_on_another_thread_and_comes_back() {
+        // LD A,'!'; LDH (0x01),A; JR -2
+        let mut memory_data = test_memory(&[0x3E, b'!', 0xE0, 0x01, 0x18, 0xFE]);
+        // Boxed, since the PPU's frame buffers make the machine too big to copy around a fresh thread's stack
+        let mut gameboy = Box::new(Gameboy::new(&mut memory_data, true));
+        let sink = CollectingSink::new();
+        gameboy.set_serial_sink(Box::new(sink.clone()));
+        gameboy.set_on_vblank(Some(Box::new(|_| ())));
+        let mut gameboy = std::thread::scope(|scope| {
+            scope.spawn(move || {
+                assert!(gameboy.run_until_vblank());
+                gameboy
+            }).join().unwrap()
+        });
+        assert_eq!(gameboy.frame_count(), 1);
+        assert_eq!(gameboy.cpu_state().a, b'!');
+        assert_eq!(sink.bytes(), vec![b'!']);
+    }
+
+    #[test]
+    fn on_vblank_fires_once_per_frame_with_the_frame_count() {
+        // JR -2
+        let mut memory_data = test_memory(&[0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let frames_seen = Arc::new(Mutex::new(vec![]));
+        let frames_seen_by_callback = frames_seen.clone();
+        gameboy.set_on_vblank(Some(Box::new(move |frame| frames_seen_by_callback.lock().unwrap().push(frame))));
+        for frame in 1..=5 {
+            assert!(gameboy.run_until_vblank());
+            assert_eq!(gameboy.frame_count(), frame);
+            assert_eq!(*frames_seen.lock().unwrap(), (1..=frame).collect::<Vec<u64>>());
+            // Staying in VBlank for the other 9 lines doesn't count again
+            for _ in 0..9 {
+                gameboy.step_scanline();
+            }
+            assert!(gameboy.ppu.in_vblank());
+            assert_eq!(frames_seen.lock().unwrap().len() as u64, frame);
+        }
+    }
+
+    // Counts its callbacks. The machine owns its memory, so nothing a tracer holds can get in the way of DMA
+    struct CallbackCounter(Arc<AtomicU32>);
+
+    impl Tracer for CallbackCounter {
+        fn on_instruction(&mut self, _: &CpuState) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_ppu_mode_change(&mut self, _: u8, _: Byte) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn dma_runs_with_tracers_called_back_in_between() {
+        // LD A,0xC0; LDH (0x46),A; JR -2
+        let mut memory_data = test_memory(&[0x3E, 0xC0, 0xE0, 0x46, 0x18, 0xFE]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let source = (0..0xA0).map(|i| i as Byte ^ 0x5A).collect::<Vec<Byte>>();
+        gameboy.memory.load_region(0xC000, &source);
+        let callbacks = Arc::new(AtomicU32::new(0));
+        gameboy.set_cpu_tracer(Some(Box::new(CallbackCounter(callbacks.clone()))));
+        gameboy.set_ppu_tracer(Some(Box::new(CallbackCounter(callbacks.clone()))));
+        assert!(gameboy.run_until_vblank());
+        assert!(gameboy.run_until_vblank());
+        assert!(callbacks.load(Ordering::Relaxed) > 100);
+        let oam = gameboy.oam_view();
+        assert_eq!(oam[0].y_pos, 0x5A);
+        assert_eq!(oam[39].flags, 0x9F ^ 0x5A);
+    }
+
+    #[test]
+    fn forced_dmg_takes_the_dmg_branch_of_a_color_enhanced_game() {
+        // CP 0x11; JR Z,+4; LD B,0x0D; JR -2; LD B,0x0C; JR -2. Color hardware boots with A at 0x11
+        let program = [0xFE, 0x11, 0x28, 0x04, 0x06, 0x0D, 0x18, 0xFE, 0x06, 0x0C, 0x18, 0xFE];
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0143] = 0x80;
+        rom[0x0100..(0x0100 + program.len())].copy_from_slice(&program);
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("Blank ROM should always be valid"), Joypad::new());
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        for _ in 0..2 {
+            assert_eq!(gameboy.mode, GameboyMode::Dmg);
+            assert_eq!(gameboy.memory.mode, GameboyMode::Dmg);
+            gameboy.step_instructions(4);
+            assert_eq!(gameboy.cpu.registers.read_byte(ByteRegisterName::RegB), 0x0D);
+            // Still forced after a reset
+            gameboy.reset();
+        }
+    }
+
+    #[test]
+    fn step_instructions_lands_on_the_expected_pc() {
+        // NOP; LD A,0x05; INC A; JP 0x0200, then LD B,A; HALT at 0x0200
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0100..0x0107].copy_from_slice(&[0x00, 0x3E, 0x05, 0x3C, 0xC3, 0x00, 0x02]);
+        rom[0x0200..0x0202].copy_from_slice(&[0x47, 0x76]);
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("Blank ROM should always be valid"), Joypad::new());
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        // Nothing enabled, so the HALT never wakes
+        gameboy.memory.write(0x00 as Byte, 0xFFFF);
+        assert_eq!(gameboy.step_instructions(0), 0x0100);
+        assert_eq!(gameboy.step_instructions(5), 0x0201);
+        assert_eq!(gameboy.cpu.instructions, 5);
+        assert_eq!(gameboy.cpu.registers.read_byte(ByteRegisterName::RegB), 0x06);
+        // Time spent halted doesn't count as instructions, and gives up rather than spinning forever
+        assert_eq!(gameboy.step_instructions(1), 0x0202);
+        assert_eq!(gameboy.step_instructions(1), 0x0202);
+        assert_eq!(gameboy.cpu.instructions, 6);
+    }
+
+    #[test]
+    fn shutdown_saves_battery_ram_only_when_it_changed() {
+        let path = std::env::temp_dir().join(format!("gbzd_gameboy_shutdown_{}.sav", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // MBC1 with 8KiB of battery RAM
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid"), Joypad::new());
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        // Nowhere to save to yet
+        assert!(!gameboy.shutdown().expect("Nothing to write"));
+        // An autosave far off, so only shutdown writes
+        gameboy.set_autosave(Some(Autosave::new(path.clone(), 1_000_000)));
+        assert!(!gameboy.shutdown().expect("Nothing to write"));
+        assert!(!path.exists());
+        gameboy.memory.write(0x0A as Byte, 0x0000);
+        gameboy.memory.write(0x99 as Byte, 0xA010);
+        gameboy.run_until_vblank();
+        assert!(!path.exists());
+        assert!(gameboy.shutdown().expect("Save file should be writable"));
+        assert_eq!(std::fs::read(&path).expect("Save file should have been written")[0x10], 0x99);
+        // Saved RAM is clean again
+        assert!(!gameboy.shutdown().expect("Nothing to write"));
+        std::fs::remove_file(&path).expect("Save file should be removable");
+    }
+
+    // STOP; INC B; JR -2, on a DMG or CGB only cart
+    fn stop_memory(cgb_flag: Byte) -> MemoryMapData {
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0143] = cgb_flag;
+        rom[0x0100..0x0105].copy_from_slice(&[0x10, 0x00, 0x04, 0x18, 0xFE]);
+        MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("Blank ROM should always be valid"), Joypad::new())
+    }
+
+    #[test]
+    fn dmg_stop_holds_div_until_a_selected_button_is_pressed() {
+        let mut memory_data = stop_memory(0x00);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        {
+            // Action buttons selected, VBlank enabled
+            let memory = &mut gameboy.memory;
+            memory.write(0x10 as Byte, 0xFF00);
+            memory.write(0x01 as Byte, 0xFFFF);
+            memory.write(0x00 as Byte, 0xFF0F);
+        }
+        gameboy.cpu.ime = true;
+        gameboy.step_instructions(1);
+        assert!(gameboy.cpu.stopped);
+        let b = gameboy.cpu.registers.read_byte(ByteRegisterName::RegB);
+        // Frames go by with the CPU and DIV frozen, and VBlank doesn't wake it
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF04), 0);
+        gameboy.run_until_vblank();
+        gameboy.run_until_vblank();
+        assert!(gameboy.cpu.stopped);
+        assert_eq!(gameboy.cpu.registers.read_word(WordRegisterName::RegPC), 0x0102);
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF04), 0);
+        // The d-pad isn't selected
+        gameboy.set_buttons(BUTTON_UP);
+        for _ in 0..100 {
+            gameboy.step();
+        }
+        assert!(gameboy.cpu.stopped);
+        gameboy.set_buttons(BUTTON_UP | BUTTON_START);
+        gameboy.cpu.ime = false;
+        for _ in 0..100 {
+            gameboy.step();
+        }
+        assert!(!gameboy.cpu.stopped);
+        assert_ne!(gameboy.cpu.registers.read_byte(ByteRegisterName::RegB), b);
+    }
+
+    #[test]
+    fn cgb_stop_switches_speed_when_armed_and_stops_otherwise() {
+        let mut memory_data = stop_memory(0xC0);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        assert_eq!(gameboy.mode, GameboyMode::Cgb);
+        gameboy.memory.write(0x01 as Byte, 0xFF4D);
+        gameboy.step_instructions(1);
+        assert!(!gameboy.cpu.stopped);
+        assert!(gameboy.cpu.double_speed);
+        // Speed bit set, armed bit cleared
+        assert_eq!(gameboy.memory.read::<Byte>(0xFF4D), 0xFE);
+        assert_eq!(gameboy.step_instructions(1), 0x0103);
+
+        let mut memory_data = stop_memory(0xC0);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        gameboy.step_instructions(1);
+        assert!(gameboy.cpu.stopped);
+        assert!(!gameboy.cpu.double_speed);
+    }
+
+    #[test]
+    fn io_registers_start_at_their_post_boot_values() {
+        let mut memory_data = test_memory(&[]);
+        let mut gameboy = Gameboy::new(&mut memory_data, true);
+        let memory = &mut gameboy.memory;
+        let post_boot = [
+            (0xFF00, 0xCF), (0xFF02, 0x7E), (0xFF07, 0xF8), (0xFF0F, 0xE1),
+            (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF), (0xFF14, 0xBF),
+            (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF19, 0xBF),
+            (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1E, 0xBF),
+            (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF),
+            (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF26, 0xF1),
+            (0xFF40, 0x91), (0xFF41, 0x85), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF44, 0x00), (0xFF45, 0x00),
+            (0xFF46, 0xFF), (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00), (0xFFFF, 0x00)
+        ];
+        for (address, value) in post_boot {
+            assert_eq!(memory.read::<Byte>(address), value, "{:#06x}", address);
+        }
+    }
+}
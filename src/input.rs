@@ -1,23 +1,41 @@
-use std::{cell::RefCell, rc::Rc};
-use gilrs::{Event, Gilrs};
+use gilrs::{Event, EventType, GamepadId, Gilrs};
 
 use crate::memory_gb::{Address, Byte, MemoryMap, MemoryRegion};
 
+// Which button group the game has selected by pulling P14 (d-pad) or P15 (buttons) low
 pub enum JoypadMode {
     DPad,
     Buttons,
+    // Both low, a line reads as pressed if a button in either group is
+    Both,
     Unselected
 }
 
+// Bits of the pressed-button masks taken by Gameboy::set_buttons, in the same order as the joypad's button values
+pub const BUTTON_A: Byte = 1 << 0;
+pub const BUTTON_B: Byte = 1 << 1;
+pub const BUTTON_SELECT: Byte = 1 << 2;
+pub const BUTTON_START: Byte = 1 << 3;
+pub const BUTTON_RIGHT: Byte = 1 << 4;
+pub const BUTTON_LEFT: Byte = 1 << 5;
+pub const BUTTON_UP: Byte = 1 << 6;
+pub const BUTTON_DOWN: Byte = 1 << 7;
+
 pub struct Joypad {
     button_values: Byte,
     mode: JoypadMode
 }
 
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
 // Things can get funky when tracing Joypad code
 // InputHandler is responsible for changing the value of the Joypad buttons, and triggering joypad interrupts when applicable
 // The CPU is responsible for setting the Joypad mode indirectly by writing to the select bits of the joypad register
-// InputHandler has shared ownership of the memory map (along with CPU), and the memory map owns the Joypad
+// InputHandler is handed the memory map on each poll, and the memory map owns the Joypad
 impl Joypad {
     pub fn new() -> Joypad {
         Joypad {
@@ -32,21 +50,56 @@ impl Joypad {
         self.mode = mode;
     }
 
+    // Takes button values active low, like the register reads. Reports whether any button went down, which is what
+    // raises the joypad interrupt
+    pub fn set_button_values(&mut self, button_values: Byte) -> bool {
+        let newly_pressed = (self.button_values ^ button_values) & self.button_values;
+        self.button_values = button_values;
+        newly_pressed > 0
+    }
+
+    // Bits 6 and 7 aren't wired to anything and always read high. The select bits read back as written
     pub fn read(&self) -> Byte {
-        match self.mode {
-            JoypadMode::Buttons => (1 << 5) | ((self.button_values >> 4) & 0x0F),
-            JoypadMode::DPad => (1 << 4) | ((self.button_values) & 0x0F),
-            JoypadMode::Unselected => 0x0F
+        const UNUSED_BITS: Byte = 0xC0;
+        let buttons = self.button_values & 0x0F;
+        let dpad = self.button_values >> 4;
+        UNUSED_BITS | match self.mode {
+            JoypadMode::Buttons => (1 << 4) | buttons,
+            JoypadMode::DPad => (1 << 5) | dpad,
+            JoypadMode::Both => buttons & dpad,
+            JoypadMode::Unselected => 0x3F
         }
     }
 }
 
+// Hands new active low button values to the joypad, firing off the joypad interrupt if a button has gone from high to low
+pub fn update_buttons(mem: &mut MemoryMap, button_values: Byte) {
+    const IF_REG_ADDR: Address = 0xFF0F;
+    if mem.joypad.set_button_values(button_values) {
+        let interrupt_flag: Byte = mem.read(IF_REG_ADDR);
+        mem.write(interrupt_flag | (1 << 4), IF_REG_ADDR);
+    }
+}
+
 pub enum ButtonState {
     Pressed = 0,
     Unpressed = 1
 }
 
+// Controls for the emulator itself rather than the emulated Game Boy. These never reach the Joypad,
+// the front-end decides what to do with them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HostAction {
+    Turbo,
+    Pause,
+    DebugOverlay
+}
+
+const HOST_ACTIONS: [HostAction; 3] = [HostAction::Turbo, HostAction::Pause, HostAction::DebugOverlay];
+
 pub trait InputDevice {
+    // Called once per poll before any of the button queries, for devices that need to pump events or rescan
+    fn refresh(&mut self) {}
     fn a_pressed(&mut self) -> ButtonState;
     fn b_pressed(&mut self) -> ButtonState;
     fn up_pressed(&mut self) -> ButtonState;
@@ -55,6 +108,10 @@ pub trait InputDevice {
     fn right_pressed(&mut self) -> ButtonState;
     fn start_pressed(&mut self) -> ButtonState;
     fn select_pressed(&mut self) -> ButtonState;
+    // Devices don't have to offer any host controls
+    fn host_action_pressed(&mut self, _action: HostAction) -> ButtonState {
+        ButtonState::Unpressed
+    }
 }
 
 pub struct DummyDevice {
@@ -87,8 +144,36 @@ impl InputDevice for DummyDevice {
     }
 }
 
+// Pads currently contributing input, kept up to date with connect and disconnect events so pads can be hot-plugged.
+// Generic over the pad id so it can be driven by events that didn't come from gilrs
+struct ActivePads<Id> {
+    ids: Vec<Id>
+}
+
+impl<Id: Copy + PartialEq> ActivePads<Id> {
+    // Applies events in order, handing back the ones that actually added or removed a pad so the caller can say so
+    fn update<I: IntoIterator<Item = (Id, EventType)>>(&mut self, events: I) -> Vec<(Id, EventType)> {
+        let mut changes = vec![];
+        for (id, event) in events {
+            match event {
+                EventType::Connected if !self.ids.contains(&id) => {
+                    self.ids.push(id);
+                    changes.push((id, event));
+                }
+                EventType::Disconnected if self.ids.contains(&id) => {
+                    self.ids.retain(|&active_id| active_id != id);
+                    changes.push((id, event));
+                }
+                _ => ()
+            }
+        }
+        changes
+    }
+}
+
 pub struct GilControllers {
     context: Gilrs,
+    active_pads: ActivePads<GamepadId>
 }
 
 // Please ignore the GilControllers implementation. There's nothing sane happening here.
@@ -97,21 +182,35 @@ impl GilControllers {
     pub fn enumerate_gilrs_controllers() -> GilControllers {
         let context = Gilrs::new().unwrap();
 
-        for (_id, gamepad) in context.gamepads() {
+        let mut active_pads = vec![];
+        for (id, gamepad) in context.gamepads() {
             println!("{} is {:?}", gamepad.name(), gamepad.power_info());
+            active_pads.push(id);
         }
 
-        GilControllers { context: context }
+        GilControllers { context, active_pads: ActivePads { ids: active_pads } }
+    }
+
+    // Drain pending gilrs events, picking up pads plugged in mid-session and dropping ones that went away
+    fn pump_events(&mut self) {
+        let events = std::iter::from_fn(|| self.context.next_event())
+            .map(|Event { id, event, .. }| (id, event))
+            .collect::<Vec<(GamepadId, EventType)>>();
+        for (id, event) in self.active_pads.update(events) {
+            let gamepad = self.context.gamepad(id);
+            match event {
+                EventType::Connected => println!("{} connected, is {:?}", gamepad.name(), gamepad.power_info()),
+                _ => println!("{} disconnected", gamepad.name())
+            }
+        }
     }
 
     // Check if a button is pressed on any of the GilControllers
     fn gil_button_status(&mut self, button: gilrs::ev::Button) -> ButtonState {
-        while let Some(Event { .. }) = self.context.next_event() {
-        }
-        let gamepads = self.context.gamepads();
         let mut pressed = false;
-        for (mut _id, pad) in gamepads {
-            let stuff = pad.button_data(button);
+        for id in self.active_pads.ids.iter() {
+            // A pad that vanished between events and now just doesn't count as pressing anything
+            let stuff = self.context.connected_gamepad(*id).and_then(|pad| pad.button_data(button).copied());
             pressed |= match stuff {
                 Some(a) => a.is_pressed(),
                 None => false
@@ -127,6 +226,9 @@ impl GilControllers {
 }
 
 impl InputDevice for GilControllers {
+    fn refresh(&mut self) {
+        self.pump_events()
+    }
     fn a_pressed(&mut self) -> ButtonState {
         self.gil_button_status(gilrs::ev::Button::South)
     }
@@ -151,56 +253,171 @@ impl InputDevice for GilControllers {
     fn select_pressed(&mut self) -> ButtonState {
         self.gil_button_status(gilrs::ev::Button::Select)
     }
+    fn host_action_pressed(&mut self, action: HostAction) -> ButtonState {
+        match action {
+            HostAction::Turbo => self.gil_button_status(gilrs::ev::Button::RightTrigger2),
+            HostAction::Pause => self.gil_button_status(gilrs::ev::Button::Mode),
+            HostAction::DebugOverlay => self.gil_button_status(gilrs::ev::Button::LeftThumb)
+        }
+    }
 }
 
 
-pub struct InputHandler<'a> {
+pub struct InputHandler {
     devices: Vec<Box<dyn InputDevice>>,
-    memory: Rc<RefCell<MemoryMap<'a>>>,
-    last_button_state: Byte
+    // Held state of each host action, indexed by HostAction, for this poll and the one before it
+    host_actions: [bool; HOST_ACTIONS.len()],
+    last_host_actions: [bool; HOST_ACTIONS.len()]
 }
 
-impl<'a> InputHandler<'a> {
-    pub fn new(devices: Vec<Box<dyn InputDevice>>, memory: Rc<RefCell<MemoryMap<'a>>>) -> Self {
+impl InputHandler {
+    pub fn new(devices: Vec<Box<dyn InputDevice>>) -> Self {
         InputHandler {
             devices,
-            memory,
-            last_button_state: 0xFF
+            host_actions: [false; HOST_ACTIONS.len()],
+            last_host_actions: [false; HOST_ACTIONS.len()]
         }
     }
 
-    pub fn poll(&mut self) {
+    // Whether any device is holding down the host action as of the last poll
+    pub fn host_action_held(&self, action: HostAction) -> bool {
+        self.host_actions[action as usize]
+    }
+
+    // Whether the host action went down on the last poll, for actions that toggle rather than act while held
+    pub fn host_action_just_pressed(&self, action: HostAction) -> bool {
+        self.host_actions[action as usize] && !self.last_host_actions[action as usize]
+    }
+
+    pub fn poll(&mut self, memory: &mut MemoryMap) {
         let mut sum_of_button_states: u8 = 0;
+        self.last_host_actions = self.host_actions;
+        self.host_actions = [false; HOST_ACTIONS.len()];
         for device in self.devices.iter_mut() {
+            device.refresh();
             sum_of_button_states |= !(InputHandler::get_button_state(&mut **device));
+            for action in HOST_ACTIONS {
+                if let ButtonState::Pressed = device.host_action_pressed(action) {
+                    self.host_actions[action as usize] = true;
+                }
+            }
         }
         sum_of_button_states = !sum_of_button_states;
 
-        let mut mem = self.memory.borrow_mut();
-        mem.joypad.button_values = sum_of_button_states;
+        update_buttons(memory, sum_of_button_states);
+    }
+
+    fn get_button_state(device: &mut dyn InputDevice) -> Byte {
+        // Active low like the joypad register, so a button's bit stays set while it's up
+        let bit = |state: ButtonState, button: Byte| if let ButtonState::Unpressed = state { button } else { 0 };
+        bit(device.a_pressed(), BUTTON_A) |
+            bit(device.b_pressed(), BUTTON_B) |
+            bit(device.select_pressed(), BUTTON_SELECT) |
+            bit(device.start_pressed(), BUTTON_START) |
+            bit(device.right_pressed(), BUTTON_RIGHT) |
+            bit(device.left_pressed(), BUTTON_LEFT) |
+            bit(device.up_pressed(), BUTTON_UP) |
+            bit(device.down_pressed(), BUTTON_DOWN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::gameboy::{Gameboy, FRAME_DURATION};
+    use crate::memory_gb::tests::test_memory;
 
-        // Fire off joypad interrupt if one of the button values has gone from high to low
-        const IF_REG_ADDR: Address = 0xFF0F;
-        let mut interrupt_flag: Byte = mem.read(IF_REG_ADDR);
-        if ((self.last_button_state ^ sum_of_button_states) & self.last_button_state) > 0
-        {
-            interrupt_flag |= 1 << 4;
+    // Holds Start and Down, nothing else
+    struct StartAndDown;
+
+    impl InputDevice for StartAndDown {
+        fn a_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn b_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn up_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn down_pressed(&mut self) -> ButtonState { ButtonState::Pressed }
+        fn left_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn right_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn start_pressed(&mut self) -> ButtonState { ButtonState::Pressed }
+        fn select_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+    }
+
+    #[test]
+    fn polled_buttons_read_active_low() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut input_handler = InputHandler::new(vec![Box::new(DummyDevice {}), Box::new(StartAndDown)]);
+        input_handler.poll(&mut memory);
+        assert_eq!(memory.joypad.button_values, !(BUTTON_START | BUTTON_DOWN));
+        // Action buttons, then the d-pad
+        memory.write(0x10 as Byte, 0xFF00);
+        assert_eq!(memory.read::<Byte>(0xFF00) & 0x0F, 0x07);
+        memory.write(0x20 as Byte, 0xFF00);
+        assert_eq!(memory.read::<Byte>(0xFF00) & 0x0F, 0x07);
+    }
+
+    #[test]
+    fn p1_reads_every_selection_with_the_top_bits_set() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.joypad.button_values = !(BUTTON_A | BUTTON_DOWN);
+        // Action buttons, the d-pad, neither, both. Writes only reach the select bits
+        let expected = [(0x10, 0xDE), (0x20, 0xE7), (0x30, 0xFF), (0x00, 0xC6), (0xFF, 0xFF)];
+        for (select, p1) in expected {
+            memory.write(select as Byte, 0xFF00);
+            assert_eq!(memory.read::<Byte>(0xFF00), p1, "P1 after writing {:#04x}", select);
         }
-        mem.write(interrupt_flag, IF_REG_ADDR);
+    }
 
-        self.last_button_state = sum_of_button_states;
+    #[test]
+    fn pads_plugged_in_later_become_active_and_unplugged_ones_stop() {
+        let mut pads = ActivePads { ids: vec![0] };
+        let changes = pads.update([
+            (1, EventType::Connected),
+            (1, EventType::Connected),
+            (2, EventType::Dropped),
+            (0, EventType::Disconnected),
+            (3, EventType::Disconnected)
+        ]);
+        assert_eq!(pads.ids, vec![1]);
+        assert_eq!(changes, vec![(1, EventType::Connected), (0, EventType::Disconnected)]);
     }
 
-    fn get_button_state(device: &mut dyn InputDevice) -> Byte {
-        let state: Byte =
-            (device.a_pressed() as u8) |
-            (device.b_pressed() as u8) << 1 |
-            (device.select_pressed() as u8) << 2 |
-            (device.start_pressed() as u8) << 3 |
-            (device.right_pressed() as u8) << 4 |
-            (device.left_pressed() as u8) << 5 |
-            (device.up_pressed() as u8) << 6 |
-            (device.down_pressed() as u8) << 7;
-        state
-    }
-}
\ No newline at end of file
+    // Holds the turbo button for as long as the shared flag is set, and nothing else
+    struct TurboButton(Rc<Cell<bool>>);
+
+    impl InputDevice for TurboButton {
+        fn a_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn b_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn up_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn down_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn left_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn right_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn start_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn select_pressed(&mut self) -> ButtonState { ButtonState::Unpressed }
+        fn host_action_pressed(&mut self, action: HostAction) -> ButtonState {
+            if action == HostAction::Turbo && self.0.get() { ButtonState::Pressed } else { ButtonState::Unpressed }
+        }
+    }
+
+    #[test]
+    fn turbo_unthrottles_only_while_held() {
+        let mut memory_data = test_memory(&[]);
+        let mut gameboy = Gameboy::new(&mut memory_data, false);
+        let held = Rc::new(Cell::new(true));
+        let mut input_handler = InputHandler::new(vec![Box::new(TurboButton(held.clone()))]);
+        // The same check the front-end makes every frame
+        input_handler.poll(gameboy.memory_mut());
+        gameboy.set_throttled(!input_handler.host_action_held(HostAction::Turbo));
+        assert_eq!(gameboy.frame_duration(), Duration::ZERO);
+        // Host controls never reach the joypad
+        assert_eq!(gameboy.memory_mut().joypad.button_values, 0xFF);
+        held.set(false);
+        input_handler.poll(gameboy.memory_mut());
+        gameboy.set_throttled(!input_handler.host_action_held(HostAction::Turbo));
+        assert_eq!(gameboy.frame_duration(), FRAME_DURATION);
+    }
+}
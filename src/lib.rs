@@ -0,0 +1,23 @@
+// The emulator core. Everything a front end needs to run a game lives here, with no window, argument parsing, or
+// other host concerns. main.rs is one front end built on top of it
+pub mod processor {
+    pub mod cpu;
+    pub mod ops;
+    pub mod execute;
+}
+pub mod memory_gb;
+pub mod cart;
+pub mod special_registers;
+pub mod ppu;
+pub mod input;
+pub mod gameboy;
+pub mod tracer;
+pub mod clock;
+pub mod cheats;
+pub mod oam;
+pub mod serial;
+pub mod palette;
+pub mod autosave;
+pub mod disassembler;
+pub mod symbols;
+pub mod debugger;
@@ -1,39 +1,99 @@
-mod processor {
-    pub mod cpu;
-    pub mod ops;
-    pub mod execute;
-}
-mod memory_gb;
-mod cart;
-mod special_registers;
-mod ppu;
 mod display;
-mod input;
+mod bench;
+mod fuzz;
+mod cli;
+#[cfg(feature = "debugger")]
+mod debugger_window;
 
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::path::Path;
 use std::thread::sleep;
-use std::time::{Duration, Instant};
-use display::DisplayMiniFB;
+use std::time::Instant;
+use display::{draw_debug_overlay, window_scale, DisplayMiniFB, DisplayTerminal};
 
-use crate::processor::cpu::*;
-use crate::ppu::*;
-use crate::input::*;
+use gbzd::{cart, input, memory_gb};
+use gbzd::clock::SystemClock;
+use gbzd::autosave::{Autosave, AUTOSAVE_INTERVAL_FRAMES};
+use crate::cli::{parse_args, Command, LinkCable, Options, USAGE};
+use gbzd::gameboy::{Gameboy, FRAME_DURATION};
+use gbzd::ppu::*;
+use gbzd::input::*;
+use gbzd::palette::resolve_shade;
+use gbzd::serial::{CollectingSink, TcpLink};
+use gbzd::tracer::{LogComparer, PrintTracer};
 
-const FRAME_TIME_TOTAL: Duration = Duration::from_micros(16_740);
+// Long enough for cpu_instrs, which takes close to a minute on hardware, with room to spare
+const SERIAL_TEST_MAX_FRAMES: u64 = 2 * 60 * 60;
 
 fn main() {
-    let args = std::env::args().collect::<Vec<String>>();
-    if args.len() != 2 {
-        panic!("Incorrect number of arguments supplied. Please provide a path to a ROM file");
-    }
-    let rom = &args[1];
-    let cart = cart::Cart::load_from_file(rom).expect("Problem with ROM file");
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => *options,
+        Ok(Command::Info(rom_path)) => {
+            print_rom_info(&rom_path);
+            return;
+        }
+        Ok(Command::Bench) => {
+            bench::run();
+            return;
+        }
+        Ok(Command::Fuzz { runs, first_seed }) => {
+            let clean = fuzz::run(runs, first_seed);
+            std::process::exit(if clean { 0 } else { 1 });
+        }
+        Err(error) => {
+            println!("{}\n\n{}", error, USAGE);
+            std::process::exit(1);
+        }
+    };
+    let mut cart = cart::Cart::load_from_file(&options.rom_path.to_string_lossy(), Box::new(SystemClock)).expect("Problem with ROM file");
+    load_save(&mut cart, &options);
     let joypad = input::Joypad::new();
-    let mut system_memory_data = memory_gb::MemoryMap::allocate(cart, joypad);
-    let system_memory = Rc::new(RefCell::new(memory_gb::MemoryMap::new(&mut system_memory_data)));
-    let mut cpu = Cpu::new(system_memory.clone());
-    let mut ppu = Ppu::new(system_memory.clone());
+    // Lives until the program exits either way. Leaking it lets the debugger window, which can't borrow anything, own the machine
+    let system_memory_data = Box::leak(Box::new(memory_gb::MemoryMap::allocate(cart, joypad)));
+    system_memory_data.init_ram(options.ram_init);
+    let mut gameboy = Gameboy::new(system_memory_data, options.force_dmg);
+    println!("Running in {:?} mode", gameboy.mode);
+    if let Some(warning) = gameboy.mode_warning() {
+        println!("{}", warning);
+    }
+    if let Some(model) = options.model {
+        gameboy.set_boot_state(model);
+    }
+    gameboy.set_cycles_per_second(options.cycles_per_second);
+    for code in options.cheats.iter() {
+        if !gameboy.add_cheat(code) {
+            println!("Couldn't make sense of cheat code {}, skipping it", code);
+        }
+    }
+    if let Some(ref boot_rom_path) = options.boot_rom {
+        let boot_rom = std::fs::read(boot_rom_path).expect("Problem with boot ROM file");
+        gameboy.load_boot_rom(boot_rom).unwrap_or_else(|error| panic!("Problem with boot ROM file. {}", error));
+    }
+    if options.trace {
+        gameboy.set_cpu_tracer(Some(Box::new(PrintTracer)));
+        gameboy.set_ppu_tracer(Some(Box::new(PrintTracer)));
+    }
+    if let Some(ref link) = options.link {
+        let connection = match link {
+            LinkCable::Listen(address) => {
+                println!("Waiting for a link partner on {}", address);
+                TcpLink::listen(address)
+            }
+            LinkCable::Connect(address) => TcpLink::connect(address)
+        };
+        match connection {
+            Ok(tcp_link) => gameboy.set_serial_link(Some(Box::new(tcp_link))),
+            Err(error) => println!("Couldn't set up the link cable, running without it. {}", error)
+        }
+    }
+
+    if let Some(ref log_path) = options.log_path {
+        compare_against_log(&mut gameboy, &log_path.to_string_lossy());
+        return;
+    }
+    if options.serial_test {
+        std::process::exit(run_serial_test(&mut gameboy, &options));
+    }
+    gameboy.set_autosave(Some(Autosave::new(options.save_path(), AUTOSAVE_INTERVAL_FRAMES)));
     
     let controllers: Vec<Box<dyn InputDevice>> = {
         let pads = GilControllers::enumerate_gilrs_controllers();
@@ -42,62 +102,170 @@ fn main() {
         intermediate
     };
     
-    let mut input_handler = InputHandler::new(controllers, system_memory.clone());
+    let mut input_handler = InputHandler::new(controllers);
     //let mut input_handler = InputH
-    let mut display = DisplayMiniFB::new();
-
-    // Debt represents the timing balance between cpu and ppu.
-    // The cpu runs up the debt (positive)
-    // The ppu pays down the debt (negative)
-    // The ppu only has to do work if its debt is greater than 0
-    let mut debt: i16 = 0;
-    let mut cpu_locked: bool = false;
+    if options.debugger {
+        #[cfg(feature = "debugger")]
+        debugger_window::run(gameboy, input_handler, options.palette, options.save_path(), options.symbols.clone());
+        #[cfg(not(feature = "debugger"))]
+        println!("This build doesn't have the debugger, rebuild with --features debugger");
+        return;
+    }
+    if options.terminal {
+        run_in_terminal(&mut gameboy, &mut input_handler, options.turbo);
+    }
+    let mut display = DisplayMiniFB::new(window_scale(options.scale).unwrap());
+    display.set_ghosting(options.ghosting);
+
     let mut color_buffer = vec![0u32; 160*144];
     let mut frame_time_start = Instant::now();
     let mut frame_time_end = Instant::now();
+    let mut paused = false;
+    let mut show_overlay = false;
 
-    loop {
-        if debt <= 0 && !cpu_locked {
-            let payment = (cpu.run() * 4) as i16;
-            debt += payment;
-            if payment == 0 {
-                cpu_locked = true;
-            }
-        }
-        else {
-            if cpu_locked {
-                ppu.run();
-                cpu_locked = false
-            }
-            else {
-                debt -= ppu.run();
+    while display.is_open() {
+        // While paused, just keep an eye out for the unpause
+        if paused {
+            input_handler.poll(gameboy.memory_mut());
+            if input_handler.host_action_just_pressed(HostAction::Pause) {
+                paused = false;
             }
+            sleep(FRAME_DURATION);
+            frame_time_start = Instant::now();
+            continue;
         }
+
+        gameboy.step();
         
         // Things that happen once per frame go here
-        if ppu.frame_is_ready() {
-            color_buffer = ppu.display_handle()
+        if gameboy.frame_is_ready() {
+            color_buffer = gameboy.display_handle()
                 .into_iter()
-                .map(|color: Color| {
-                    match color {
-                        Color::A => 0xe0f8d0u32,
-                        Color::B => 0x88c070u32,
-                        Color::C => 0x346856u32,
-                        Color::D => 0x081820u32
-                    }
-                })
+                .map(|color: Color| resolve_shade(color, &options.palette).to_0rgb())
                 .collect::<Vec<u32>>();
+            if show_overlay {
+                draw_debug_overlay(&mut color_buffer, display.width, &gameboy.object_bounds(), gameboy.window_bounds());
+            }
             // println!("{:x?}", color_buffer);
             display.update(&color_buffer);
             // Poll input for the next frame (first frame will always have default values, but that's fine)
-            input_handler.poll();
+            input_handler.poll(gameboy.memory_mut());
+            // Host controls are dealt with here and never reach the emulated joypad
+            gameboy.set_throttled(!options.turbo && !input_handler.host_action_held(HostAction::Turbo));
+            if input_handler.host_action_just_pressed(HostAction::Pause) {
+                paused = true;
+                // Where the CPU stopped, for lining up with an outside debugger or script
+                println!("Paused. CPU state: {}", gameboy.cpu_state_json());
+            }
+            if input_handler.host_action_just_pressed(HostAction::DebugOverlay) {
+                show_overlay = !show_overlay;
+            }
 
             // Clock in the time taken as late as possible for a decent sleep timing
             frame_time_end = Instant::now();
             let frame_time_elapsed = frame_time_end - frame_time_start;
             // println!("frame start {:?}, frame end {:?}, duration {:?}", frame_time_start, frame_time_end, frame_time_elapsed);
-            if frame_time_elapsed < FRAME_TIME_TOTAL {
-                sleep(FRAME_TIME_TOTAL - frame_time_elapsed);
+            let frame_time_total = gameboy.frame_duration();
+            if frame_time_elapsed < frame_time_total {
+                sleep(frame_time_total - frame_time_elapsed);
+            }
+            frame_time_start = Instant::now();
+        }
+    }
+
+    // Window closed, catch the save up one last time
+    match gameboy.shutdown() {
+        Ok(true) => println!("Saved to {}", options.save_path().display()),
+        Ok(false) => (),
+        Err(error) => println!("Couldn't save to {}. {}", options.save_path().display(), error)
+    }
+}
+
+// Reads the header straight from the file, so it works even for carts the emulator can't run
+fn print_rom_info(rom_path: &std::path::Path) {
+    let contents = std::fs::read(rom_path).expect("Problem with ROM file");
+    match cart::CartHeader::parse(&contents) {
+        Ok(header) => println!("{}", header),
+        Err(error) => println!("{}", error)
+    }
+}
+
+// Picks up where the last session left off, if there's a battery save for this ROM
+fn load_save(cart: &mut cart::Cart, options: &Options) {
+    let save_path = options.save_path();
+    if let Ok(save) = std::fs::read(&save_path) {
+        match cart.set_ram(&save) {
+            Ok(()) => println!("Loaded save from {}", save_path.display()),
+            Err(error) => println!("Ignoring save {}. {}", save_path.display(), error)
+        }
+    }
+}
+
+// Runs without a window, checking every instruction against a Gameboy Doctor log until it either diverges or the log runs out
+fn compare_against_log(gameboy: &mut Gameboy, log_path: &str) {
+    let log = std::fs::read_to_string(log_path).expect("Problem with log file");
+    let comparer = LogComparer::new(&log).unwrap_or_else(|error| panic!("Problem with log file. {}", error));
+    let results = comparer.results();
+    gameboy.memory_mut().ly_override = Some(0x90);
+    gameboy.set_cpu_tracer(Some(Box::new(comparer)));
+    while !results.lock().unwrap().is_finished() {
+        gameboy.step();
+    }
+    let results = results.lock().unwrap();
+    match results.divergence {
+        Some(divergence) => {
+            println!("Diverged from the log at line {}", divergence.line);
+            println!("Expected: {}", divergence.expected);
+            println!("Actual:   {}", divergence.actual);
+        }
+        None => println!("All {} lines match", results.lines_matched)
+    }
+}
+
+// Runs without a window until each ROM reports a result over serial, the way blargg's test ROMs do. ROMs after the first are
+// swapped into the same machine in turn. Everything they printed is passed along, and the exit code is 0 only if all passed
+fn run_serial_test(gameboy: &mut Gameboy, options: &Options) -> i32 {
+    let mut all_passed = serial_test_passed(gameboy, &options.rom_path);
+    for rom_path in options.more_roms.iter() {
+        let cart = cart::Cart::load_from_file(&rom_path.to_string_lossy(), Box::new(SystemClock)).expect("Problem with ROM file");
+        gameboy.load_cart(cart).expect("Problem writing the battery save of the last ROM");
+        all_passed &= serial_test_passed(gameboy, rom_path);
+    }
+    if all_passed { 0 } else { 1 }
+}
+
+fn serial_test_passed(gameboy: &mut Gameboy, rom_path: &Path) -> bool {
+    let sink = CollectingSink::new();
+    gameboy.set_serial_sink(Box::new(sink.clone()));
+    let result = gameboy.run_until_serial(&sink, &["Passed", "Failed"], SERIAL_TEST_MAX_FRAMES);
+    println!("{}\n{}", rom_path.display(), sink.text());
+    match result {
+        Some("Passed") => true,
+        Some(_) => false,
+        None => {
+            println!("No result after {} frames", SERIAL_TEST_MAX_FRAMES);
+            false
+        }
+    }
+}
+
+// Runs with frames drawn as text instead of in a window, until the process is killed. Autosave keeps the battery save
+// current in the meantime, since there's no window close to catch
+fn run_in_terminal(gameboy: &mut Gameboy, input_handler: &mut InputHandler, turbo: bool) -> ! {
+    let mut display = DisplayTerminal::new();
+    // Start from a blank screen, every frame after draws over the one before
+    print!("\x1b[2J");
+    let mut frame_time_start = Instant::now();
+    loop {
+        gameboy.step();
+        if gameboy.frame_is_ready() {
+            display.update(&gameboy.display_handle());
+            input_handler.poll(gameboy.memory_mut());
+            gameboy.set_throttled(!turbo && !input_handler.host_action_held(HostAction::Turbo));
+            let frame_time_elapsed = frame_time_start.elapsed();
+            let frame_time_total = gameboy.frame_duration();
+            if frame_time_elapsed < frame_time_total {
+                sleep(frame_time_total - frame_time_elapsed);
             }
             frame_time_start = Instant::now();
         }
@@ -1,6 +1,6 @@
 use std::mem;
 
-use crate::{cart::Cart, input::{self, Joypad}, special_registers::Timer};
+use crate::{cart::{Cart, CgbSupport}, cheats::{GameGenieCode, GameSharkCode}, gameboy::GameboyMode, input::{self, Joypad}, oam::OamEntry, serial::{SerialLink, SerialSink, StdoutSink}, special_registers::Timer, tracer::{AccessKind, MemoryAccess}};
 
 pub type Byte = u8;
 pub type Word = u16;
@@ -187,6 +187,103 @@ const IOREGS_START: usize = 0xFF00;
 const HRAM_START: usize = 0xFF80;
 const IE_START: usize = 0xFFFF;
 
+// Echo RAM has nothing of its own behind it, 0xE000-0xFDFF is WRAM showing up again 0x2000 higher.
+// It stops short of OAM, so 0xFE00 and up are left alone
+fn mirror_echo_ram(address: Address) -> Address {
+    if (ECHORAM_START..OAM_START).contains(&(address as usize)) {
+        address - (ECHORAM_START - WRAM_START) as Address
+    }
+    else {
+        address
+    }
+}
+
+pub const KEY1_ADDRESS: Address = 0xFF4D;
+// VRAM bank select on CGB. Bank 1 holds the background tile attributes and a second set of tile data
+pub const VBK_ADDRESS: Address = 0xFF4F;
+// Object priority mode on CGB. Bit 0 clear: lower OAM index wins. Set: lower X wins, like DMG
+pub const OPRI_ADDRESS: Address = 0xFF6C;
+
+// Bits of each IO register that always read back as 1 on DMG, regardless of what was written.
+// Registers that don't exist read as all 1s. See https://gbdev.io/pandocs/Hardware_Reg_List.html
+fn io_read_mask(address: Address) -> Byte {
+    match address {
+        0xFF00 => 0xC0,         // P1
+        0xFF01 => 0x00,         // SB
+        0xFF02 => 0x7E,         // SC
+        0xFF04..=0xFF06 => 0x00,// DIV, TIMA, TMA
+        0xFF07 => 0xF8,         // TAC
+        0xFF0F => 0xE0,         // IF
+        0xFF10 => 0x80,         // NR10
+        0xFF11 => 0x3F,         // NR11
+        0xFF12 => 0x00,         // NR12
+        0xFF13 => 0xFF,         // NR13
+        0xFF14 => 0xBF,         // NR14
+        0xFF16 => 0x3F,         // NR21
+        0xFF17 => 0x00,         // NR22
+        0xFF18 => 0xFF,         // NR23
+        0xFF19 => 0xBF,         // NR24
+        0xFF1A => 0x7F,         // NR30
+        0xFF1B => 0xFF,         // NR31
+        0xFF1C => 0x9F,         // NR32
+        0xFF1D => 0xFF,         // NR33
+        0xFF1E => 0xBF,         // NR34
+        0xFF20 => 0xFF,         // NR41
+        0xFF21 => 0x00,         // NR42
+        0xFF22 => 0x00,         // NR43
+        0xFF23 => 0xBF,         // NR44
+        0xFF24 => 0x00,         // NR50
+        0xFF25 => 0x00,         // NR51
+        0xFF26 => 0x70,         // NR52
+        0xFF30..=0xFF3F => 0x00,// Wave RAM
+        0xFF40 => 0x00,         // LCDC
+        0xFF41 => 0x80,         // STAT
+        0xFF42..=0xFF4B => 0x00,// SCY through WX
+        _ => 0xFF
+    }
+}
+
+// What RAM holds at power on. Real hardware comes up with semi-random garbage, which a few games and test ROMs notice
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RamInit {
+    Zeros,
+    Ones,
+    // Noise from a seeded generator, so a run can be reproduced
+    Random(u64)
+}
+
+impl RamInit {
+    fn fill(&self, regions: &mut [&mut [Byte]]) {
+        match *self {
+            RamInit::Zeros => regions.iter_mut().for_each(|region| region.fill(0x00)),
+            RamInit::Ones => regions.iter_mut().for_each(|region| region.fill(0xFF)),
+            RamInit::Random(seed) => {
+                let mut noise = Xorshift64::new(seed);
+                for byte in regions.iter_mut().flat_map(|region| region.iter_mut()) {
+                    *byte = noise.next_byte();
+                }
+            }
+        }
+    }
+}
+
+// xorshift64, plenty for making noise and the same sequence for the same seed every time
+pub struct Xorshift64(u64);
+
+impl Xorshift64 {
+    // Any xorshift gets stuck on a zero state, so the seed is nudged away from it
+    pub fn new(seed: u64) -> Xorshift64 {
+        Xorshift64(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    pub fn next_byte(&mut self) -> Byte {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 24) as Byte
+    }
+}
+
 // TODO: revisit if repr(C) is necessary
 // TODO: hide rom, rom_swappable, external_ram behind cart abstraction
 #[repr(C)]
@@ -194,36 +291,93 @@ pub struct MemoryMapData {
     cart: Cart,
     timer: Timer,
     joypad: Joypad,
-    vram: [Byte; EXRAM_START - VRAM_START],
+    vram: [[Byte; EXRAM_START - VRAM_START]; 2],
     work_ram: [Byte; WRAM_S_START - WRAM_START],
     work_ram_swappable: [Byte; ECHORAM_START - WRAM_S_START],
-    echo_ram: [Byte; OAM_START - ECHORAM_START],
     oam: [Byte; UNUSABLE_START - OAM_START],
     unusable: [Byte; IOREGS_START - UNUSABLE_START],
     io_registers: [Byte; HRAM_START - IOREGS_START],
     hram: [Byte; IE_START - HRAM_START],
     ie: [Byte; 1],
+    ram_init: RamInit,
 }
 
 pub struct MemoryMap<'a> { 
     cart: &'a mut Cart,
     pub timer: &'a mut Timer,
     pub joypad: &'a mut Joypad,
-    vram: SimpleRegion<'a>,
+    vram: [SimpleRegion<'a>; 2],
+    // Which VRAM bank the CPU sees at 0x8000-0x9FFF. Always 0 on DMG
+    vram_bank: usize,
     work_ram: SimpleRegion<'a>,
     work_ram_swappable: SimpleRegion<'a>,
-    echo_ram: SimpleRegion<'a>,
     oam: SimpleRegion<'a>,
     unusable: SimpleRegion<'a>,
     pub io_registers: SimpleRegion<'a>,
     hram: SimpleRegion<'a>,
     ie: SimpleRegion<'a>,
+    // What RAM gets refilled with when the power is cycled
+    ram_init: RamInit,
+    // Hardware being emulated, decides whether CGB-only registers are mapped
+    pub mode: GameboyMode,
+    // Accurate by default: VRAM/OAM writes are dropped while the PPU holds them, and writes to the unusable region go nowhere.
+    // Turning this off lets those writes land, which is handy for test setup and cheats. ROM writes are MBC register writes
+    // either way, since games can't bank without them. Patching ROM itself is load_region's job
+    pub write_protected_regions: bool,
+    // Set when the CPU writes LY, until the PPU picks it up and restarts the frame
+    ly_reset: bool,
+    // When set, LY reads return this instead of the current line. Gameboy Doctor logs are made with LY stuck at 0x90
+    pub ly_override: Option<Byte>,
+    serial_sink: Box<dyn SerialSink>,
+    serial_link: Option<Box<dyn SerialLink>>,
+    // Covers the bottom of the cart ROM until the boot ROM writes to 0xFF50 to hand over
+    boot_rom: Option<Vec<Byte>>,
+    // Every CPU side read and write in order, while switched on. The CPU empties it each instruction
+    pub access_log: Option<Vec<MemoryAccess>>,
 }
 
-// TODO: Override get_bank to implement mapped addressing against a structure full of MemoryRegions
 impl<'a> MemoryRegion for MemoryMap<'a> {
-
     fn read<T: MemoryUnit>(&mut self, address: Address) -> T {
+        // The bus is 8 bits wide. Words go a byte at a time, so a word straddling two regions gets each half from the right one
+        let width = mem::size_of::<T>();
+        if width > 1 {
+            let mut bytes = [0 as Byte; mem::size_of::<Word>()];
+            for (offset, byte) in bytes[..width].iter_mut().enumerate() {
+                *byte = self.read(address.wrapping_add(offset as Address));
+            }
+            return T::from_le_bytes(&bytes[..width])
+        }
+        let value: T = self.bus_read(address);
+        self.log_access(AccessKind::Read, address, value.demote());
+        value
+    }
+
+    fn write<T: MemoryUnit>(&mut self, value: T, address: Address) -> () {
+        // Same as reads, low byte first
+        let width = mem::size_of::<T>();
+        if width > 1 {
+            let mut bytes = [0 as Byte; mem::size_of::<Word>()];
+            value.copy_into_le_bytes(&mut bytes[..width]);
+            for (offset, byte) in bytes[..width].iter().enumerate() {
+                self.write(*byte, address.wrapping_add(offset as Address));
+            }
+            return
+        }
+        self.log_access(AccessKind::Write, address, value.demote());
+        self.bus_write(value, address)
+    }
+}
+
+// TODO: Override get_bank to implement mapped addressing against a structure full of MemoryRegions
+impl<'a> MemoryMap<'a> {
+    // What the CPU would read from address, for debuggers. Reads don't change anything, and peeking keeps it out of the access log
+    pub fn peek(&mut self, address: Address) -> Byte {
+        self.bus_read(address)
+    }
+
+    // A single byte read with all the side effects a CPU read has, but nothing logged
+    fn bus_read<T: MemoryUnit>(&mut self, address: Address) -> T {
+        let address = mirror_echo_ram(address);
         let _address = address as usize;
         if _address == IE_START {
             self.ie.read(address)
@@ -233,35 +387,58 @@ impl<'a> MemoryRegion for MemoryMap<'a> {
         }
         else if _address >= IOREGS_START {
             // Some registers have special behaviors
-            // TODO: Implement joypad
-            if address == 0xFF00 {
-                T::promote(self.joypad.read())
+            let value: Byte = if address == 0xFF00 {
+                self.joypad.read()
             }
             else if address == 0xFF04 {
-                T::promote(self.timer.read_divider())
+                self.timer.read_divider()
             }
             else if address == 0xFF05 {
-                T::promote(self.timer.read_counter())
+                self.timer.read_counter()
             }
             else if address == 0xFF06 {
-                T::promote(self.timer.read_modulo())
+                self.timer.read_modulo()
             }
             else if address == 0xFF07 {
-                T::promote(self.timer.read_control())
+                self.timer.read_control()
+            }
+            else if address == 0xFF44 {
+                match self.ly_override {
+                    Some(ly) => ly,
+                    None => self.io_registers.read(address)
+                }
+            }
+            else if address == VBK_ADDRESS {
+                self.vram_bank as Byte
             }
             else {
                 self.io_registers.read(address)
-            }
+            };
+            // Unused and write-only bits float high on the bus
+            // KEY1, VBK, and OPRI only exist on CGB, on DMG they're unmapped like any other missing register
+            let mask = match address {
+                KEY1_ADDRESS if self.mode == GameboyMode::Cgb => 0x7E,
+                VBK_ADDRESS if self.mode == GameboyMode::Cgb => 0xFE,
+                OPRI_ADDRESS if self.mode == GameboyMode::Cgb => 0xFE,
+                _ => io_read_mask(address)
+            };
+            T::promote(value | mask)
         }
         else if _address >= UNUSABLE_START {
-            self.unusable.read(address)
+            // Nothing is really mapped here, and what comes back depends on the hardware.
+            // DMG reads 0x00, or 0xFF while the PPU has OAM tied up. Later CGBs repeat the high nibble of the low address byte, so 0xFEB3 reads 0xBB
+            let value: Byte = match self.mode {
+                GameboyMode::Dmg => if self.oam_accessible() { 0x00 } else { 0xFF },
+                GameboyMode::Cgb => {
+                    let nibble = ((address >> 4) & 0x0F) as Byte;
+                    (nibble << 4) | nibble
+                }
+            };
+            T::promote(value)
         }
         else if _address >= OAM_START {
             self.oam.read(address)
         }
-        else if _address >= ECHORAM_START {
-            self.echo_ram.read(address)
-        }
         else if _address >= WRAM_S_START {
             self.work_ram_swappable.read(address)
         }
@@ -273,8 +450,10 @@ impl<'a> MemoryRegion for MemoryMap<'a> {
             self.cart.read(address)
         }
         else if _address >= VRAM_START {
-            // Likely to have a different BankType later
-            self.vram.read(address)
+            self.vram[self.vram_bank].read(address)
+        }
+        else if self.boot_rom_covers::<T>(address) {
+            read_from_buffer_extended(self.boot_rom.as_ref().unwrap(), _address)
         }
         else {
             // The rest of the address space is mapped from the cartridge ROM
@@ -282,7 +461,8 @@ impl<'a> MemoryRegion for MemoryMap<'a> {
         } 
     }
 
-    fn write<T: MemoryUnit>(&mut self, value: T, address: Address) -> () {
+    fn bus_write<T: MemoryUnit>(&mut self, value: T, address: Address) {
+        let address = mirror_echo_ram(address);
         let _address = address as usize;
         if _address == IE_START {
             self.ie.write(value, address)
@@ -293,18 +473,30 @@ impl<'a> MemoryRegion for MemoryMap<'a> {
         else if _address >= IOREGS_START {
             // Some registers have special behaviors
             if address == 0xFF00 {
-                // Check upper nibble of the written byte
-                match value.demote() >> 4 {
-                    0 => self.joypad.set_mode(input::JoypadMode::Unselected),
-                    1 => self.joypad.set_mode(input::JoypadMode::DPad),
-                    2 => self.joypad.set_mode(input::JoypadMode::Buttons),
-                    _ => () // Ignore the write if an invalid combination is supplied
+                // Bits 4 and 5 select a group by going low, everything else is read-only
+                match (value.demote() >> 4) & 0x3 {
+                    0 => self.joypad.set_mode(input::JoypadMode::Both),
+                    1 => self.joypad.set_mode(input::JoypadMode::Buttons),
+                    2 => self.joypad.set_mode(input::JoypadMode::DPad),
+                    _ => self.joypad.set_mode(input::JoypadMode::Unselected)
                 }
             }
-            // Redirect serial to printed ascii
+            // Serial output goes to the sink whether or not there's a link partner to send it to
             else if address == 0xFF01 {
-                let a = value.as_ascii();
-                print!("{}", a);
+                self.serial_sink.write_byte(value.demote());
+                self.io_registers.write(value, address)
+            }
+            // Starting a transfer on the internal clock swaps bytes with the link partner straight away.
+            // On the external clock it waits for the partner, see poll_serial_link. Without a partner nothing happens either way
+            else if address == 0xFF02 {
+                self.io_registers.write(value, address);
+                if (value.demote() & 0x81) == 0x81 {
+                    if let Some(link) = self.serial_link.as_mut() {
+                        let outgoing = self.io_registers.read::<Byte>(0xFF01);
+                        let incoming = link.exchange(outgoing);
+                        self.finish_serial_transfer(incoming);
+                    }
+                }
             }
             else if address == 0xFF04 {
                 self.timer.write_divider(value.demote())
@@ -318,21 +510,69 @@ impl<'a> MemoryRegion for MemoryMap<'a> {
             else if address == 0xFF07 {
                 self.timer.write_control(value.demote())
             }
+            // The mode and coincidence bits of STAT belong to the PPU, only the interrupt selects are writable
+            else if address == 0xFF41 {
+                let ppu_bits: Byte = self.io_registers.read::<Byte>(address) & 0x07;
+                self.io_registers.write(ppu_bits | (value.demote() & 0x78), address)
+            }
+            // LY can't be set, any write resets it to 0
+            else if address == 0xFF44 {
+                self.io_registers.write(0 as Byte, address);
+                self.ly_reset = true;
+            }
+            // DMA reads back whatever page was last written
             else if address == 0xFF46 {
+                self.io_registers.write(value, address);
                 self.dma(value.demote())
             }
+            // The boot ROM's last act is writing here to unmap itself, and it can't be mapped back
+            else if address == 0xFF50 {
+                if value.demote() != 0 {
+                    self.boot_rom = None;
+                }
+                self.io_registers.write(value, address)
+            }
+            // Only the armed bit of KEY1 is writable, the current speed bit is flipped by the CPU on STOP
+            else if address == KEY1_ADDRESS {
+                let current_speed: Byte = self.io_registers.read::<Byte>(address) & 0x80;
+                self.io_registers.write(current_speed | (value.demote() & 0x01), address)
+            }
+            else if address == OPRI_ADDRESS {
+                self.io_registers.write(value.demote() & 0x01, address)
+            }
+            else if address == VBK_ADDRESS {
+                if self.mode == GameboyMode::Cgb {
+                    self.vram_bank = (value.demote() & 0x01) as usize;
+                }
+            }
+            // Only NR52's power bit is writable, the low bits report which channels are playing.
+            // Powering the APU down stops every channel and clears NR10 through NR51
+            else if address == 0xFF26 {
+                let power = value.demote() & 0x80;
+                if power == 0 {
+                    for register in 0xFF10..0xFF26 {
+                        self.io_registers.write(0 as Byte, register)
+                    }
+                    self.io_registers.write(0 as Byte, address)
+                }
+                else {
+                    let channels: Byte = self.io_registers.read::<Byte>(address) & 0x0F;
+                    self.io_registers.write(power | channels, address)
+                }
+            }
             else {
                 self.io_registers.write(value, address)
             }
         }
         else if _address >= UNUSABLE_START {
-            self.unusable.write(value, address)
+            if !self.write_protected_regions {
+                self.unusable.write(value, address)
+            }
         }
         else if _address >= OAM_START {
-            self.oam.write(value, address)
-        }
-        else if _address >= ECHORAM_START {
-            self.echo_ram.write(value, address)
+            if !self.write_protected_regions || self.oam_accessible() {
+                self.oam.write(value, address)
+            }
         }
         else if _address >= WRAM_S_START {
             self.work_ram_swappable.write(value, address)
@@ -345,16 +585,43 @@ impl<'a> MemoryRegion for MemoryMap<'a> {
             self.cart.write(value, address)
         }
         else if _address >= VRAM_START {
-            // Likely to have a different BankType later
-            self.vram.write(value, address)
+            if !self.write_protected_regions || self.vram_accessible() {
+                self.vram[self.vram_bank].write(value, address)
+            }
         }
         else {
-            // The rest of the address space is mapped from the cartridge ROM
+            // The rest of the address space is mapped from the cartridge ROM, where writes only ever reach the MBC registers.
+            // Changing ROM itself goes through load_region instead
             self.cart.write(value, address)
         }
     }
 }
 
+impl MemoryMapData {
+    pub fn cart(&self) -> &Cart {
+        &self.cart
+    }
+
+    pub fn cgb_support(&self) -> CgbSupport {
+        self.cart.cgb_support()
+    }
+
+    // Refills VRAM, WRAM, OAM, and HRAM as they'd look at power on. Only makes sense before anything runs.
+    // Resets fill them the same way again
+    pub fn init_ram(&mut self, init: RamInit) {
+        self.ram_init = init;
+        let [vram, vram_bank_1] = &mut self.vram;
+        init.fill(&mut [
+            vram,
+            &mut self.work_ram,
+            &mut self.work_ram_swappable,
+            &mut self.oam,
+            &mut self.hram,
+            vram_bank_1
+        ]);
+    }
+}
+
 impl<'a> MemoryMap<'a> {
     pub fn allocate(cart: Cart, joypad: Joypad) -> MemoryMapData {
         let timer: Timer = Timer::new() ;
@@ -362,45 +629,521 @@ impl<'a> MemoryMap<'a> {
             cart,
             timer,
             joypad,
-            vram: [0; EXRAM_START - VRAM_START],
+            vram: [[0; EXRAM_START - VRAM_START]; 2],
             work_ram: [0; WRAM_S_START - WRAM_START],
             work_ram_swappable: [0; ECHORAM_START - WRAM_S_START],
-            echo_ram: [0; OAM_START - ECHORAM_START],
             oam: [0; UNUSABLE_START - OAM_START],
             unusable: [0; IOREGS_START - UNUSABLE_START],
             io_registers: [0xFF; HRAM_START - IOREGS_START],
             hram: [0; IE_START - HRAM_START],
             ie: [0; 1],
+            ram_init: RamInit::Zeros,
         }
     }
 
     pub fn new(data: &mut MemoryMapData) -> MemoryMap {
+        let [vram, vram_bank_1] = &mut data.vram;
         MemoryMap { 
             cart: &mut data.cart,
             timer: &mut data.timer,
             joypad: &mut data.joypad,
-            vram: SimpleRegion { start: VRAM_START as Address, data: &mut data.vram },
+            vram: [
+                SimpleRegion { start: VRAM_START as Address, data: vram },
+                SimpleRegion { start: VRAM_START as Address, data: vram_bank_1 }
+            ],
+            vram_bank: 0,
             work_ram: SimpleRegion { start: WRAM_START as Address, data: &mut data.work_ram },
             work_ram_swappable: SimpleRegion { start: WRAM_S_START as Address, data: &mut data.work_ram_swappable },
-            echo_ram: SimpleRegion { start: ECHORAM_START as Address, data: &mut data.echo_ram },
             oam: SimpleRegion { start: OAM_START as Address, data: &mut data.oam },
             unusable: SimpleRegion { start: UNUSABLE_START as Address, data: &mut data.unusable },
             io_registers: SimpleRegion { start: IOREGS_START as Address, data: &mut data.io_registers },
             hram: SimpleRegion { start: HRAM_START as Address, data: &mut data.hram },
             ie: SimpleRegion { start: IE_START as Address, data: &mut data.ie },
+            ram_init: data.ram_init,
+            mode: GameboyMode::Dmg,
+            write_protected_regions: true,
+            ly_reset: false,
+            ly_override: None,
+            serial_sink: Box::new(StdoutSink::new()),
+            serial_link: None,
+            boot_rom: None,
+            access_log: None,
+        }
+    }
+
+    // Maps a boot ROM over the start of the cart. 256 bytes for DMG, 2304 for CGB, which also covers 0x0200-0x08FF and leaves the header showing through
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<Byte>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    fn boot_rom_covers<T: MemoryUnit>(&self, address: Address) -> bool {
+        match self.boot_rom {
+            Some(ref boot_rom) => {
+                let start = address as usize;
+                let end = start + mem::size_of::<T>();
+                end <= 0x0100 || (start >= 0x0200 && end <= boot_rom.len())
+            }
+            None => false
         }
     }
 
+    pub fn cart(&self) -> &Cart {
+        self.cart
+    }
+
+    // The cart as the running game sees it, for saving RAM mid-session
+    pub fn cart_mut(&mut self) -> &mut Cart {
+        self.cart
+    }
+
+    // Puts a different cart in the slot, handing back the one that was there
+    pub fn swap_cart(&mut self, cart: Cart) -> Cart {
+        mem::replace(self.cart, cart)
+    }
+
+    // Everything but the cart's memory and the joypad back to how allocate and init_ram left it, as if the power was cycled.
+    // Where serial output goes, the link partner, and whether accesses are logged belong to the host, so those stay
+    pub fn reset(&mut self) {
+        *self.timer = Timer::new();
+        self.cart.reset_registers();
+        let [vram, vram_bank_1] = &mut self.vram;
+        self.ram_init.fill(&mut [
+            &mut *vram.data,
+            &mut *self.work_ram.data,
+            &mut *self.work_ram_swappable.data,
+            &mut *self.oam.data,
+            &mut *self.hram.data,
+            &mut *vram_bank_1.data
+        ]);
+        self.vram_bank = 0;
+        self.unusable.data.fill(0);
+        self.ie.data.fill(0);
+        self.io_registers.data.fill(0xFF);
+        self.ly_reset = false;
+        self.boot_rom = None;
+        if let Some(log) = self.access_log.as_mut() {
+            log.clear();
+        }
+    }
+
+    fn log_access(&mut self, kind: AccessKind, address: Address, value: Byte) {
+        if let Some(log) = self.access_log.as_mut() {
+            log.push(MemoryAccess { index: log.len() as u8, kind, address, value });
+        }
+    }
+
+    pub fn set_serial_sink(&mut self, sink: Box<dyn SerialSink>) {
+        self.serial_sink = sink;
+    }
+
+    pub fn set_serial_link(&mut self, link: Option<Box<dyn SerialLink>>) {
+        self.serial_link = link;
+    }
+
+    // Checks whether the link partner has clocked a byte over for a transfer waiting on the external clock
+    pub fn poll_serial_link(&mut self) {
+        let serial_control: Byte = self.io_registers.read(0xFF02);
+        if (serial_control & 0x81) == 0x80 {
+            let outgoing = self.io_registers.read::<Byte>(0xFF01);
+            let incoming = self.serial_link.as_mut().and_then(|link| link.poll(outgoing));
+            if let Some(incoming) = incoming {
+                self.finish_serial_transfer(incoming);
+            }
+        }
+    }
+
+    // The partner's byte lands in SB, the transfer flag clears, and the serial interrupt is requested.
+    // Real transfers take 8 bit times, here they're over as soon as both bytes are known
+    fn finish_serial_transfer(&mut self, incoming: Byte) {
+        self.io_registers.write(incoming, 0xFF01);
+        let serial_control: Byte = self.io_registers.read(0xFF02);
+        self.io_registers.write(serial_control & 0x7F, 0xFF02);
+        let interrupt_flag: Byte = self.io_registers.read(0xFF0F);
+        self.io_registers.write(interrupt_flag | (1 << 3), 0xFF0F);
+    }
+
+    // Whether LY was written since the last check, clearing the request
+    pub fn take_ly_reset(&mut self) -> bool {
+        mem::replace(&mut self.ly_reset, false)
+    }
+
+    // All 40 OAM entries in slot order, straight from OAM
+    pub fn oam_entries(&self) -> impl Iterator<Item = OamEntry> + '_ {
+        self.oam.data.chunks_exact(OamEntry::SIZE).map(OamEntry::from_bytes)
+    }
+
+    pub fn add_game_genie_code(&mut self, code: GameGenieCode) {
+        self.cart.add_game_genie_code(code)
+    }
+
+    // Pokes a GameShark code's value into place without any of a CPU write's side effects. Cart RAM gets it in the code's
+    // bank, whichever bank is mapped and even if the game has RAM disabled
+    pub fn apply_gameshark_code(&mut self, code: &GameSharkCode) {
+        if (EXRAM_START..WRAM_START).contains(&(code.address as usize)) {
+            self.cart.patch_ram_bank(code.bank, code.address, code.value)
+        }
+        else {
+            self.load_region(code.address, &[code.value])
+        }
+    }
+
+    // Borrow a run of VRAM straight from its backing array, for reading whole tiles without going through the bus word by word.
+    // The PPU sees both banks whichever one VBK has switched in for the CPU
+    pub fn vram_block(&self, bank: usize, start: Address, length: usize) -> &[Byte] {
+        let offset = (start as usize) - VRAM_START;
+        &self.vram[bank].data[offset..(offset + length)]
+    }
+
+    // Copy a block of bytes straight into backing memory, skipping the side effects a CPU write would trigger.
+    // Handy for setting up tile data, tile maps, and programs in tests. Cart ROM and RAM are patched in place, as currently
+    // banked, instead of the bytes being taken as MBC register writes. VRAM goes to whichever bank VBK has switched in.
+    // Anything that would run past 0xFFFF is dropped
+    pub fn load_region(&mut self, start: Address, bytes: &[Byte]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            let address = match Address::try_from(offset).ok().and_then(|offset| start.checked_add(offset)) {
+                Some(address) => mirror_echo_ram(address),
+                None => break
+            };
+            match self.backing_region(address) {
+                Some(region) => region.write(*byte, address),
+                None if (address as usize) < VRAM_START => self.cart.patch_rom(address, *byte),
+                None => self.cart.patch_ram(address, *byte)
+            }
+        }
+    }
+
+    // The plain buffer behind an address, or None if the address belongs to the cartridge
+    fn backing_region(&mut self, address: Address) -> Option<&mut SimpleRegion<'a>> {
+        let _address = address as usize;
+        if _address == IE_START {
+            Some(&mut self.ie)
+        }
+        else if _address >= HRAM_START {
+            Some(&mut self.hram)
+        }
+        else if _address >= IOREGS_START {
+            Some(&mut self.io_registers)
+        }
+        else if _address >= UNUSABLE_START {
+            Some(&mut self.unusable)
+        }
+        else if _address >= OAM_START {
+            Some(&mut self.oam)
+        }
+        else if _address >= WRAM_S_START {
+            Some(&mut self.work_ram_swappable)
+        }
+        else if _address >= WRAM_START {
+            Some(&mut self.work_ram)
+        }
+        else if _address >= EXRAM_START {
+            None
+        }
+        else if _address >= VRAM_START {
+            Some(&mut self.vram[self.vram_bank])
+        }
+        else {
+            None
+        }
+    }
+
+    // The PPU mode as last reported in STAT, or None while the LCD is off and the PPU isn't touching anything
+    fn ppu_mode(&mut self) -> Option<Byte> {
+        let lcdc: Byte = self.io_registers.read(0xFF40);
+        let stat: Byte = self.io_registers.read(0xFF41);
+        if (lcdc & (1 << 7)) > 0 { Some(stat & 0x3) } else { None }
+    }
+
+    // VRAM is tied up while the PPU draws pixels
+    fn vram_accessible(&mut self) -> bool {
+        self.ppu_mode() != Some(3)
+    }
+
+    // OAM is tied up while the PPU scans objects and draws pixels
+    fn oam_accessible(&mut self) -> bool {
+        !matches!(self.ppu_mode(), Some(2) | Some(3))
+    }
+
     // Cheating DMA function that completes instantly instead of in 160 dots
     fn dma(&mut self, source_upper_byte: Byte) {
         const DMA_BYTES: Address = 0xA0;
-        let dma_base = (source_upper_byte as Address) << 8;
+        // DMA can't see echo RAM, OAM, or IO. Pages 0xE0 and up land on WRAM 0x2000 lower instead
+        let source_page = if source_upper_byte >= 0xE0 { source_upper_byte - 0x20 } else { source_upper_byte };
+        let dma_base = (source_page as Address) << 8;
         for i in 0..DMA_BYTES {
             let source = dma_base + i;
-            // Copy to OAM
+            // Copy to OAM. DMA has its own path into OAM, so the PPU holding it doesn't matter
             let destination = 0xFE00 + i;
-            let copy_byte: Byte = self.read(source);
-            self.write(copy_byte, destination);
+            // Straight off the bus, DMA isn't the CPU so it doesn't show up in the access log
+            let copy_byte: Byte = self.bus_read(source);
+            self.oam.write(copy_byte, destination);
+        }
+    }
+}
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    // A 32 KiB ROM-only cart with the given program at the entry point and nothing else, for tests that need a live memory map
+    pub fn test_cart(program: &[Byte]) -> Cart {
+        let mut rom = vec![0 as Byte; 0x8000];
+        rom[0x0100..(0x0100 + program.len())].copy_from_slice(program);
+        Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("Blank ROM should always be valid")
+    }
+
+    pub fn test_memory(program: &[Byte]) -> MemoryMapData {
+        MemoryMap::allocate(test_cart(program), Joypad::new())
+    }
+
+    #[test]
+    fn load_region_patches_cart_memory_instead_of_writing_mbc_registers() {
+        // MBC1 with 8KiB of RAM and four ROM banks, each marked with its number
+        let mut rom = vec![0 as Byte; 0x10000];
+        rom[0x0147] = 0x03;
+        rom[0x0148] = 0x01;
+        rom[0x0149] = 0x02;
+        for bank in 1..4 {
+            rom[bank * 0x4000] = bank as Byte;
+        }
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid"), Joypad::new());
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // As a CPU write this would select bank 3
+        memory.load_region(0x2000, &[0x03]);
+        assert_eq!(memory.read::<Byte>(0x2000), 0x03);
+        assert_eq!(memory.read::<Byte>(0x4000), 1);
+        // RAM is still disabled, but the bytes land anyway and show up once the game enables it
+        memory.load_region(0xA000, &[0x12, 0x34]);
+        assert_eq!(memory.read::<Byte>(0xA000), 0xFF);
+        memory.write(0x0A as Byte, 0x0000);
+        assert_eq!(memory.read::<Word>(0xA000), 0x3412);
+    }
+
+    #[test]
+    fn load_region_stops_at_the_top_of_the_address_space() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.load_region(0xFFFE, &[0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(memory.read::<Byte>(0xFFFE), 0x11);
+        assert_eq!(memory.read::<Byte>(0xFFFF), 0x22);
+        assert_eq!(memory.read::<Byte>(0x0000), 0x00);
+    }
+
+    #[test]
+    fn permissive_writes_land_in_locked_regions_but_still_bank() {
+        // MBC1 with four ROM banks, each marked with its number
+        let mut rom = vec![0 as Byte; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        for bank in 1..4 {
+            rom[bank * 0x4000] = bank as Byte;
+        }
+        let mut memory_data = MemoryMap::allocate(Cart::from_bytes(rom, Box::new(ManualClock::default())).expect("MBC1 test ROM should be valid"), Joypad::new());
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // LCD on and the PPU in mode 3, holding both VRAM and OAM
+        memory.io_registers.write(0x80 as Byte, 0xFF40);
+        memory.io_registers.write(0x03 as Byte, 0xFF41);
+        for permissive in [false, true] {
+            memory.write_protected_regions = !permissive;
+            let value: Byte = if permissive { 0x22 } else { 0x11 };
+            for address in [0x8000, 0xFE00, 0xFEA0, 0x0150] {
+                memory.write(value, address);
+            }
+            let landed = if permissive { value } else { 0x00 };
+            assert_eq!(memory.vram[0].data[0], landed);
+            assert_eq!(memory.oam.data[0], landed);
+            assert_eq!(memory.unusable.data[0], landed);
+            // ROM stays as it was, and a bank select still selects
+            assert_eq!(memory.read::<Byte>(0x0150), 0x00);
+            let bank: Byte = if permissive { 3 } else { 2 };
+            memory.write(bank, 0x2000);
+            assert_eq!(memory.read::<Byte>(0x4000), bank);
+        }
+    }
+
+    #[test]
+    fn unused_io_bits_read_back_as_1() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        for address in [0xFF03, 0xFF07, 0xFF0F, 0xFF10, 0xFF41, 0xFF4C] {
+            memory.write(0x00 as Byte, address);
         }
+        // Unmapped registers read as all 1s, whatever was written
+        assert_eq!(memory.read::<Byte>(0xFF03), 0xFF);
+        assert_eq!(memory.read::<Byte>(0xFF4C), 0xFF);
+        assert_eq!(memory.read::<Byte>(0xFF07), 0xF8);
+        assert_eq!(memory.read::<Byte>(0xFF0F), 0xE0);
+        assert_eq!(memory.read::<Byte>(0xFF10), 0x80);
+        assert_eq!(memory.read::<Byte>(0xFF41) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn opri_only_keeps_bit_0_and_only_on_cgb() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.mode = GameboyMode::Cgb;
+        memory.write(0x00 as Byte, OPRI_ADDRESS);
+        assert_eq!(memory.read::<Byte>(OPRI_ADDRESS), 0xFE);
+        memory.write(0x03 as Byte, OPRI_ADDRESS);
+        assert_eq!(memory.read::<Byte>(OPRI_ADDRESS), 0xFF);
+        memory.mode = GameboyMode::Dmg;
+        memory.write(0x00 as Byte, OPRI_ADDRESS);
+        assert_eq!(memory.read::<Byte>(OPRI_ADDRESS), 0xFF);
+    }
+
+    #[test]
+    fn vbk_switches_vram_banks_only_on_cgb() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // LCD off, so VRAM isn't tied up by the PPU
+        memory.write(0x00 as Byte, 0xFF40);
+        memory.write(0x11 as Byte, 0x8000);
+        memory.mode = GameboyMode::Cgb;
+        assert_eq!(memory.read::<Byte>(VBK_ADDRESS), 0xFE);
+        // Only bit 0 picks the bank, the rest read back as 1
+        memory.write(0x03 as Byte, VBK_ADDRESS);
+        assert_eq!(memory.read::<Byte>(VBK_ADDRESS), 0xFF);
+        assert_eq!(memory.read::<Byte>(0x8000), 0x00);
+        memory.write(0x22 as Byte, 0x8000);
+        memory.load_region(0x9FFF, &[0x33]);
+        assert_eq!(memory.vram_block(1, 0x8000, 1), [0x22]);
+        assert_eq!(memory.vram_block(1, 0x9FFF, 1), [0x33]);
+        memory.write(0x00 as Byte, VBK_ADDRESS);
+        assert_eq!(memory.read::<Byte>(0x8000), 0x11);
+        assert_eq!(memory.read::<Byte>(0x9FFF), 0x00);
+        // DMG has no VBK, writes to it don't switch anything
+        memory.mode = GameboyMode::Dmg;
+        memory.write(0x01 as Byte, VBK_ADDRESS);
+        assert_eq!(memory.read::<Byte>(VBK_ADDRESS), 0xFF);
+        assert_eq!(memory.read::<Byte>(0x8000), 0x11);
+    }
+
+    #[test]
+    fn dma_copies_a_page_into_oam_and_folds_high_pages_onto_wram() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let oam = |memory: &mut MemoryMap| memory.oam_entries()
+            .flat_map(|object| [object.y_pos, object.x_pos, object.tile_index, object.flags])
+            .collect::<Vec<Byte>>();
+        let work_ram = (0..0xA0).collect::<Vec<Byte>>();
+        memory.load_region(0xC000, &work_ram);
+        // DMA reads don't belong to the CPU, so the only access logged is the write that started it
+        memory.access_log = Some(vec![]);
+        memory.write(0xC0 as Byte, 0xFF46);
+        assert_eq!(memory.access_log.take().map(|log| log.len()), Some(1));
+        assert_eq!(oam(&mut memory), work_ram);
+        assert_eq!(memory.read::<Byte>(0xFF46), 0xC0);
+        // Echo RAM, OAM and IO can't be reached, pages 0xE0 and up come from 0x2000 lower
+        let top_page = (0..0xA0).map(|value| value ^ 0x5A).collect::<Vec<Byte>>();
+        memory.load_region(0xDF00, &top_page);
+        memory.write(0xFF as Byte, 0xFF46);
+        assert_eq!(oam(&mut memory), top_page);
+        memory.write(0xE0 as Byte, 0xFF46);
+        assert_eq!(oam(&mut memory), work_ram);
+    }
+
+    #[test]
+    fn unusable_region_reads_fixed_values_and_ignores_writes() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.io_registers.write(0x00 as Byte, 0xFF40);
+        assert_eq!(memory.read::<Byte>(0xFEA0), 0x00);
+        memory.write(0x55 as Byte, 0xFEA0);
+        assert_eq!(memory.read::<Byte>(0xFEA0), 0x00);
+        assert_eq!(memory.unusable.data[0], 0x00);
+        // With the LCD on and the PPU in mode 2, OAM is tied up and DMG reads 0xFF instead
+        memory.io_registers.write(0x80 as Byte, 0xFF40);
+        memory.io_registers.write(0x82 as Byte, 0xFF41);
+        assert_eq!(memory.read::<Byte>(0xFEA0), 0xFF);
+        memory.mode = GameboyMode::Cgb;
+        assert_eq!(memory.read::<Byte>(0xFEA0), 0xAA);
+        assert_eq!(memory.read::<Byte>(0xFEB3), 0xBB);
+        assert_eq!(memory.read::<Byte>(0xFEFF), 0xFF);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn echo_ram_mirrors_wram_up_to_0xfdff_and_stops_at_oam() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // LCD off so OAM is open to the CPU
+        memory.io_registers.write(0x00 as Byte, 0xFF40);
+        memory.write(0x11 as Byte, 0xFDFF);
+        memory.write(0x22 as Byte, 0xFE00);
+        assert_eq!(memory.read::<Byte>(0xDDFF), 0x11);
+        assert_eq!(memory.read::<Byte>(0xFDFF), 0x11);
+        assert_eq!(memory.read::<Byte>(0xFE00), 0x22);
+        assert_eq!(memory.read::<Byte>(0xDE00), 0x00);
+        assert_eq!(memory.oam_entries().next().unwrap().y_pos, 0x22);
+        // Both directions, and through load_region too
+        memory.write(0x33 as Byte, 0xC000);
+        assert_eq!(memory.read::<Byte>(0xE000), 0x33);
+        memory.write(0x44 as Byte, 0xF000);
+        assert_eq!(memory.read::<Byte>(0xD000), 0x44);
+        memory.load_region(0xE001, &[0x55]);
+        assert_eq!(memory.read::<Byte>(0xC001), 0x55);
+        assert_eq!(mirror_echo_ram(0xE000), 0xC000);
+        assert_eq!(mirror_echo_ram(0xFDFF), 0xDDFF);
+        assert_eq!(mirror_echo_ram(0xFE00), 0xFE00);
+        assert_eq!(mirror_echo_ram(0xDFFF), 0xDFFF);
+    }
+
+    #[test]
+    fn nr52_power_clears_the_apu_and_nrxx_reads_are_masked() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // Powered on with channel 1 playing, as the boot ROM leaves it
+        memory.io_registers.write(0xF1 as Byte, 0xFF26);
+        memory.write(0x00 as Byte, 0xFF10);
+        assert_eq!(memory.read::<Byte>(0xFF10), 0x80);
+        // The channel bits are read only
+        memory.write(0x8F as Byte, 0xFF26);
+        assert_eq!(memory.read::<Byte>(0xFF26), 0xF1);
+        memory.write(0x77 as Byte, 0xFF24);
+        memory.write(0xFF as Byte, 0xFF11);
+        memory.write(0x00 as Byte, 0xFF26);
+        assert_eq!(memory.read::<Byte>(0xFF26), 0x70);
+        assert_eq!(memory.read::<Byte>(0xFF24), 0x00);
+        // NR11 only shows its duty bits, cleared along with everything else
+        assert_eq!(memory.read::<Byte>(0xFF11), 0x3F);
+        memory.write(0x80 as Byte, 0xFF26);
+        assert_eq!(memory.read::<Byte>(0xFF26), 0xF0);
+        // Frequency low bytes are write only
+        memory.write(0x00 as Byte, 0xFF13);
+        assert_eq!(memory.read::<Byte>(0xFF13), 0xFF);
+    }
+
+    #[test]
+    fn oam_entries_decode_every_slot_in_order() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let oam = (0..0xA0).map(|i| i as Byte).collect::<Vec<Byte>>();
+        memory.load_region(0xFE00, &oam);
+        memory.load_region(0xFE00 + 4 * 39, &[10, 20, 30, 0x40]);
+        let entries = memory.oam_entries().collect::<Vec<OamEntry>>();
+        assert_eq!(entries.len(), 40);
+        assert_eq!(entries[0], OamEntry { y_pos: 0, x_pos: 1, tile_index: 2, flags: 3 });
+        assert_eq!(entries[1], OamEntry { y_pos: 4, x_pos: 5, tile_index: 6, flags: 7 });
+        assert_eq!(entries[39], OamEntry { y_pos: 10, x_pos: 20, tile_index: 30, flags: 0x40 });
+    }
+
+    // The first and last byte of VRAM, both WRAM banks, OAM, and HRAM straight after init_ram
+    fn ram_after_init(ram_init: RamInit) -> Vec<Byte> {
+        let mut memory_data = test_memory(&[]);
+        memory_data.init_ram(ram_init);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        [0x8000, 0x9FFF, 0xC000, 0xCFFF, 0xD000, 0xDFFF, 0xFE00, 0xFE9F, 0xFF80, 0xFFFE].iter()
+            .map(|&address| memory.read::<Byte>(address))
+            .collect()
+    }
+
+    #[test]
+    fn init_ram_fills_every_region_with_the_chosen_pattern() {
+        assert!(ram_after_init(RamInit::Zeros).iter().all(|&byte| byte == 0x00));
+        assert!(ram_after_init(RamInit::Ones).iter().all(|&byte| byte == 0xFF));
+        // The same seed always gives the same garbage
+        let random = ram_after_init(RamInit::Random(7));
+        assert_eq!(random, ram_after_init(RamInit::Random(7)));
+        assert_ne!(random, ram_after_init(RamInit::Random(8)));
+        assert!(random.iter().any(|&byte| byte != 0x00 && byte != 0xFF));
+    }
+}
@@ -0,0 +1,23 @@
+use crate::memory_gb::Byte;
+
+// One 4 byte object attribute entry, as laid out in OAM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OamEntry {
+    pub y_pos: Byte,
+    pub x_pos: Byte,
+    pub tile_index: Byte,
+    pub flags: Byte
+}
+
+impl OamEntry {
+    pub const SIZE: usize = 4;
+
+    pub fn from_bytes(bytes: &[Byte]) -> OamEntry {
+        OamEntry {
+            y_pos:      bytes[0],
+            x_pos:      bytes[1],
+            tile_index: bytes[2],
+            flags:      bytes[3]
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::memory_gb::Byte;
+use crate::ppu::{Color, ColorIndex};
+
+// A fully resolved color, ready to go on screen or into an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8
+}
+
+impl Rgba {
+    pub const fn from_0rgb(value: u32) -> Rgba {
+        Rgba {
+            r: (value >> 16) as u8,
+            g: (value >> 8) as u8,
+            b: value as u8,
+            a: 0xFF
+        }
+    }
+
+    // The packed format minifb wants, alpha is dropped
+    pub fn to_0rgb(&self) -> u32 {
+        ((self.r as u32) << 16) | ((self.g as u32) << 8) | (self.b as u32)
+    }
+
+    // CGB palette RAM holds little-endian RGB555. Stretch each 5 bit channel to 8 bits, copying the top bits into the bottom so white stays white
+    pub fn from_rgb555(value: u16) -> Rgba {
+        let expand = |channel: u16| -> u8 {
+            let channel = (channel & 0x1F) as u8;
+            (channel << 3) | (channel >> 2)
+        };
+        Rgba {
+            r: expand(value),
+            g: expand(value >> 5),
+            b: expand(value >> 10),
+            a: 0xFF
+        }
+    }
+}
+
+// What the four DMG shades, Color::A through Color::D, look like
+pub type Palette = [Rgba; 4];
+
+// The classic pea soup green
+pub const GREEN_PALETTE: Palette = [Rgba::from_0rgb(0xe0f8d0), Rgba::from_0rgb(0x88c070), Rgba::from_0rgb(0x346856), Rgba::from_0rgb(0x081820)];
+pub const GRAY_PALETTE: Palette = [Rgba::from_0rgb(0xffffff), Rgba::from_0rgb(0xaaaaaa), Rgba::from_0rgb(0x555555), Rgba::from_0rgb(0x000000)];
+// The Game Boy Pocket's greyish olive screen
+pub const POCKET_PALETTE: Palette = [Rgba::from_0rgb(0xc4cfa1), Rgba::from_0rgb(0x8b956d), Rgba::from_0rgb(0x4d533c), Rgba::from_0rgb(0x1f1f1f)];
+
+pub fn named_palette(name: &str) -> Option<Palette> {
+    match name {
+        "green" => Some(GREEN_PALETTE),
+        "gray" | "grey" => Some(GRAY_PALETTE),
+        "pocket" => Some(POCKET_PALETTE),
+        _ => None
+    }
+}
+
+// Where a color index gets looked up
+#[derive(Debug, Clone, Copy)]
+pub enum PaletteSource<'a> {
+    // A DMG palette register like BGP or OBP0, and what its four shades look like
+    Dmg { register: Byte, shades: &'a Palette },
+    // One 8 byte CGB palette, four RGB555 colors straight out of palette RAM
+    Cgb(&'a [Byte; 8])
+}
+
+// BGP/OBP value that maps every index onto the shade with the same number
+pub const IDENTITY_PALETTE: Byte = 0b11_10_01_00;
+
+// A DMG shade as it should appear on screen. Shades in the PPU's frame have already been through their palette register,
+// so they only need the identity one on the way through
+pub fn resolve_shade(shade: Color, shades: &Palette) -> Rgba {
+    let index = ColorIndex::from_value(shade.to_value()).expect("Shades only go up to 3");
+    resolve_color(index, PaletteSource::Dmg { register: IDENTITY_PALETTE, shades })
+}
+
+// The single place a color index turns into an actual color, so the screen, the debug views, and anything else that
+// shows pixels can't disagree about what they look like
+pub fn resolve_color(index: ColorIndex, palette: PaletteSource) -> Rgba {
+    match palette {
+        PaletteSource::Dmg { register, shades } => shades[index.apply_palette(register).to_value() as usize],
+        PaletteSource::Cgb(colors) => {
+            let entry = index.to_value() as usize * 2;
+            Rgba::from_rgb555(u16::from_le_bytes([colors[entry], colors[entry + 1]]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dmg_indices_go_through_the_register_to_a_shade() {
+        for register in 0..=0xFF {
+            for value in 0..4 {
+                let index = ColorIndex::from_value(value).unwrap();
+                let shade = (register >> (value * 2)) & 0b11;
+                assert_eq!(resolve_color(index, PaletteSource::Dmg { register, shades: &GREEN_PALETTE }), GREEN_PALETTE[shade as usize]);
+            }
+        }
+        assert_eq!(resolve_shade(Color::C, &POCKET_PALETTE), POCKET_PALETTE[2]);
+    }
+
+    #[test]
+    fn cgb_indices_pick_an_rgb555_entry() {
+        // White, red, green, blue
+        let colors = [0xFF, 0x7F, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C];
+        assert_eq!(resolve_color(ColorIndex::Blank, PaletteSource::Cgb(&colors)).to_0rgb(), 0xFFFFFF);
+        assert_eq!(resolve_color(ColorIndex::One, PaletteSource::Cgb(&colors)).to_0rgb(), 0xFF0000);
+        assert_eq!(resolve_color(ColorIndex::Two, PaletteSource::Cgb(&colors)).to_0rgb(), 0x00FF00);
+        assert_eq!(resolve_color(ColorIndex::Three, PaletteSource::Cgb(&colors)), Rgba { r: 0x00, g: 0x00, b: 0xFF, a: 0xFF });
+        // Channels stretch to 8 bits with the top bits copied into the bottom
+        assert_eq!(Rgba::from_rgb555(0x0010).r, 0x84);
+    }
+}
@@ -1,22 +1,17 @@
-use std::cell::RefMut;
 use std::mem;
-use std::rc::Rc;
-use std::cell::RefCell;
 
+use crate::gameboy::GameboyMode;
+use crate::memory_gb;
 use crate::memory_gb::Address;
 use crate::memory_gb::Byte;
 use crate::memory_gb::ByteExt;
 use crate::memory_gb::Word;
 use crate::memory_gb::MemoryRegion;
 use crate::memory_gb::MemoryMap;
-
-#[derive(Clone, Copy)]
-struct OamEntry {
-    y_pos: Byte,
-    x_pos: Byte,
-    tile_index: Byte,
-    flags: Byte
-}
+use crate::memory_gb::OPRI_ADDRESS;
+use crate::oam::OamEntry;
+use crate::palette::{resolve_color, Palette, PaletteSource, Rgba};
+use crate::tracer::Tracer;
 
 #[derive(Clone, Copy)]
 enum ObjectIntersection {
@@ -33,9 +28,10 @@ struct Tile {
 }
 
 impl Tile {
-    pub fn from_address(memory: &mut RefMut<MemoryMap>, address: Address) -> Tile {
-        // println!("Tile address {:x}", address);
-        let lines: [Word; 8] = core::array::from_fn(|i| memory.read(address + (mem::size_of::<Word>() * i) as Address));
+    pub fn from_address(memory: &mut MemoryMap, address: Address) -> Tile {
+        // Tiles always live in VRAM, so grab all 16 bytes in one go and split them into lines
+        let bytes = memory.vram_block(0, address, mem::size_of::<Tile>());
+        let lines: [Word; 8] = core::array::from_fn(|i| memory_gb::read_from_buffer(bytes, (mem::size_of::<Word>() * i) as Address));
         Tile {
             lines
         }
@@ -136,11 +132,27 @@ impl Color {
             Color::D => 3
         }
     }
+}
 
-    pub fn is_blank_color(&self, palette: Byte) -> bool {
-        let blank_color = palette & 0x3;
-        self.to_value() == blank_color
-    }
+// A background or window pixel before it's mixed with objects
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BgPixel {
+    color_index: ColorIndex,
+    // CGB tile attribute bit 7, puts the tile over objects no matter what the objects ask for
+    priority: bool
+}
+
+impl BgPixel {
+    const BLANK: BgPixel = BgPixel { color_index: ColorIndex::Blank, priority: false };
+}
+
+// A box in screen pixels, for debug overlays. Objects and the window often hang off the edges, so it can too
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenRect {
+    pub x: i16,
+    pub y: i16,
+    pub width: i16,
+    pub height: i16
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -165,23 +177,31 @@ const DISPLAY_BUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
 
 const SCREEN_WIDTH: usize = 160;
 const SCREEN_HEIGHT: usize = 144;
-const DOTS_PER_LINE: u32 = 456;
+pub const DOTS_PER_LINE: u32 = 456;
 // Denotes the start of VBlank
 const VBLANK_START_DOTS: u32 = DOTS_PER_LINE * (SCREEN_HEIGHT as u32);
 // Number of dots at which VBlank resets
 const DOT_MAX: u32 = VBLANK_START_DOTS + (10 * DOTS_PER_LINE);
+// Dots in a whole frame, VBlank included
+pub const DOTS_PER_FRAME: u32 = DOT_MAX;
 // Number of dots taken in an OAM Scan
 const OAM_SCAN_TIME: u32 = 80;
-// Number of dots taken in a pixel draw
+// Number of dots taken in a pixel draw with nothing slowing it down. Fine scrolling and objects stretch it out up to 289,
+// and HBlank makes up the difference so lines always come out to the same length
 const PIXEL_DRAW_TIME: u32 = 172;
-// Number of dots taken in HBlank
-const HBLANK_TIME: u32 = 204;
-// Denotes the start of HBlank on any given line
-const PIXEL_DRAW_END_DOTS: u32 = OAM_SCAN_TIME + PIXEL_DRAW_TIME;
+// Worst case pixel draw cost of an object, when it has to wait on a background fetch too
+const OBJECT_PENALTY_MAX: u32 = 11;
+// Flat pixel draw cost of fetching an object's tile
+const OBJECT_FETCH_PENALTY: u32 = 6;
 
 const TILE_WIDTH: u8 = 8;
 const TILEMAP_WH: u16 = 256;
 
+// Tile data viewer lays out tiles 16 to a row, 24 rows deep
+pub const TILE_DATA_VIEW_TILES_PER_ROW: usize = 16;
+pub const TILE_DATA_VIEW_WIDTH: usize = TILE_DATA_VIEW_TILES_PER_ROW * TILE_WIDTH as usize;
+pub const TILE_DATA_VIEW_HEIGHT: usize = 24 * TILE_WIDTH as usize;
+
 const IF_REG_ADDR: Address = 0xFF0F;
 const LCDC_ADDRESS: Address = 0xFF40;
 const STAT_ADDRESS: Address = 0xFF41;
@@ -196,32 +216,51 @@ const WY_ADDRESS: Address = 0xFF4A;
 const WX_ADDRESS: Address = 0xFF4B;
 
 
-pub struct Ppu<'a> {
+pub struct Ppu {
     current_mode: RenderMode,
     current_dot: u32,
     // Double buffer with a back and front
     display_buffer: [Color; DISPLAY_BUFFER_SIZE * 2],
+    // The same pixels before their palette was applied, laid out and swapped along with display_buffer
+    index_buffer: [ColorIndex; DISPLAY_BUFFER_SIZE * 2],
     front_buffer_base: usize,
     back_buffer_base: usize,
     oam_scan_results: Vec<OamEntry>,
+    // How long this line's pixel draw takes, worked out once the OAM scan knows which objects are on it
+    pixel_draw_time: u32,
+    // Each background/window pixel on the line being drawn, as it was before BGP mapped it to a shade.
+    // Object priority is decided from these, so they have to be kept around for the object pass
+    bg_line: [BgPixel; SCREEN_WIDTH],
     internal_window_line_counter: u16,
+    // Whether any enabled STAT interrupt condition held at the last update
+    stat_line: bool,
     frame_ready: bool,
-    system_memory: Rc<RefCell<MemoryMap<'a>>>
+    pub tracer: Option<Box<dyn Tracer>>
 }
 
-impl<'a> Ppu<'a> {
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
+}
+
+impl Ppu {
     // Creates a PPU initialized to the tail end of VBLANK
-    pub fn new(system_memory: Rc<RefCell<MemoryMap>>) -> Ppu {
+    pub fn new() -> Ppu {
         let new_ppu = Ppu { 
             current_mode: RenderMode::VBlank,
             current_dot: DOT_MAX,
             display_buffer: [Color::A; DISPLAY_BUFFER_SIZE * 2],
+            index_buffer: [ColorIndex::Blank; DISPLAY_BUFFER_SIZE * 2],
             front_buffer_base: 0,
             back_buffer_base: DISPLAY_BUFFER_SIZE,
             oam_scan_results: Vec::with_capacity(0),
+            pixel_draw_time: PIXEL_DRAW_TIME,
+            bg_line: [BgPixel::BLANK; SCREEN_WIDTH],
             internal_window_line_counter: 0,
+            stat_line: false,
             frame_ready: false,
-            system_memory
+            tracer: None
         };
         new_ppu
     }
@@ -232,13 +271,53 @@ impl<'a> Ppu<'a> {
         ready
     }
 
+    // Line the PPU is working on, 0-153. Lines 144 and up are VBlank.
+    // The PPU parks at the very end of the frame while the LCD is off, which reads as the top of line 0 like it does on hardware
+    pub fn current_scanline(&self) -> u8 {
+        ((self.current_dot % DOT_MAX) / DOTS_PER_LINE) as u8
+    }
+
+    // How far into the current line the PPU is, 0-455
+    pub fn current_dot_in_line(&self) -> u16 {
+        ((self.current_dot % DOT_MAX) % DOTS_PER_LINE) as u16
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        self.current_mode == RenderMode::VBlank
+    }
+
+    // A line of the frame being drawn, as far as the PPU has gotten with it. VBlank lines have no pixels
+    pub fn scanline_pixels(&self, line: u8) -> Option<Vec<Color>> {
+        if (line as usize) < SCREEN_HEIGHT {
+            let line_start = self.back_buffer_base + SCREEN_WIDTH * (line as usize);
+            Some(self.display_buffer[line_start..(line_start + SCREEN_WIDTH)].to_vec())
+        }
+        else {
+            None
+        }
+    }
+
     pub fn display_handle(&self) -> Vec<Color> {
         (&self.display_buffer[self.front_buffer_base .. (DISPLAY_BUFFER_SIZE + self.front_buffer_base)]).to_vec()
     }
 
-    pub fn run(&mut self) -> i16 {
+    // The last finished frame as color indices instead of shades, for re-coloring it or working out where pixels came from.
+    // Which palette an index went through isn't kept, object pixels look the same as background ones
+    pub fn index_handle(&self) -> Vec<ColorIndex> {
+        self.index_buffer[self.front_buffer_base .. (DISPLAY_BUFFER_SIZE + self.front_buffer_base)].to_vec()
+    }
+
+    // FNV-1a over the shades of the last finished frame. Unlike std's hashers it gives the same answer on every build,
+    // so a known good frame can be checked with a single number instead of a whole image
+    pub fn frame_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+        const FNV_PRIME: u64 = 0x00000100000001B3;
+        self.display_buffer[self.front_buffer_base .. (DISPLAY_BUFFER_SIZE + self.front_buffer_base)].iter()
+            .fold(FNV_OFFSET_BASIS, |hash, color| (hash ^ color.to_value() as u64).wrapping_mul(FNV_PRIME))
+    }
+
+    pub fn run(&mut self, memory: &mut MemoryMap) -> i16 {
         let running = {
-            let mut memory = self.system_memory.borrow_mut();
             let lcdc: Byte = memory.read(LCDC_ADDRESS);
             (lcdc & (1 << 7)) > 0
         };
@@ -249,19 +328,28 @@ impl<'a> Ppu<'a> {
             self.front_buffer_base = 0;
             self.frame_ready = false;
             self.internal_window_line_counter = 0;
+            self.stat_line = false;
             return 1
         }
+        // A CPU write to LY throws out the rest of the frame and starts over at the top of line 0
+        if memory.take_ly_reset() {
+            self.current_mode = RenderMode::OAMScan;
+            self.current_dot = 0;
+            self.internal_window_line_counter = 0;
+        }
         let dots_spent = match self.current_mode {
             RenderMode::OAMScan => {
-                // Scan the whole OAM in one shot since coroutines aren't 'real' yet
-                // and I really don't want to implement that without those unless I really have to
-                self.oam_scan_results.clear();
-                
+                // OAM is scanned one object every 2 dots, so all 40 slots are covered by the end of the 80 dot scan
                 const OAM_DOT_GRANULARITY: u32 = OAM_SCAN_TIME/40;
+                let slot = (self.current_dot % DOTS_PER_LINE) / OAM_DOT_GRANULARITY;
+                if slot == 0 {
+                    self.oam_scan_results.clear();
+                }
+                self.scan_oam_slot(memory, slot as Address);
                 self.current_dot += OAM_DOT_GRANULARITY;
                 if (self.current_dot % DOTS_PER_LINE) >= OAM_SCAN_TIME {
-                    self.oam_scan_results = self.scan_oam();
-                    // println!("oam_scan_results length {}", self.oam_scan_results.len());
+                    self.order_oam_scan_results(memory);
+                    self.pixel_draw_time = self.pixel_draw_duration(memory);
                 }
                 (OAM_DOT_GRANULARITY) as i16
             }
@@ -271,19 +359,22 @@ impl<'a> Ppu<'a> {
                 // but SURELY this should be good enough and things will probably mostly shake out
                 const PIXEL_DRAW_GRANULARITY: u32 = PIXEL_DRAW_TIME/4;
                 let line_number = self.current_dot / DOTS_PER_LINE;
-                self.current_dot += PIXEL_DRAW_GRANULARITY;
+                // The last chunk only runs up to the end of the pixel draw, however long this line's turned out to be
+                let dots = PIXEL_DRAW_GRANULARITY.min(self.pixel_draw_end_dots() - (self.current_dot % DOTS_PER_LINE));
+                self.current_dot += dots;
                 // If we're onscreen and at the end of the pixel drawing mode, write the pixels into the buffer
                 if line_number < SCREEN_HEIGHT as u32 {
-                    if (self.current_dot % DOTS_PER_LINE) >= PIXEL_DRAW_END_DOTS {
-                        self.draw_line(line_number);
+                    if (self.current_dot % DOTS_PER_LINE) >= self.pixel_draw_end_dots() {
+                        self.draw_line(memory, line_number);
                     }
                 }
-                PIXEL_DRAW_GRANULARITY as i16
+                dots as i16
             }
             RenderMode::HBlank => {
-                const HBLANK_GRANULARITY: u32 = HBLANK_TIME;
-                self.current_dot += HBLANK_GRANULARITY;
-                HBLANK_GRANULARITY as i16
+                // HBlank gets whatever's left of the line
+                let dots = DOTS_PER_LINE - (self.current_dot % DOTS_PER_LINE);
+                self.current_dot += dots;
+                dots as i16
             }
             RenderMode::VBlank => {
                 if self.current_dot == DOT_MAX - DOTS_PER_LINE {
@@ -297,7 +388,7 @@ impl<'a> Ppu<'a> {
             }
         };
         // Do some state transitions top level here so it happens after the cpu catches up
-        self.update_render_state();
+        self.update_render_state(memory);
         dots_spent
     }
 
@@ -309,21 +400,10 @@ impl<'a> Ppu<'a> {
     }
 
     // Handles mode changes and updates the render buffer with pixel data at the tail of VBlank
-    fn update_render_state(&mut self) {
-        let mut memory = self.system_memory.borrow_mut();
-        let mut ly_eq_lyc = false;
-        let mut start_oam_scan = false;
-        let mut start_hblank = false;
+    fn update_render_state(&mut self, memory: &mut MemoryMap) {
         let mut start_vblank = false;
 
-        let ly = (self.current_dot / DOTS_PER_LINE) as u8;
-        let lyc: Byte = memory.read(LYC_ADDRESS);
-
-        if (self.current_dot % DOTS_PER_LINE) == 0 {
-            // We have this separate flag to check for a rising edge on this condition
-            ly_eq_lyc = ly == lyc;
-            // println!("ly {}, {}", ly, ly_eq_lyc);
-        }
+        let previous_mode = self.current_mode;
 
         self.current_mode = match self.current_mode {
             RenderMode::OAMScan => {
@@ -334,8 +414,7 @@ impl<'a> Ppu<'a> {
                 }
             }
             RenderMode::PixelDraw => {
-                if (self.current_dot % DOTS_PER_LINE) >= PIXEL_DRAW_END_DOTS {
-                    start_hblank = true;
+                if (self.current_dot % DOTS_PER_LINE) >= self.pixel_draw_end_dots() {
                     RenderMode::HBlank
                 }
                 else {
@@ -352,7 +431,6 @@ impl<'a> Ppu<'a> {
                     }
                     else {
                         // HBlank typically goes back to the next line's OAM scan
-                        start_oam_scan = true;
                         RenderMode::OAMScan
                     }
                 }
@@ -364,7 +442,6 @@ impl<'a> Ppu<'a> {
                 // VBlank happens for 10 lines, until it hits the reset point
                 if self.current_dot >= DOT_MAX {
                     self.current_dot = 0;
-                    start_oam_scan = true;
                     RenderMode::OAMScan
                 }
                 else {
@@ -372,29 +449,37 @@ impl<'a> Ppu<'a> {
                 }
             }
         };
-        memory.write(ly, LY_ADDRESS);
+        // Work out LY after the transitions so the wrap back to line 0 shows up right away
+        let ly = (self.current_dot / DOTS_PER_LINE) as u8;
+        let lyc: Byte = memory.read(LYC_ADDRESS);
+        // LY and the low bits of STAT are read-only from the bus, so the PPU updates them in the register file directly
+        memory.io_registers.write(ly, LY_ADDRESS);
 
         // Update the LY=LYC check and mode in the STAT register. 
         // Probably not enough to be accurate for CPU changes to LYC
         // Might be worth trapping LYC on the CPU to cover both ends
 
-        //println!("ly {}", ly);
         let ly_eq_lyc_flag = (if lyc == ly { 1 } else { 0 }) << 2;
         let mode_number_flag = self.current_mode.mode_number();
         let old_stat: Byte = memory.read(STAT_ADDRESS);
         let stat = (old_stat & !(0x7)) | (ly_eq_lyc_flag | mode_number_flag);
-        memory.write(stat, STAT_ADDRESS);
+        memory.io_registers.write(stat, STAT_ADDRESS);
         
         // Handle possible interrupts arising from VBlank or STAT
         let mut interrupt_flag: Byte = memory.read(IF_REG_ADDR);
-        // Check stat interrupt enables and set the stat interrupt flag if enabled mode changes occur
-        if (start_oam_scan && (stat & (1 << 5)) > 0) 
-            || (ly_eq_lyc && (stat & (1 << 6)) > 0)
-            || (start_vblank && (stat & (1 << 4)) > 0) 
-            || (start_hblank && (stat & (1 << 3)) > 0) 
-        {
+        // All the enabled STAT conditions are ORed onto one line, and the interrupt only fires when that line goes high.
+        // So conditions that come true together fire once, and one coming true while another already holds doesn't fire at all.
+        // Moving to a new mode on a new line lets go of everything from the line before, leaving LY=LYC free to fire at its start
+        let stat_line = ((stat & (1 << 3)) > 0 && self.current_mode == RenderMode::HBlank)
+            || ((stat & (1 << 4)) > 0 && self.current_mode == RenderMode::VBlank)
+            || ((stat & (1 << 5)) > 0 && self.current_mode == RenderMode::OAMScan)
+            || ((stat & (1 << 6)) > 0 && lyc == ly);
+        let line_changed = (self.current_dot % DOTS_PER_LINE) == 0 && self.current_mode != previous_mode;
+        let stat_line_was_high = self.stat_line && !line_changed;
+        if stat_line && !stat_line_was_high {
             interrupt_flag |= 0x2;
         }
+        self.stat_line = stat_line;
         
         if start_vblank {
             interrupt_flag |= 0x1;
@@ -402,47 +487,88 @@ impl<'a> Ppu<'a> {
 
         memory.write(interrupt_flag, IF_REG_ADDR);
 
+        if self.current_mode != previous_mode {
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.on_ppu_mode_change(self.current_mode.mode_number(), ly);
+            }
+        }
     }
 
-    // Returns a vector of OAM entries sorted by reverse priority
-    fn scan_oam(&mut self) -> Vec<OamEntry> {
+    // Checks a single OAM slot against the current line, keeping the object if the line still has room for it
+    fn scan_oam_slot(&mut self, mem: &mut MemoryMap, slot: Address) {
         const MAX_OBJECTS_PER_LINE: usize = 10;
-        const OAM_START: Address = 0xFE00;
-        const OAM_END: Address = 0xFE9F + 1;
-        const _TOTAL_OAM_SLOTS: u8 = 40;
 
-        let mut line_objects_buffer: Vec<OamEntry> = Vec::with_capacity(MAX_OBJECTS_PER_LINE);
-
-        let mut mem = self.system_memory.borrow_mut();
+        if self.oam_scan_results.len() >= MAX_OBJECTS_PER_LINE {
+            return
+        }
 
         let lcdc: Byte = mem.read(LCDC_ADDRESS);
-        let ly: Byte = mem.read(LY_ADDRESS);
+        // Work the line out from the dot count, LY in memory can lag a step behind at the start of a frame
+        let ly = (self.current_dot / DOTS_PER_LINE) as u16;
 
         let objects_are_tall = (lcdc & (1 << 2)) > 0; 
         // Pad LY because objects exist in a space beginning 16 lines before the screen. Convert LY to that space for easy comparisons
         let ly_padded = ly + 16;
         let object_size = if objects_are_tall { 2 * TILE_WIDTH } else { TILE_WIDTH };
 
-        for entry_address in (OAM_START..OAM_END).step_by(4) {
-            let current_object = OamEntry {
-                y_pos:      mem.read(entry_address),
-                x_pos:      mem.read(entry_address + 1),
-                tile_index: mem.read(entry_address + 2),
-                flags:      mem.read(entry_address + 3)
-            };
-            // Check each object (up to max allowable) to see if they exist on this line
-            if (ly_padded >= current_object.y_pos ) && (ly_padded < (current_object.y_pos + (object_size))) {
-                line_objects_buffer.push(current_object);
-                if line_objects_buffer.len() >= MAX_OBJECTS_PER_LINE {
-                    break;
+        let current_object = mem.oam_entries().nth(slot as usize).unwrap();
+        // Check if the object exists on this line
+        if (ly_padded >= current_object.y_pos as u16) && (ly_padded < (current_object.y_pos as u16 + object_size as u16)) {
+            self.oam_scan_results.push(current_object);
+        }
+    }
+
+    // Sorts the objects found on this line by reverse priority once the scan is done
+    // The scan pushes objects in OAM order, so where an object sits in the list already says which has the lower OAM index.
+    // DMG: lower X wins, ties go to the lower OAM index. The sort is stable, so same-X objects stay in OAM order.
+    // CGB: the lower OAM index always wins, X doesn't matter. Unless OPRI asks for the DMG rule
+    fn order_oam_scan_results(&mut self, mem: &mut MemoryMap) {
+        let coordinate_priority = mem.mode == GameboyMode::Dmg || (mem.io_registers.read::<Byte>(OPRI_ADDRESS) & 0x01) > 0;
+        if coordinate_priority {
+            self.oam_scan_results.sort_by_key(|object| object.x_pos);
+        }
+        // Order is reversed because we want to draw lower priority pixels first and potentially overwrite them with higher priority ones
+        self.oam_scan_results.reverse();
+    }
+
+    // Where HBlank starts on the current line
+    fn pixel_draw_end_dots(&self) -> u32 {
+        OAM_SCAN_TIME + self.pixel_draw_time
+    }
+
+    // Pixel draw length for the line just scanned.
+    // Fine scrolling throws away SCX % 8 pixels at the start of the line, and each object pauses the background fetcher.
+    // Objects cost 6 dots to fetch, plus up to 5 more waiting on the background tile they start in. Only the first object in a tile waits.
+    // See https://gbdev.io/pandocs/Rendering.html#mode-3-length
+    fn pixel_draw_duration(&self, mem: &mut MemoryMap) -> u32 {
+        let lcdc: Byte = mem.read(LCDC_ADDRESS);
+        let scx: Byte = mem.read(SCX_ADDRESS);
+        let mut duration = PIXEL_DRAW_TIME + (scx % 8) as u32;
+        // Objects that aren't being drawn don't get fetched
+        if (lcdc & (1 << 1)) == 0 {
+            return duration
+        }
+        let mut tiles_waited_on: Vec<u8> = Vec::with_capacity(self.oam_scan_results.len());
+        // The fetcher meets objects left to right whatever order they're drawn in
+        let mut objects_left_to_right = self.oam_scan_results.clone();
+        objects_left_to_right.sort_by_key(|object| object.x_pos);
+        for object in objects_left_to_right.iter() {
+            if object.x_pos == 0 {
+                // Hidden off the left edge, but still fetched
+                duration += OBJECT_PENALTY_MAX;
+            }
+            else if (object.x_pos as usize) < SCREEN_WIDTH + TILE_WIDTH as usize {
+                let background_x = object.x_pos.wrapping_sub(TILE_WIDTH).wrapping_add(scx);
+                let tile = background_x / TILE_WIDTH;
+                if !tiles_waited_on.contains(&tile) {
+                    tiles_waited_on.push(tile);
+                    let pixels_right_of_object = (TILE_WIDTH - 1) - (background_x % TILE_WIDTH);
+                    duration += pixels_right_of_object.saturating_sub(2) as u32;
                 }
+                duration += OBJECT_FETCH_PENALTY;
             }
         }
-        // Object priority in the original gameboy requires a stable sorting of objects by x position
-        line_objects_buffer.sort_by(|a, b| a.x_pos.partial_cmp(&b.x_pos).unwrap());
-        // Order is reversed because we want to draw lower priority pixels first and potentially overwrite them with higher priority ones
-        line_objects_buffer.reverse();
-        line_objects_buffer
+        duration
     }
 
     // ((top left), (bottom right)) xy coordinate pairs
@@ -457,15 +583,111 @@ impl<'a> Ppu<'a> {
         ))
     }
 
-    fn draw_line(&mut self, line_number: u32) {
-        let mut mem = self.system_memory.borrow_mut();
+    // Finds the data for a BG/window tile number, using whichever addressing mode the base address implies
+    fn tile_data_address(tile_data_base_address: Address, tile_number: Byte) -> Address {
+        if tile_data_base_address == 0x8000 {
+            // Get address of actual data
+            tile_data_base_address + (tile_number as Address * mem::size_of::<Tile>() as Address)
+        }
+        else {
+            // Signed tile numbers only reach 0x8800-0x97F0 from the 0x9000 base, but wrap anyway so no base address can panic
+            let tile_data_offset = tile_number.interpret_as_signed() as i16 * mem::size_of::<Tile>() as i16;
+            tile_data_base_address.wrapping_add_signed(tile_data_offset)
+        }
+    }
+
+    // Debug views of VRAM and OAM, for seeing what a game has loaded independently of what's on screen
+
+    // All 384 tiles in VRAM laid out in rows of 16, colored with the background palette
+    pub fn tile_data_view(&self, mem: &mut MemoryMap, shades: &Palette) -> Vec<Rgba> {
+        const TILE_COUNT: usize = 384;
+        let palette = PaletteSource::Dmg { register: mem.read(BGP_ADDRESS), shades };
+        let mut view = vec![shades[0]; TILE_DATA_VIEW_WIDTH * TILE_DATA_VIEW_HEIGHT];
+        for tile_number in 0..TILE_COUNT {
+            let tile = Tile::from_address(mem, 0x8000 + (tile_number * mem::size_of::<Tile>()) as Address);
+            let origin_x = (tile_number % TILE_DATA_VIEW_TILES_PER_ROW) * TILE_WIDTH as usize;
+            let origin_y = (tile_number / TILE_DATA_VIEW_TILES_PER_ROW) * TILE_WIDTH as usize;
+            for y in 0..TILE_WIDTH {
+                for x in 0..TILE_WIDTH {
+                    let pixel_index = (origin_y + y as usize) * TILE_DATA_VIEW_WIDTH + origin_x + x as usize;
+                    view[pixel_index] = resolve_color(tile.color_index(x, y).unwrap(), palette);
+                }
+            }
+        }
+        view
+    }
+
+    // The whole 256x256 background map as currently selected by LCDC, ignoring scrolling
+    pub fn background_map_view(&self, mem: &mut MemoryMap, shades: &Palette) -> Vec<Rgba> {
+        let palette = PaletteSource::Dmg { register: mem.read(BGP_ADDRESS), shades };
+        let lcdc: Byte = mem.read(LCDC_ADDRESS);
+        let tile_data_base_address: Address = if (lcdc & (1 << 4)) > 0 { 0x8000 } else { 0x9000 };
+        let background_map_base_address: Address = if (lcdc & (1 << 3)) > 0 { 0x9C00 } else { 0x9800 };
+        let map_size = TILEMAP_WH as usize;
+        let mut view = vec![shades[0]; map_size * map_size];
+        for tile_index in 0..(32 * 32) {
+            let tile_number = mem.read::<Byte>(background_map_base_address + tile_index as Address);
+            let tile = Tile::from_address(mem, Self::tile_data_address(tile_data_base_address, tile_number));
+            let origin_x = (tile_index % 32) * TILE_WIDTH as usize;
+            let origin_y = (tile_index / 32) * TILE_WIDTH as usize;
+            for y in 0..TILE_WIDTH {
+                for x in 0..TILE_WIDTH {
+                    let pixel_index = (origin_y + y as usize) * map_size + origin_x + x as usize;
+                    view[pixel_index] = resolve_color(tile.color_index(x, y).unwrap(), palette);
+                }
+            }
+        }
+        view
+    }
+
+    // All 40 OAM entries in slot order
+    pub fn oam_view(&self, memory: &mut MemoryMap) -> Vec<OamEntry> {
+        memory.oam_entries().collect::<Vec<OamEntry>>()
+    }
+
+    // Where each of the 40 objects would land on screen, sized by the current LCDC object height
+    pub fn object_bounds(&self, mem: &mut MemoryMap) -> Vec<ScreenRect> {
+        let lcdc: Byte = mem.read(LCDC_ADDRESS);
+        let height = if (lcdc & (1 << 2)) > 0 { 2 * TILE_WIDTH } else { TILE_WIDTH };
+        mem.oam_entries()
+            .map(|object| ScreenRect {
+                x: object.x_pos as i16 - 8,
+                y: object.y_pos as i16 - 16,
+                width: TILE_WIDTH as i16,
+                height: height as i16
+            })
+            .collect::<Vec<ScreenRect>>()
+    }
+
+    // The part of the screen the window covers, or None if it's switched off or placed off screen
+    pub fn window_bounds(&self, mem: &mut MemoryMap) -> Option<ScreenRect> {
+        let lcdc: Byte = mem.read(LCDC_ADDRESS);
+        let wy: Byte = mem.read(WY_ADDRESS);
+        let wx: Byte = mem.read(WX_ADDRESS);
+        let x = wx as i16 - 7;
+        let y = wy as i16;
+        if (lcdc & (1 << 5)) == 0 || x >= SCREEN_WIDTH as i16 || y >= SCREEN_HEIGHT as i16 {
+            return None
+        }
+        Some(ScreenRect {
+            x,
+            y,
+            width: SCREEN_WIDTH as i16 - x,
+            height: SCREEN_HEIGHT as i16 - y
+        })
+    }
+
+    fn draw_line(&mut self, mem: &mut MemoryMap, line_number: u32) {
         let bg_palette: Byte = mem.read(BGP_ADDRESS);
         let obj_palette_0: Byte = mem.read(OBP0_ADDRESS);
         let obj_palette_1: Byte = mem.read(OPB1_ADDRESS);
         let lcdc: Byte = mem.read(LCDC_ADDRESS);
         let viewport = Self::viewport_of(mem.read(SCX_ADDRESS), mem.read(SCY_ADDRESS));
+        // LCDC bit 0 means different things depending on the hardware.
+        // On DMG it switches the background and window off entirely. On CGB they're always drawn, and it decides whether they can cover objects
+        let bg_window_master = (lcdc & (1 << 0)) > 0;
         // Background/Window enabled, so draw them
-        if (lcdc & (1 << 0)) > 0 {
+        if bg_window_master || mem.mode == GameboyMode::Cgb {
             let tile_data_base_address: Address = if (lcdc & (1 << 4)) > 0 {
                 0x8000
             }
@@ -509,26 +731,29 @@ impl<'a> Ppu<'a> {
 
                 let tile_map_address: Address = map_base_address + tile_index;
 
-                let tile_data_address = if tile_data_base_address == 0x8000 {
-                    // Get address of actual data
-                    let tile_data_offset = mem.read::<Byte>(tile_map_address) as Address;
-                    tile_data_base_address + (tile_data_offset * mem::size_of::<Tile>() as Address)
-                }
-                else {
-                    let tile_data_offset = mem.read::<Byte>(tile_map_address).interpret_as_signed() as i32;
-                    // Impossible to overflow/underflow Address with the TDO value range, so we can just unwrap here
-                    ((tile_data_base_address as i32) + (tile_data_offset * mem::size_of::<Tile>() as i32)).try_into().unwrap()
-                };
-                let tile = Tile::from_address(&mut mem, tile_data_address);
-                let color = tile.color_index(tile_pos_x, tile_pos_y);
+                let tile_data_address = Self::tile_data_address(tile_data_base_address, mem.read::<Byte>(tile_map_address));
+                let tile = Tile::from_address(mem, tile_data_address);
+                let color = tile.color_index(tile_pos_x, tile_pos_y).unwrap();
+                // CGB keeps each map entry's attributes at the same address in VRAM bank 1, bit 7 is the priority bit.
+                // The palette, bank, and flip bits aren't used yet
+                let attributes = if mem.mode == GameboyMode::Cgb { mem.vram_block(1, tile_map_address, 1)[0] } else { 0 };
+                self.bg_line[pixel as usize] = BgPixel { color_index: color, priority: (attributes & (1 << 7)) > 0 };
                 // Always draw to the back buffer
                 let pixel_index = self.back_buffer_base + SCREEN_WIDTH*(line_number as usize) + (pixel as usize);
-                self.display_buffer[pixel_index] = color.unwrap().apply_palette(bg_palette);
+                self.display_buffer[pixel_index] = color.apply_palette(bg_palette);
+                self.index_buffer[pixel_index] = color;
             }
             if drew_inside_window {
                 self.internal_window_line_counter += 1;
             }
         }
+        // Otherwise the line is left blank (white) for objects to draw over
+        else {
+            let line_start = self.back_buffer_base + SCREEN_WIDTH*(line_number as usize);
+            self.display_buffer[line_start..(line_start + SCREEN_WIDTH)].fill(Color::A);
+            self.index_buffer[line_start..(line_start + SCREEN_WIDTH)].fill(ColorIndex::Blank);
+            self.bg_line.fill(BgPixel::BLANK);
+        }
 
         // Objects enabled, so draw them 
         if (lcdc & (1 << 1)) > 0 {
@@ -537,8 +762,10 @@ impl<'a> Ppu<'a> {
                 // First check LCDC to see how tall objects are configured to be
                 let obj_height = if (lcdc & (1 << 2)) > 0 { 2 * TILE_WIDTH } else { TILE_WIDTH };
                 // Then look if the current object space pixel coordinate is inside the given object
-                if (pix_obj_x >= obj.x_pos) && (pix_obj_x < (obj.x_pos + TILE_WIDTH)) && (pix_obj_line >= obj.y_pos) && (pix_obj_line < (obj.y_pos + obj_height)) {
-                    // println!("HIT: x: {}, y: {}", pix_obj_x, pix_obj_line);
+                // Bounds are widened to u16 since objects parked near the right or bottom edge of object space would overflow a u8
+                let (obj_x, obj_y) = (obj.x_pos as u16, obj.y_pos as u16);
+                let (pix_x, pix_line) = (pix_obj_x as u16, pix_obj_line as u16);
+                if (pix_x >= obj_x) && (pix_x < (obj_x + TILE_WIDTH as u16)) && (pix_line >= obj_y) && (pix_line < (obj_y + obj_height as u16)) {
                     ObjectIntersection::Coordinate(pix_obj_x - obj.x_pos, pix_obj_line - obj.y_pos, obj_height)
                 }
                 else {
@@ -546,6 +773,8 @@ impl<'a> Ppu<'a> {
                 }
             };
             for pixel in 0..(SCREEN_WIDTH as u16) {
+                // Objects at x_pos 0 or 168 and beyond have no columns on screen, so they never intersect a pixel.
+                // Objects partially off the left edge (x_pos 1-7) only intersect their visible columns
                 for object in &self.oam_scan_results {
                     // get the palette that this object is using
                     let obj_palette = if (object.flags & (1 << 4)) == 0 { obj_palette_0 } else { obj_palette_1 };
@@ -560,6 +789,7 @@ impl<'a> Ppu<'a> {
                         let flip_adjusted_x = if (object.flags & (1 << 5)) > 0 { (TILE_WIDTH - 1) - interior_x } else { interior_x };
                         let flip_adjusted_y = if (object.flags & (1 << 6)) > 0 { (obj_height - 1) - interior_y } else { interior_y };
                         // See OAM byte 2 tile index documentation for details. Behavior is funky for 8x16 objects
+                        // The top/bottom tile is picked from the flip adjusted row, so a Y-flipped 8x16 object swaps its tiles as well as their rows
                         let tile_index = if (lcdc & (1 << 2)) > 0 {
                             if ( flip_adjusted_y / TILE_WIDTH ) == 0 { object.tile_index & 0xFE } else { object.tile_index | 0x1 }
                         }
@@ -568,15 +798,22 @@ impl<'a> Ppu<'a> {
                         };
                         // Look where object data is stored. Add the tile index for this object. If we are in the lower part of the object, look at the next tile instead
                         let tile_data_address: Address = obj_data_base_address + (tile_index as Address * mem::size_of::<Tile>() as Address);
-                        let tile = Tile::from_address(&mut mem, tile_data_address);
+                        let tile = Tile::from_address(mem, tile_data_address);
                         let color_index = tile.color_index(flip_adjusted_x, flip_adjusted_y % 8).unwrap();
                         // Blank is transparent, and should allow the background or lower priority objects to shine through
                         // No reason to draw blanks
                         if color_index != ColorIndex::Blank {
                             let pixel_index = self.back_buffer_base + SCREEN_WIDTH*(line_number as usize) + (pixel as usize);
-                            // But otherwise, we draw it if objects have priority or the background is just a blank pixel
-                            if ((object.flags & (1 << 7)) == 0) || (self.display_buffer[pixel_index].is_blank_color(bg_palette)) {
+                            // But otherwise, we draw it unless the object or the background tile asks for the background to go on top.
+                            // Even then, background color index 0 never covers anything, whatever shade BGP gives it.
+                            // With LCDC bit 0 clear the background never wins, it's either switched off (DMG) or stripped of its priority (CGB)
+                            let bg_pixel = self.bg_line[pixel as usize];
+                            let bg_on_top = bg_window_master
+                                && ((object.flags & (1 << 7)) > 0 || bg_pixel.priority)
+                                && bg_pixel.color_index != ColorIndex::Blank;
+                            if !bg_on_top {
                                 self.display_buffer[pixel_index] = color_index.apply_palette(obj_palette);
+                                self.index_buffer[pixel_index] = color_index;
                             }
                         }
                     }
@@ -585,3 +822,497 @@ impl<'a> Ppu<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_gb::tests::test_memory;
+    use crate::memory_gb::VBK_ADDRESS;
+    use crate::palette::GREEN_PALETTE;
+
+    #[test]
+    fn debug_views_resolve_through_the_background_palette() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // Inverted BGP, so index 3 shows as the lightest shade
+        memory.write(0x1Bu8, BGP_ADDRESS);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        // Tile 17 is solid index 3, and sits second from the left on the map's second row
+        memory.load_region(0x8000 + 17 * 16, &[0xFF; 16]);
+        memory.load_region(0x9800 + 33, &[17]);
+        let ppu = Ppu::new();
+        let tiles = ppu.tile_data_view(&mut memory, &GREEN_PALETTE);
+        assert_eq!(tiles.len(), TILE_DATA_VIEW_WIDTH * TILE_DATA_VIEW_HEIGHT);
+        assert_eq!(tiles[8 * TILE_DATA_VIEW_WIDTH + 8], GREEN_PALETTE[0]);
+        assert_eq!(tiles[8 * TILE_DATA_VIEW_WIDTH + 7], GREEN_PALETTE[3]);
+        let map = ppu.background_map_view(&mut memory, &GREEN_PALETTE);
+        assert_eq!(map[8 * 256 + 8], GREEN_PALETTE[0]);
+        assert_eq!(map[0], GREEN_PALETTE[3]);
+    }
+
+    #[test]
+    fn load_region_tile_reads_back_through_tile_from_address() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        // Low plane 0x55 and high plane 0x33 run through indices 0 to 3 twice across each row
+        memory.load_region(0x8000, &[0x55, 0x33].repeat(8));
+        let tile = Tile::from_address(&mut memory, 0x8000);
+        for y in 0..8 {
+            let row = (0..8).map(|x| tile.color_index(x, y).map(|index| index.to_value())).collect::<Vec<Option<u8>>>();
+            assert_eq!(row, [0, 1, 2, 3, 0, 1, 2, 3].map(Some));
+        }
+    }
+
+    #[test]
+    fn tile_numbers_address_unsigned_from_0x8000_and_signed_from_0x9000() {
+        assert_eq!(Ppu::tile_data_address(0x8000, 0x00), 0x8000);
+        assert_eq!(Ppu::tile_data_address(0x8000, 0x7F), 0x87F0);
+        assert_eq!(Ppu::tile_data_address(0x8000, 0x80), 0x8800);
+        assert_eq!(Ppu::tile_data_address(0x8000, 0xFF), 0x8FF0);
+        assert_eq!(Ppu::tile_data_address(0x9000, 0x00), 0x9000);
+        assert_eq!(Ppu::tile_data_address(0x9000, 0x7F), 0x97F0);
+        assert_eq!(Ppu::tile_data_address(0x9000, 0x80), 0x8800);
+        assert_eq!(Ppu::tile_data_address(0x9000, 0xFF), 0x8FF0);
+    }
+
+    #[test]
+    fn incremental_oam_scan_matches_a_one_shot_scan() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        // Twelve objects on line 0 with X counting down, and one in slot 5 further down the screen.
+        // Only the first ten on the line make it in
+        for slot in 0..13 {
+            let y_pos = if slot == 5 { 100 } else { 16 };
+            memory.load_region(0xFE00 + 4 * slot, &[y_pos, 50 - slot as Byte, slot as Byte, 0]);
+        }
+        let mut ppu = Ppu::new();
+        // Out of VBlank and into the scan for line 0, then through the rest of it 2 dots at a time
+        ppu.run(&mut memory);
+        while ppu.current_mode == RenderMode::OAMScan {
+            ppu.run(&mut memory);
+        }
+        let incremental = ppu.oam_scan_results.clone();
+        let tiles = incremental.iter().map(|object| object.tile_index).collect::<Vec<Byte>>();
+        assert_eq!(tiles, [0, 1, 2, 3, 4, 6, 7, 8, 9, 10]);
+
+        ppu.current_dot = 0;
+        ppu.oam_scan_results.clear();
+        for slot in 0..40 {
+            ppu.scan_oam_slot(&mut memory, slot);
+        }
+        ppu.order_oam_scan_results(&mut memory);
+        assert_eq!(ppu.oam_scan_results, incremental);
+    }
+
+    // Draws a line with a single object from OAM slot 0 over it, returning the line's colors. Tile 1 is solid index 3.
+    // Tiles 2 and 3 only have their top and bottom rows set, in indices 1 and 2, so the shade says which tile and row got drawn
+    fn draw_object_line(lcdc: Byte, line_number: u32, object: [Byte; 4]) -> Vec<Color> {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(lcdc, LCDC_ADDRESS);
+        memory.write(0xE4u8, OBP0_ADDRESS);
+        memory.load_region(0x8010, &[0xFF; 16]);
+        memory.load_region(0x8020, &[0xFF, 0x00]);
+        memory.load_region(0x8030 + 14, &[0x00, 0xFF]);
+        memory.load_region(0xFE00, &object);
+        let mut ppu = Ppu::new();
+        ppu.current_dot = line_number * DOTS_PER_LINE;
+        for slot in 0..40 {
+            ppu.scan_oam_slot(&mut memory, slot);
+        }
+        ppu.order_oam_scan_results(&mut memory);
+        ppu.draw_line(&mut memory, line_number);
+        let line_start = ppu.back_buffer_base + SCREEN_WIDTH * line_number as usize;
+        ppu.display_buffer[line_start..(line_start + SCREEN_WIDTH)].to_vec()
+    }
+
+    #[test]
+    fn objects_clip_at_the_screen_edges() {
+        // Objects on, background off, so anything not drawn by the object stays white
+        let hidden_left = draw_object_line(0x82, 0, [16, 0, 1, 0]);
+        assert!(hidden_left.iter().all(|color| *color == Color::A));
+        let straddling_left = draw_object_line(0x82, 0, [16, 4, 1, 0]);
+        assert!(straddling_left[0..4].iter().all(|color| *color == Color::D));
+        assert!(straddling_left[4..].iter().all(|color| *color == Color::A));
+        let straddling_right = draw_object_line(0x82, 0, [16, 164, 1, 0]);
+        assert!(straddling_right[0..156].iter().all(|color| *color == Color::A));
+        assert!(straddling_right[156..].iter().all(|color| *color == Color::D));
+        let hidden_right = draw_object_line(0x82, 0, [16, 168, 1, 0]);
+        assert!(hidden_right.iter().all(|color| *color == Color::A));
+    }
+
+    #[test]
+    fn tall_objects_flip_vertically_as_a_unit() {
+        // 8x16 objects mask the tile index, so tile 3 draws tiles 2 and 3 from top to bottom
+        assert_eq!(draw_object_line(0x86, 0, [16, 8, 3, 0x00])[0], Color::B);
+        assert_eq!(draw_object_line(0x86, 15, [16, 8, 3, 0x00])[0], Color::C);
+        // Flipped, the bottom tile's last row ends up on top and the top tile's first row at the bottom
+        assert_eq!(draw_object_line(0x86, 0, [16, 8, 3, 0x40])[0], Color::C);
+        assert_eq!(draw_object_line(0x86, 15, [16, 8, 3, 0x40])[0], Color::B);
+        assert_eq!(draw_object_line(0x86, 7, [16, 8, 3, 0x40])[0], Color::A);
+    }
+
+    // Scans and draws line 0, returning the tile numbers of the objects found in draw order along with the line's colors.
+    // The background isn't scrolled, tile 1 is solid index 1, tile 2 is solid index 3, and OBP0 passes indices straight through.
+    // Everything else is up to setup
+    fn draw_first_line(setup: impl FnOnce(&mut MemoryMap)) -> (Vec<Byte>, Vec<Color>) {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x00u8, SCX_ADDRESS);
+        memory.write(0x00u8, SCY_ADDRESS);
+        memory.write(0xE4u8, OBP0_ADDRESS);
+        memory.load_region(0x8010, &[0xFF, 0x00].repeat(8));
+        memory.load_region(0x8020, &[0xFF; 16]);
+        setup(&mut memory);
+        let mut ppu = Ppu::new();
+        ppu.current_dot = 0;
+        for slot in 0..40 {
+            ppu.scan_oam_slot(&mut memory, slot);
+        }
+        ppu.order_oam_scan_results(&mut memory);
+        ppu.draw_line(&mut memory, 0);
+        let order = ppu.oam_scan_results.iter().map(|object| object.tile_index).collect::<Vec<Byte>>();
+        (order, ppu.display_buffer[ppu.back_buffer_base..(ppu.back_buffer_base + SCREEN_WIDTH)].to_vec())
+    }
+
+    #[test]
+    fn background_priority_compares_color_indices_not_shades() {
+        // BGP shows index 0 as the second shade and index 1 as the lightest. The object asks to go behind the background
+        let behind_background = |bg_tile: Byte| draw_first_line(|memory| {
+            memory.write(0x93u8, LCDC_ADDRESS);
+            memory.write(0xE1u8, BGP_ADDRESS);
+            memory.load_region(0x9800, &[bg_tile; 32]);
+            memory.load_region(0xFE00, &[16, 8, 2, 0x80]);
+        }).1;
+        // Index 1 covers the object even though it's the lightest shade
+        assert_eq!(behind_background(1)[0], Color::A);
+        // Index 0 never covers anything, even in a darker shade
+        let line = behind_background(0);
+        assert_eq!(line[0], Color::D);
+        assert_eq!(line[8], Color::B);
+    }
+
+    #[test]
+    fn cgb_background_priority_attribute_covers_objects_that_want_to_be_on_top() {
+        // The object spans the first two map entries, and only the first has the priority bit set in its attributes
+        let line_in = |mode: GameboyMode| draw_first_line(|memory| {
+            memory.mode = GameboyMode::Cgb;
+            memory.write(0x01u8, VBK_ADDRESS);
+            memory.load_region(0x9800, &[0x80, 0x00]);
+            memory.write(0x00u8, VBK_ADDRESS);
+            memory.mode = mode;
+            memory.write(0x93u8, LCDC_ADDRESS);
+            memory.write(0xE4u8, BGP_ADDRESS);
+            memory.load_region(0x9800, &[1; 32]);
+            memory.load_region(0xFE00, &[16, 12, 2, 0x00]);
+        }).1;
+        let cgb = line_in(GameboyMode::Cgb);
+        assert_eq!(cgb[4..8], [Color::B; 4]);
+        assert_eq!(cgb[8..12], [Color::D; 4]);
+        // DMG has no attributes, the object is on top all the way across
+        assert_eq!(line_in(GameboyMode::Dmg)[4..12], [Color::D; 8]);
+    }
+
+    // Runs line 0 with the given SCX and OAM contents from slot 0, returning the dot HBlank starts on and the dot the line ends on
+    fn hblank_start_and_line_end(scx: Byte, objects: &[Byte]) -> (u16, u32) {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x83u8, LCDC_ADDRESS);
+        memory.write(0x00u8, SCY_ADDRESS);
+        memory.write(scx, SCX_ADDRESS);
+        let mut oam = objects.to_vec();
+        oam.resize(160, 0);
+        memory.load_region(0xFE00, &oam);
+        let mut ppu = Ppu::new();
+        while !(ppu.current_mode == RenderMode::HBlank && ppu.current_scanline() == 0) {
+            ppu.run(&mut memory);
+        }
+        let hblank_start = ppu.current_dot_in_line();
+        while ppu.current_mode == RenderMode::HBlank {
+            ppu.run(&mut memory);
+        }
+        (hblank_start, ppu.current_dot)
+    }
+
+    #[test]
+    fn pixel_draw_grows_with_fine_scroll_and_objects() {
+        // 80 dots of OAM scan, then at least 172 of pixel draw
+        assert_eq!(hblank_start_and_line_end(0, &[]).0, 252);
+        assert_eq!(hblank_start_and_line_end(3, &[]).0, 255);
+        // An object lined up with a background tile costs 6 dots to fetch plus 5 waiting on the fetcher
+        assert_eq!(hblank_start_and_line_end(0, &[16, 8, 0, 0]).0, 263);
+        // A second object in the same tile only costs the fetch
+        assert_eq!(hblank_start_and_line_end(0, &[16, 8, 0, 0, 16, 9, 0, 0]).0, 269);
+        // Hidden off the left edge it still costs the full 11
+        assert_eq!(hblank_start_and_line_end(0, &[16, 0, 0, 0]).0, 263);
+        // Further into a tile there's less to wait for
+        assert_eq!(hblank_start_and_line_end(0, &[16, 14, 0, 0]).0, 258);
+        // The worst case
+        let ten_objects = (0..10).flat_map(|_| [16, 0, 0, 0]).collect::<Vec<Byte>>();
+        assert_eq!(hblank_start_and_line_end(7, &ten_objects).0, 80 + 289);
+        // HBlank gives back what pixel draw took, so the line still ends on time
+        assert_eq!(hblank_start_and_line_end(0, &[16, 8, 0, 0]).1, DOTS_PER_LINE);
+    }
+
+    // Objects go in from OAM slot 0 over a blank screen, with OPRI asking for the CGB priority rule
+    fn draw_overlapping_objects(mode: GameboyMode, objects: &[Byte]) -> (Vec<Byte>, Vec<Color>) {
+        draw_first_line(|memory| {
+            memory.mode = mode;
+            memory.write(0x00u8, OPRI_ADDRESS);
+            memory.write(0x82u8, LCDC_ADDRESS);
+            memory.load_region(0xFE00, objects);
+        })
+    }
+
+    #[test]
+    fn same_x_objects_go_to_the_lower_oam_index_in_both_modes() {
+        for mode in [GameboyMode::Dmg, GameboyMode::Cgb] {
+            // Draw order is lowest priority first, so the winner comes last
+            let (order, line) = draw_overlapping_objects(mode, &[16, 20, 1, 0, 16, 20, 2, 0]);
+            assert_eq!(order, [2, 1], "{:?}", mode);
+            assert_eq!(line[12], Color::B, "{:?}", mode);
+            let (order, line) = draw_overlapping_objects(mode, &[16, 20, 2, 0, 16, 20, 1, 0]);
+            assert_eq!(order, [1, 2], "{:?}", mode);
+            assert_eq!(line[12], Color::D, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn lower_x_wins_on_dmg_but_not_on_cgb() {
+        // OAM slot 0 sits further right, the two overlap from screen x 22 to 25
+        let objects = [16, 30, 1, 0, 16, 26, 2, 0];
+        let (order, line) = draw_overlapping_objects(GameboyMode::Dmg, &objects);
+        assert_eq!(order, [1, 2]);
+        assert_eq!(line[23], Color::D);
+        let (order, line) = draw_overlapping_objects(GameboyMode::Cgb, &objects);
+        assert_eq!(order, [2, 1]);
+        assert_eq!(line[23], Color::B);
+    }
+
+    #[test]
+    fn opri_switches_cgb_to_the_dmg_priority_rule() {
+        let winner = |mode: GameboyMode, opri: Byte| draw_first_line(|memory| {
+            memory.mode = mode;
+            memory.write(opri, OPRI_ADDRESS);
+            memory.write(0x82u8, LCDC_ADDRESS);
+            // Slot 0 is further right and draws tile 1, slot 1 draws tile 2. They overlap at screen x 23
+            memory.load_region(0xFE00, &[16, 30, 1, 0, 16, 26, 2, 0]);
+        }).1[23];
+        assert_eq!(winner(GameboyMode::Cgb, 0x00), Color::B);
+        assert_eq!(winner(GameboyMode::Cgb, 0x01), Color::D);
+        // Only bit 0 counts
+        assert_eq!(winner(GameboyMode::Cgb, 0xFE), Color::B);
+        // DMG always goes by X
+        assert_eq!(winner(GameboyMode::Dmg, 0x00), Color::D);
+    }
+
+    // Runs a frame with the given STAT interrupt sources and LYC, counting STAT interrupt requests on each line
+    fn stat_interrupts_per_line(stat_sources: Byte, lyc: Byte) -> Vec<u32> {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        memory.write(stat_sources, STAT_ADDRESS);
+        memory.write(lyc, LYC_ADDRESS);
+        let mut ppu = Ppu::new();
+        // Out of the tail end of VBlank the PPU starts in, to the top of line 0
+        ppu.run(&mut memory);
+        memory.write(0x00u8, IF_REG_ADDR);
+        let mut counts = vec![0; 154];
+        let mut dots = 0;
+        while dots < DOT_MAX {
+            let line = ppu.current_scanline() as usize;
+            dots += ppu.run(&mut memory) as u32;
+            let interrupt_flag: Byte = memory.read(IF_REG_ADDR);
+            if (interrupt_flag & 0x2) > 0 {
+                // A request on the dot a line starts belongs to the new line
+                counts[line.max(ppu.current_scanline() as usize)] += 1;
+                memory.write(0x00u8, IF_REG_ADDR);
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn stat_sources_true_together_request_one_interrupt() {
+        // HBlank and LY=LYC. Line 5 is still high from LY=LYC when its HBlank comes, so it only fires once like every other line
+        let counts = stat_interrupts_per_line(0x48, 5);
+        assert_eq!(counts[4..7], [1, 1, 1]);
+        // OAM scan and LY=LYC come true on the same dot
+        let counts = stat_interrupts_per_line(0x60, 7);
+        assert_eq!(counts[7..9], [1, 1]);
+        assert_eq!(counts[150], 0);
+        // VBlank holds the line high for all ten lines of it
+        let counts = stat_interrupts_per_line(0x10, 200);
+        assert_eq!(counts.iter().sum::<u32>(), 1);
+        assert_eq!(counts[144], 1);
+    }
+
+    // Runs the PPU through a whole frame with the LCD on, the background unscrolled and BGP passing indices straight through,
+    // after setup has had its way with memory. Then hands the PPU over to look at the finished frame
+    fn render_frame<T>(setup: impl FnOnce(&mut MemoryMap), inspect: impl FnOnce(&Ppu) -> T) -> T {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        memory.write(0x00u8, SCX_ADDRESS);
+        memory.write(0x00u8, SCY_ADDRESS);
+        memory.write(0xE4u8, BGP_ADDRESS);
+        setup(&mut memory);
+        let mut ppu = Ppu::new();
+        while !ppu.frame_is_ready() {
+            ppu.run(&mut memory);
+        }
+        inspect(&ppu)
+    }
+
+    #[test]
+    fn frame_hash_is_stable_and_changes_with_the_picture() {
+        let blank = render_frame(|_| (), |ppu| ppu.frame_hash());
+        // FNV-1a over 160x144 zeros, the same on every build
+        assert_eq!(blank, 0xECA47F6549902B25);
+        assert_eq!(render_frame(|_| (), |ppu| ppu.frame_hash()), blank);
+        // Filling in tile 0 changes every tile of the background
+        let filled = render_frame(|memory| memory.load_region(0x8000, &[0xFF; 16]), |ppu| ppu.frame_hash());
+        assert_ne!(filled, blank);
+        // Changing a single pixel is enough
+        let one_pixel = render_frame(|memory| memory.load_region(0x8000, &[0x80]), |ppu| ppu.frame_hash());
+        assert_ne!(one_pixel, blank);
+        assert_ne!(one_pixel, filled);
+    }
+
+    #[test]
+    fn index_handle_matches_display_handle_through_bgp() {
+        // Inverted BGP, and tile 0 running through indices 0 to 3 twice across each row
+        let (colors, indices) = render_frame(|memory| {
+            memory.write(0x1Bu8, BGP_ADDRESS);
+            memory.load_region(0x8000, &[0x55, 0x33].repeat(8));
+        }, |ppu| (ppu.display_handle(), ppu.index_handle()));
+        assert_eq!(colors.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(indices.len(), colors.len());
+        for (color, index) in colors.iter().zip(indices.iter()) {
+            assert_eq!(*color, index.apply_palette(0x1B));
+        }
+        assert_eq!(indices[0..4], [ColorIndex::Blank, ColorIndex::One, ColorIndex::Two, ColorIndex::Three]);
+        assert_eq!(colors[0..4], [Color::D, Color::C, Color::B, Color::A]);
+    }
+
+    #[test]
+    fn stat_keeps_its_read_only_bits_and_writing_ly_restarts_the_frame() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        let mut ppu = Ppu::new();
+        while ppu.current_scanline() < 50 {
+            ppu.run(&mut memory);
+        }
+        // Bit 7 always reads 1, and the LY=LYC and mode bits belong to the PPU
+        let stat: Byte = memory.read(STAT_ADDRESS);
+        memory.write(0x00u8, STAT_ADDRESS);
+        assert_eq!(memory.read::<Byte>(STAT_ADDRESS), 0x80 | (stat & 0x07));
+        memory.write(0xFFu8, STAT_ADDRESS);
+        assert_eq!(memory.read::<Byte>(STAT_ADDRESS), 0xF8 | (stat & 0x07));
+        // Any write to LY sends it back to 0, and the PPU picks up from the OAM scan at the top of the frame
+        memory.write(0x42u8, LY_ADDRESS);
+        assert_eq!(memory.read::<Byte>(LY_ADDRESS), 0);
+        ppu.run(&mut memory);
+        assert_eq!(memory.read::<Byte>(LY_ADDRESS), 0);
+        assert_eq!(memory.read::<Byte>(STAT_ADDRESS) & 0x03, 2);
+        // From there it runs a normal frame, never going past line 153
+        let mut highest_ly = 0;
+        while !ppu.frame_is_ready() {
+            ppu.run(&mut memory);
+            highest_ly = highest_ly.max(memory.read::<Byte>(LY_ADDRESS));
+        }
+        assert_eq!(highest_ly, 153);
+    }
+
+    #[test]
+    fn tiles_read_as_one_block_match_word_by_word_reads() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let tile_data = (0..0x1800).map(|i| (i * 31 + 7) as Byte).collect::<Vec<Byte>>();
+        memory.load_region(0x8000, &tile_data);
+        // Both ends of tile data, plus a start that isn't tile aligned
+        for address in [0x8000, 0x8010, 0x8FF0, 0x97F0, 0x8003] {
+            let tile = Tile::from_address(&mut memory, address);
+            let words: [Word; 8] = core::array::from_fn(|i| memory.read(address + 2 * i as Address));
+            assert_eq!(tile.lines, words, "tile at {:#06x}", address);
+        }
+    }
+
+    #[test]
+    fn lcdc_bit_0_blanks_the_background_on_dmg_but_not_cgb() {
+        let first_pixel = |mode: GameboyMode, lcdc: Byte| draw_first_line(|memory| {
+            memory.mode = mode;
+            memory.write(lcdc, LCDC_ADDRESS);
+            memory.write(0xE4u8, BGP_ADDRESS);
+            memory.load_region(0x9800, &[2; 32]);
+        }).1[0];
+        assert_eq!(first_pixel(GameboyMode::Dmg, 0x91), Color::D);
+        assert_eq!(first_pixel(GameboyMode::Dmg, 0x90), Color::A);
+        assert_eq!(first_pixel(GameboyMode::Cgb, 0x91), Color::D);
+        assert_eq!(first_pixel(GameboyMode::Cgb, 0x90), Color::D);
+    }
+
+    #[test]
+    fn scanline_and_dot_track_the_dots_run_through_a_frame() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        let mut ppu = Ppu::new();
+        // The first run leaves the powered off state and starts the frame
+        ppu.run(&mut memory);
+        let start = ppu.current_scanline() as u32 * DOTS_PER_LINE + ppu.current_dot_in_line() as u32;
+        let mut dots = 0;
+        let mut lines_seen = vec![];
+        while start + dots < DOT_MAX {
+            dots += ppu.run(&mut memory) as u32;
+            let position = (start + dots) % DOT_MAX;
+            assert_eq!(ppu.current_scanline() as u32, position / DOTS_PER_LINE);
+            assert_eq!(ppu.current_dot_in_line() as u32, position % DOTS_PER_LINE);
+            assert_eq!(memory.read::<Byte>(LY_ADDRESS), ppu.current_scanline());
+            if lines_seen.last() != Some(&ppu.current_scanline()) {
+                lines_seen.push(ppu.current_scanline());
+            }
+        }
+        // Every line came up once and in order, then it wrapped back to the top
+        assert_eq!(lines_seen, (0..154).chain(0..1).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn background_line_keeps_each_pixels_color_index() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        memory.write(0x91u8, LCDC_ADDRESS);
+        memory.write(0x00u8, SCX_ADDRESS);
+        memory.write(0x00u8, SCY_ADDRESS);
+        // Top row of tile 1 is indices 3,3,1,1,2,2,0,0. Tile 0 is blank
+        memory.load_region(0x8010, &[0xF0, 0xCC]);
+        memory.load_region(0x9800, &[1, 0, 1]);
+        let mut ppu = Ppu::new();
+        ppu.run(&mut memory);
+        ppu.draw_line(&mut memory, 0);
+        let indices = ppu.bg_line.iter().map(|pixel| pixel.color_index.to_value()).collect::<Vec<Byte>>();
+        assert_eq!(indices[0..24], [3, 3, 1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 3, 1, 1, 2, 2, 0, 0]);
+        assert!(ppu.bg_line.iter().all(|pixel| !pixel.priority));
+    }
+
+    #[test]
+    fn debug_bounds_follow_oam_and_the_window_registers() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let ppu = Ppu::new();
+        memory.write(0x00u8, LCDC_ADDRESS);
+        memory.load_region(0xFE00, &[20, 12, 0, 0]);
+        // 8x16 objects and the window on
+        memory.write(0xA4u8, LCDC_ADDRESS);
+        memory.write(0x20u8, WY_ADDRESS);
+        memory.write(0x07u8, WX_ADDRESS);
+        let objects = ppu.object_bounds(&mut memory);
+        assert_eq!(objects.len(), 40);
+        assert_eq!(objects[0], ScreenRect { x: 4, y: 4, width: 8, height: 16 });
+        assert_eq!(ppu.window_bounds(&mut memory), Some(ScreenRect { x: 0, y: 32, width: 160, height: 112 }));
+        memory.write(0x84u8, LCDC_ADDRESS);
+        assert_eq!(ppu.window_bounds(&mut memory), None);
+    }
+}
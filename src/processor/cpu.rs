@@ -1,5 +1,4 @@
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
 
 use crate::memory_gb;
 use crate::memory_gb::Address;
@@ -8,6 +7,11 @@ use crate::memory_gb::MemoryUnit;
 use crate::memory_gb::Word;
 use crate::memory_gb::MemoryRegion;
 use crate::memory_gb::MemoryMap;
+use crate::memory_gb::KEY1_ADDRESS;
+use crate::memory_gb::OPRI_ADDRESS;
+use crate::gameboy::GameboyMode;
+use crate::tracer::CpuState;
+use crate::tracer::Tracer;
 
 /* Semantics notes
 *   Callers of operations are responsible for tracking timing since calls are case by case anyway
@@ -62,37 +66,39 @@ pub enum ConditionCodes {
 
 const IF_REG_ADDR: Address = 0xFF0F;
 const IE_REG_ADDR: Address = 0xFFFF;
+// Only the low 5 bits of IF/IE correspond to interrupt sources, the rest read back as open bus
+const INTERRUPT_BITS: Byte = 0x1F;
 
 // type MemoryMapRef = Rc<RefCell<MemoryMap>>;
 
 // Trait for reading bytes from various Cpu sources
 pub trait ReadByte {
-    fn read_byte(&self, cpu: &mut Cpu) -> Byte;
+    fn read_byte(&self, cpu: &mut CpuContext) -> Byte;
 }
 // Trait for writing bytes to various Cpu sources
 pub trait WriteByte {
-    fn write_byte(&self, cpu: &mut Cpu, value: Byte);
+    fn write_byte(&self, cpu: &mut CpuContext, value: Byte);
 }
 
 // Trait for reading words from various Cpu sources
 pub trait ReadWord {
-    fn read_word(&self, cpu: &mut Cpu) -> Word;
+    fn read_word(&self, cpu: &mut CpuContext) -> Word;
 }
 // Trait for writing words to various Cpu sources
 pub trait WriteWord {
-    fn write_word(&self, cpu: &mut Cpu, value: Word);
+    fn write_word(&self, cpu: &mut CpuContext, value: Word);
 }
 
 pub struct ByteRegister {
     register: ByteRegisterName,
 }
 impl ReadByte for ByteRegister {
-    fn read_byte(&self, cpu: &mut Cpu) -> Byte {
+    fn read_byte(&self, cpu: &mut CpuContext) -> Byte {
         cpu.registers.read_byte(self.register)
     }
 }
 impl WriteByte for ByteRegister {
-    fn write_byte(&self, cpu: &mut Cpu, value: Byte) {
+    fn write_byte(&self, cpu: &mut CpuContext, value: Byte) {
         cpu.registers.write_byte(self.register, value);
     }
 }
@@ -106,12 +112,12 @@ pub struct WordRegister {
     register: WordRegisterName
 }
 impl ReadWord for WordRegister {
-    fn read_word(&self, cpu: &mut Cpu) -> Word {
+    fn read_word(&self, cpu: &mut CpuContext) -> Word {
         cpu.registers.read_word(self.register)
     }
 }
 impl WriteWord for WordRegister {
-    fn write_word(&self, cpu: &mut Cpu, value: Word) {
+    fn write_word(&self, cpu: &mut CpuContext, value: Word) {
         cpu.registers.write_word(self.register, value);
     }
 }
@@ -125,16 +131,16 @@ pub struct ByteRegisterIndirect {
     register: WordRegisterName,
 }
 impl ReadByte for ByteRegisterIndirect {
-    fn read_byte(&self, cpu: &mut Cpu) -> Byte {
+    fn read_byte(&self, cpu: &mut CpuContext) -> Byte {
         let address = cpu.registers.read_word(self.register);
-        let mut map = cpu.memory.borrow_mut();
+        let map = &mut *cpu.memory;
         map.read::<Byte>(address)
     }
 }
 impl WriteByte for ByteRegisterIndirect {
-    fn write_byte(&self, cpu: &mut Cpu, value: Byte) {
+    fn write_byte(&self, cpu: &mut CpuContext, value: Byte) {
         let address = cpu.registers.read_word(self.register);
-        let mut map = cpu.memory.borrow_mut();
+        let map = &mut *cpu.memory;
         map.write(value, address)
     }
 }
@@ -148,18 +154,18 @@ pub struct ByteRegisterOffsetIndirect {
     register: ByteRegisterName,
 }
 impl ReadByte for ByteRegisterOffsetIndirect {
-    fn read_byte(&self, cpu: &mut Cpu) -> Byte {
+    fn read_byte(&self, cpu: &mut CpuContext) -> Byte {
         let offset = cpu.registers.read_byte(self.register);
         let address = 0xFF00 + offset as Address;
-        let mut map = cpu.memory.borrow_mut();
+        let map = &mut *cpu.memory;
         map.read::<Byte>(address)
     }
 }
 impl WriteByte for ByteRegisterOffsetIndirect {
-    fn write_byte(&self, cpu: &mut Cpu, value: Byte) {
+    fn write_byte(&self, cpu: &mut CpuContext, value: Byte) {
         let offset = cpu.registers.read_byte(self.register) as Address;
         let address = 0xFF00 + offset;
-        let mut map = cpu.memory.borrow_mut();
+        let map = &mut *cpu.memory;
         map.write(value, address)
     }
 }
@@ -173,7 +179,7 @@ pub struct ByteImmediate {
     data: Byte,
 }
 impl ReadByte for ByteImmediate {
-    fn read_byte(&self, _: &mut Cpu) -> Byte {
+    fn read_byte(&self, _: &mut CpuContext) -> Byte {
         self.data
     }
 }
@@ -187,14 +193,14 @@ pub struct ByteImmediateIndirect {
     address: Address,
 }
 impl ReadByte for ByteImmediateIndirect {
-    fn read_byte(&self, cpu: &mut Cpu) -> Byte {
-        let mut map = cpu.memory.borrow_mut();
+    fn read_byte(&self, cpu: &mut CpuContext) -> Byte {
+        let map = &mut *cpu.memory;
         map.read::<Byte>(self.address)
     }
 }
 impl WriteByte for ByteImmediateIndirect {
-    fn write_byte(&self, cpu: &mut Cpu, value: Byte) {
-        let mut map = cpu.memory.borrow_mut();
+    fn write_byte(&self, cpu: &mut CpuContext, value: Byte) {
+        let map = &mut *cpu.memory;
         map.write(value, self.address)
     }
 }
@@ -208,16 +214,16 @@ pub struct ByteImmediateOffsetIndirect {
     offset: Byte,
 }
 impl ReadByte for ByteImmediateOffsetIndirect {
-    fn read_byte(&self, cpu: &mut Cpu) -> Byte {
+    fn read_byte(&self, cpu: &mut CpuContext) -> Byte {
         let address = 0xFF00 + self.offset as Address;
-        let mut map = cpu.memory.borrow_mut();
+        let map = &mut *cpu.memory;
         map.read::<Byte>(address)
     }
 }
 impl WriteByte for ByteImmediateOffsetIndirect {
-    fn write_byte(&self, cpu: &mut Cpu, value: Byte) {
+    fn write_byte(&self, cpu: &mut CpuContext, value: Byte) {
         let address = 0xFF00 + self.offset as Address;
-        let mut map = cpu.memory.borrow_mut();
+        let map = &mut *cpu.memory;
         map.write(value, address)
     }
 }
@@ -231,7 +237,7 @@ pub struct WordImmediate {
     data: Word,
 }
 impl ReadWord for WordImmediate {
-    fn read_word(&self, _: &mut Cpu) -> Word {
+    fn read_word(&self, _: &mut CpuContext) -> Word {
         self.data
     }
 }
@@ -245,8 +251,8 @@ pub struct WordImmediateIndirect {
     address: Address,
 }
 impl WriteWord for WordImmediateIndirect {
-    fn write_word(&self, cpu: &mut Cpu, value: Word) {
-        let mut map = cpu.memory.borrow_mut();
+    fn write_word(&self, cpu: &mut CpuContext, value: Word) {
+        let map = &mut *cpu.memory;
         map.write(value, self.address)
     }
 }
@@ -259,6 +265,8 @@ impl WordImmediateIndirect {
 #[repr(C)]
 pub struct RegisterBank {
     // Registers are in the following order in memory
+    // Pairs are stored low byte first, so RegAF read as a word has A in the high byte and F in the low byte.
+    // The same goes for B/C, D/E, and H/L
     // REGISTER F
     // Flag register
     // +-+-+-+-+-+-+-+-+
@@ -312,10 +320,19 @@ impl RegisterBank {
         self.read::<Word>(register as Address)
     }
 
+    // The lower nibble of the flag register doesn't exist and always reads as 0, so every write to F masks it off
     pub fn write_byte(&mut self, register: ByteRegisterName, value: Byte) -> () {
+        let value = match register {
+            ByteRegisterName::RegF => value & 0xF0,
+            _ => value
+        };
         self.write::<Byte>(value, register as Address)
     }
     pub fn write_word(&mut self, register: WordRegisterName, value: Word) -> () {
+        let value = match register {
+            WordRegisterName::RegAF => value & 0xFFF0,
+            _ => value
+        };
         self.write::<Word>(value, register as Address)
     }
 
@@ -366,7 +383,7 @@ impl RegisterBank {
 
     pub fn step_pc(&mut self, increment: u16) {
         let pc = self.read_word(WordRegisterName::RegPC);
-        self.write_word(WordRegisterName::RegPC, pc + increment);
+        self.write_word(WordRegisterName::RegPC, pc.wrapping_add(increment));
     }
 }
 
@@ -388,80 +405,209 @@ pub enum InterruptOutcome {
     NoService
 }
 
-pub struct Cpu<'a> {
+// Hardware revisions leave the registers in different states when their boot ROMs hand off to the cart,
+// and some games look at them to work out what they're running on. See https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BootState {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+    Agb
+}
+
+impl BootState {
+    // The revision each emulated mode stands in for
+    pub fn for_mode(mode: GameboyMode) -> BootState {
+        match mode {
+            GameboyMode::Dmg => BootState::Dmg,
+            GameboyMode::Cgb => BootState::Cgb
+        }
+    }
+
+    // The register bank as the boot ROM leaves it, in RegisterBank order. SP and PC are the same everywhere.
+    // DMG and MGB set H and C from the header checksum, this assumes it's nonzero like it is for basically every cart
+    fn registers(&self) -> [Byte; 12] {
+        // A, F, B, C, D, E, H, L
+        let [a, f, b, c, d, e, h, l] = match self {
+            BootState::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            BootState::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            BootState::Mgb => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            BootState::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            BootState::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            BootState::Agb => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D]
+        };
+        [f, a, c, b, e, d, l, h, 0xFE, 0xFF, 0x00, 0x01]
+    }
+}
+
+pub struct Cpu {
     pub registers: RegisterBank,
-    pub memory: Rc<RefCell<MemoryMap<'a>>>,
     pub ime: bool,
     enable_ime_this_frame: bool,
     enable_ime_next_frame: bool,
     pub halted: bool,
     pub stopped: bool,
-    pub cycles_per_second: u32,
+    // CGB double speed mode, the CPU gets through its cycles twice as fast relative to the PPU
+    pub double_speed: bool,
+    pub tracer: Option<Box<dyn Tracer>>,
+    // M-cycles spent since power on, idling in HALT or STOP included
+    pub cycles: u64,
+    pub instructions: u64,
+}
+
+// The cpu paired up with the memory map it runs against. The Gameboy owns the memory and lends it out for each run,
+// so everything that executes instructions lives here rather than on Cpu
+pub struct CpuContext<'c, 'a> {
+    cpu: &'c mut Cpu,
+    pub memory: &'c mut MemoryMap<'a>
+}
+
+impl Deref for CpuContext<'_, '_> {
+    type Target = Cpu;
+
+    fn deref(&self) -> &Cpu {
+        self.cpu
+    }
+}
+
+impl DerefMut for CpuContext<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Cpu {
+        self.cpu
+    }
 }
 
-impl<'a> Cpu<'a> {
-    pub fn new(system_memory: Rc<RefCell<MemoryMap>>) -> Cpu {
+impl Cpu {
+    pub fn new(memory: &mut MemoryMap) -> Cpu {
         let regs = RegisterBank {
-            // Initial values set to match test logs
-            registers: [
-                0x08, // F
-                0x01, // A
-                0x13, // C
-                0x00, // B
-                0xD8, // E
-                0x00, // D
-                0x4D, // L
-                0x01, // H
-                0xFE, // SP LOW
-                0xFF, // SP HIGH
-                0x00, // PC LOW
-                0x01, // PC HIGH
-            ]
+            registers: BootState::Dmg.registers()
         };
-        let cycles_per_second = 104826;
         let mut new_cpu = Cpu { 
             registers: regs,
-            memory: system_memory,
             ime: false,
             enable_ime_this_frame: false,
             enable_ime_next_frame: false,
             halted: false,
             stopped: false,
-            cycles_per_second,
+            double_speed: false,
+            tracer: None,
+            cycles: 0,
+            instructions: 0,
         };
+        let mut context = new_cpu.with_memory(memory);
         // No bootrom, set initial state of hardware registers to values in DMG column here https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF00), ByteImmediate::new(0xCF));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF01), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF02), ByteImmediate::new(0x7E));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF04), ByteImmediate::new(0xAB));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF05), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF06), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF07), ByteImmediate::new(0xF8));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF0F), ByteImmediate::new(0xE1));
-        // TODO: Audio registers omitted for brevity...
-        
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF40), ByteImmediate::new(0x91));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF41), ByteImmediate::new(0x85));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF42), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF43), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF44), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF45), ByteImmediate::new(0x00));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF46), ByteImmediate::new(0xFF));
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFF47), ByteImmediate::new(0xFC));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF00), ByteImmediate::new(0xCF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF02), ByteImmediate::new(0x7E));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF04), ByteImmediate::new(0xAB));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF05), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF06), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF07), ByteImmediate::new(0xF8));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF0F), ByteImmediate::new(0xE1));
+        // Audio registers, NR10 through NR52. Nothing plays them yet but games read them back
+        context.ld_byte(ByteImmediateIndirect::new(0xFF10), ByteImmediate::new(0x80));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF11), ByteImmediate::new(0xBF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF12), ByteImmediate::new(0xF3));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF13), ByteImmediate::new(0xFF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF14), ByteImmediate::new(0xBF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF16), ByteImmediate::new(0x3F));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF17), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF18), ByteImmediate::new(0xFF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF19), ByteImmediate::new(0xBF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF1A), ByteImmediate::new(0x7F));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF1B), ByteImmediate::new(0xFF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF1C), ByteImmediate::new(0x9F));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF1D), ByteImmediate::new(0xFF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF1E), ByteImmediate::new(0xBF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF20), ByteImmediate::new(0xFF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF21), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF22), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF23), ByteImmediate::new(0xBF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF24), ByteImmediate::new(0x77));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF25), ByteImmediate::new(0xF3));
+
+        context.ld_byte(ByteImmediateIndirect::new(0xFF40), ByteImmediate::new(0x91));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF42), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF43), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF45), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF46), ByteImmediate::new(0xFF));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF47), ByteImmediate::new(0xFC));
+        // OBP0 and OBP1 are left uninitialized, they come up as whatever the 0xFF fill left
+        context.ld_byte(ByteImmediateIndirect::new(0xFF4A), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFF4B), ByteImmediate::new(0x00));
         
         // CGB registers uninitialized
-        new_cpu.ld_byte(ByteImmediateIndirect::new(0xFFFF), ByteImmediate::new(0x00));
+        context.ld_byte(ByteImmediateIndirect::new(0xFFFF), ByteImmediate::new(0x00));
+        // Except KEY1, which starts at normal speed and unarmed. The speed bit can't be written through the bus so go around it
+        context.memory.io_registers.write::<Byte>(0x00, KEY1_ADDRESS);
+        // And OPRI, which the CGB boot ROM leaves on OAM index priority
+        context.memory.io_registers.write::<Byte>(0x00, OPRI_ADDRESS);
+        // Same for STAT's mode and coincidence bits and for LY, which belong to the PPU
+        context.memory.io_registers.write::<Byte>(0x85, 0xFF41);
+        context.memory.io_registers.write::<Byte>(0x00, 0xFF44);
+        // And SB, where a bus write would go out over serial
+        context.memory.io_registers.write::<Byte>(0x00, 0xFF01);
+        // And NR52, whose channel bits belong to the APU. Channel 1 is still marked on from the boot chime
+        context.memory.io_registers.write::<Byte>(0xF1, 0xFF26);
         new_cpu
     }
 
+    pub fn with_memory<'c, 'a>(&'c mut self, memory: &'c mut MemoryMap<'a>) -> CpuContext<'c, 'a> {
+        CpuContext { cpu: self, memory }
+    }
+
+    pub fn run(&mut self, memory: &mut MemoryMap) -> u8 {
+        self.with_memory(memory).run()
+    }
+
+    // Reseed the registers as a different hardware revision's boot ROM would have left them
+    pub fn set_boot_state(&mut self, boot_state: BootState) {
+        self.registers.registers = boot_state.registers();
+    }
+
+    // A snapshot of the CPU as a JSON object, for debuggers and scripts living outside the emulator
+    pub fn state_json(&mut self) -> String {
+        let bytes = [
+            ("a", ByteRegisterName::RegA),
+            ("f", ByteRegisterName::RegF),
+            ("b", ByteRegisterName::RegB),
+            ("c", ByteRegisterName::RegC),
+            ("d", ByteRegisterName::RegD),
+            ("e", ByteRegisterName::RegE),
+            ("h", ByteRegisterName::RegH),
+            ("l", ByteRegisterName::RegL)
+        ];
+        let mut fields = bytes.iter()
+            .map(|&(name, register)| format!("\"{}\":{}", name, self.registers.read_byte(register)))
+            .collect::<Vec<String>>();
+        fields.push(format!("\"sp\":{}", self.registers.read_word(WordRegisterName::RegSP)));
+        fields.push(format!("\"pc\":{}", self.registers.read_word(WordRegisterName::RegPC)));
+        fields.push(format!("\"flags\":{{\"z\":{},\"n\":{},\"h\":{},\"c\":{}}}",
+            self.registers.check_flag(Flags::Z),
+            self.registers.check_flag(Flags::N),
+            self.registers.check_flag(Flags::H),
+            self.registers.check_flag(Flags::C)));
+        fields.push(format!("\"ime\":{}", self.ime));
+        fields.push(format!("\"halted\":{}", self.halted));
+        fields.push(format!("\"stopped\":{}", self.stopped));
+        fields.push(format!("\"double_speed\":{}", self.double_speed));
+        fields.push(format!("\"cycles\":{}", self.cycles));
+        fields.push(format!("\"instructions\":{}", self.instructions));
+        format!("{{{}}}", fields.join(","))
+    }
+
+}
+
+impl CpuContext<'_, '_> {
+
     fn service_interrupt(&mut self) -> bool {
         // Check if there are serviceable interrupts and if there are, toggle off the highest priority IF bit
         // and hand back the ISR address of the associated interrupt to jump to
         let isr_location = {
-            let mut memory = self.memory.borrow_mut();
-            let reg_if = memory.read::<Byte>(IF_REG_ADDR);
-            let reg_ie = memory.read::<Byte>(IE_REG_ADDR);
-            let has_serviceable_interrupts = self.ime && ((reg_ie & reg_if) > 0);
+            let memory = &mut *self.memory;
+            let reg_if = memory.read::<Byte>(IF_REG_ADDR) & INTERRUPT_BITS;
+            let reg_ie = memory.read::<Byte>(IE_REG_ADDR) & INTERRUPT_BITS;
+            let has_serviceable_interrupts = self.cpu.ime && ((reg_ie & reg_if) > 0);
             if has_serviceable_interrupts {
                 const PLACE: u8 = 0x01;
                 let (new_if, isr_location) = if ((reg_if & reg_ie) & (PLACE << 0)) > 0 {
@@ -495,6 +641,9 @@ impl<'a> Cpu<'a> {
         // There's a fun case where stop/halt should be broken even if IME is disabled
         match isr_location {
             InterruptOutcome::Service(address) => {
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.on_interrupt(address);
+                }
                 self.ime = false;
                 self.call(address, ConditionCodes::NA);
                 true
@@ -506,7 +655,7 @@ impl<'a> Cpu<'a> {
     }
 
     fn tick_timer(&mut self) -> () {
-        let mut mem = self.memory.borrow_mut();
+        let mem = &mut *self.memory;
         let fire_interrupt_ready_status = mem.timer.tick();
         if fire_interrupt_ready_status {
             let if_value: Byte = mem.io_registers.read(0xFF0F);
@@ -514,36 +663,57 @@ impl<'a> Cpu<'a> {
         }
     }
 
+    // Hands what the last instruction did over the bus to the tracer
+    fn report_memory_accesses(&mut self) {
+        let accesses = self.memory.access_log.as_mut().map(std::mem::take).unwrap_or_default();
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_memory_accesses(&accesses);
+        }
+        // Give the buffer back so it isn't reallocated every instruction
+        if let Some(log) = self.memory.access_log.as_mut() {
+            *log = accesses;
+        }
+    }
+
+    // Captures the registers and the bytes at PC, for handing off to a tracer or showing in a debugger
+    pub fn state(&mut self) -> CpuState {
+        let pc = self.registers.read_word(WordRegisterName::RegPC);
+        CpuState {
+            a: self.registers.read_byte(ByteRegisterName::RegA),
+            f: self.registers.read_byte(ByteRegisterName::RegF),
+            b: self.registers.read_byte(ByteRegisterName::RegB),
+            c: self.registers.read_byte(ByteRegisterName::RegC),
+            d: self.registers.read_byte(ByteRegisterName::RegD),
+            e: self.registers.read_byte(ByteRegisterName::RegE),
+            h: self.registers.read_byte(ByteRegisterName::RegH),
+            l: self.registers.read_byte(ByteRegisterName::RegL),
+            sp: self.registers.read_word(WordRegisterName::RegSP),
+            pc,
+            pc_memory: core::array::from_fn(|i| self.memory.peek(pc.wrapping_add(i as Address))),
+        }
+    }
+
+    // On CGB, STOP with KEY1 armed toggles double speed mode instead of stopping. Reports whether a switch happened
+    fn switch_speed(&mut self) -> bool {
+        let memory = &mut *self.memory;
+        let armed = (memory.io_registers.read::<Byte>(KEY1_ADDRESS) & 0x01) > 0;
+        if memory.mode == GameboyMode::Cgb && armed {
+            self.cpu.double_speed = !self.cpu.double_speed;
+            let speed_bit: Byte = if self.cpu.double_speed { 0x80 } else { 0x00 };
+            memory.io_registers.write(speed_bit, KEY1_ADDRESS);
+            true
+        }
+        else {
+            false
+        }
+    }
+
     pub fn run(&mut self) -> u8 {
         const NO_WORK: u8 = 0;
 
-        // log state
-        /*
-        {
-            let mut mem = self.memory.borrow_mut();
-            let dbg_pc = self.registers.read_word(WordRegisterName::RegPC);
-            println!("A: {} F: {} B: {} C: {} D: {} E: {} H: {} L: {} SP: {} PC: 00:{} ({} {} {} {})",
-                self.registers.read_byte(ByteRegisterName::RegA).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegF).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegB).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegC).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegD).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegE).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegH).as_hex(),
-                self.registers.read_byte(ByteRegisterName::RegL).as_hex(),
-                self.registers.read_word(WordRegisterName::RegSP).as_hex(),
-                dbg_pc.as_hex(), 
-                mem.read::<Byte>(dbg_pc).as_hex(),
-                mem.read::<Byte>(dbg_pc + 1).as_hex(),
-                mem.read::<Byte>(dbg_pc + 2).as_hex(),
-                mem.read::<Byte>(dbg_pc + 3).as_hex()
-            );
-        }
-        */
-        // Service interrupts and escape the most common HALT case
-        if self.service_interrupt() {
+        // Service interrupts and escape the most common HALT case. Only the joypad gets the cpu out of STOP, further down
+        if !self.stopped && self.service_interrupt() {
             self.halted = false;
-            self.stopped = false;
             // Boot processing back to the top, throw out this cycle and restart on the interrupt
             return NO_WORK
         }
@@ -554,7 +724,23 @@ impl<'a> Cpu<'a> {
                 self.enable_ime_next_frame = false;
                 self.enable_ime_this_frame = true;
             }
+            if self.tracer.is_some() {
+                let state = self.state();
+                if let Some(tracer) = self.tracer.as_mut() {
+                    tracer.on_instruction(&state);
+                }
+            }
+            let tracing_memory = match self.memory.access_log.as_mut() {
+                Some(log) => {
+                    log.clear();
+                    true
+                }
+                None => false
+            };
             let step_info = self.step();
+            if tracing_memory {
+                self.report_memory_accesses();
+            }
             let cost = match step_info {
                 StepResult::StepSideEffect(cost, effect) => {
                     match effect {
@@ -562,7 +748,11 @@ impl<'a> Cpu<'a> {
                             self.halted = true;
                         }
                         SideEffect::Stop => {
-                            self.stopped = true;
+                            // STOP resets DIV whether it stops or switches speed
+                            self.memory.timer.write_divider(0);
+                            if !self.switch_speed() {
+                                self.stopped = true;
+                            }
                         }
                         SideEffect::EnableInterrupt => {
                             self.ime = true
@@ -586,15 +776,29 @@ impl<'a> Cpu<'a> {
                 self.ime = true;
                 self.enable_ime_this_frame = false;
             }
+            self.cycles += cost as u64;
+            self.instructions += 1;
             return cost
         }
         // HALT handling goes here for cases where IME is disabled
         else {
+            // STOP halts everything, timer included, until a button in a selected group goes down. Interrupts don't matter
+            if self.stopped {
+                let pressed = (self.memory.joypad.read() & 0x0F) != 0x0F;
+                if pressed {
+                    self.stopped = false;
+                }
+                else {
+                    self.cycles += 1;
+                }
+                return NO_WORK
+            }
             if self.halted && !self.ime {
-                let mut map = self.memory.borrow_mut();
-                let reg_if = map.read::<Byte>(IF_REG_ADDR);
-                let reg_ie = map.read::<Byte>(IE_REG_ADDR);
+                let map = &mut *self.memory;
+                let reg_if = map.read::<Byte>(IF_REG_ADDR) & INTERRUPT_BITS;
+                let reg_ie = map.read::<Byte>(IE_REG_ADDR) & INTERRUPT_BITS;
                 if (reg_if & reg_ie) > 0 {
+                    // Wake without servicing. IF is left alone and the next run() carries on from the instruction after HALT
                     self.halted = false;
                     return NO_WORK
                 }
@@ -603,7 +807,206 @@ impl<'a> Cpu<'a> {
             for _ in 0..4 {
                 self.tick_timer()
             }
+            self.cycles += 1;
             return NO_WORK
         } 
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_gb::tests::test_memory;
+
+    #[test]
+    fn af_reads_a_high_and_f_low() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_byte(ByteRegisterName::RegA, 0x12);
+        cpu.registers.write_byte(ByteRegisterName::RegF, 0xB0);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegAF), 0x12B0);
+    }
+
+    #[test]
+    fn register_pairs_read_the_first_register_high() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        let pairs = [
+            (WordRegisterName::RegBC, ByteRegisterName::RegB, ByteRegisterName::RegC, 0xB0C0),
+            (WordRegisterName::RegDE, ByteRegisterName::RegD, ByteRegisterName::RegE, 0xD0E0),
+            (WordRegisterName::RegHL, ByteRegisterName::RegH, ByteRegisterName::RegL, 0x8010)
+        ];
+        for (pair, high, low, value) in pairs {
+            cpu.registers.write_byte(high, 0x12);
+            cpu.registers.write_byte(low, 0x34);
+            assert_eq!(cpu.registers.read_word(pair), 0x1234);
+            cpu.registers.write_word(pair, value);
+            assert_eq!(cpu.registers.read_byte(high), (value >> 8) as Byte);
+            assert_eq!(cpu.registers.read_byte(low), value as Byte);
+        }
+        // Each pair kept its own value through the writes to the others
+        for (pair, _, _, value) in pairs {
+            assert_eq!(cpu.registers.read_word(pair), value);
+        }
+    }
+
+    #[test]
+    fn push_pop_af_round_trips_with_f_low_nibble_cleared() {
+        // PUSH AF, POP AF
+        let mut memory_data = test_memory(&[0xF5, 0xF1]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_word(WordRegisterName::RegAF, 0x12B0);
+        cpu.with_memory(&mut memory).step();
+        // Dirty the low nibble of F while it sits on the stack, POP has to drop it again
+        let sp = cpu.registers.read_word(WordRegisterName::RegSP);
+        assert_eq!(memory.read::<Word>(sp), 0x12B0);
+        memory.write::<Byte>(0xBF, sp);
+        cpu.with_memory(&mut memory).step();
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegAF), 0x12B0);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegSP), 0xFFFE);
+    }
+
+    #[test]
+    fn rlca_clears_z_but_cb_rlc_sets_it() {
+        // RLCA, RLC B
+        let mut memory_data = test_memory(&[0x07, 0xCB, 0x00]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_byte(ByteRegisterName::RegA, 0x00);
+        cpu.registers.write_byte(ByteRegisterName::RegB, 0x00);
+        cpu.registers.set_flag(Flags::Z, true);
+        cpu.with_memory(&mut memory).step();
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegA), 0x00);
+        assert!(!cpu.registers.check_flag(Flags::Z));
+        cpu.with_memory(&mut memory).step();
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegB), 0x00);
+        assert!(cpu.registers.check_flag(Flags::Z));
+    }
+
+    #[test]
+    fn boot_states_set_documented_registers() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        // AF, BC, DE, HL from https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+        let documented = [
+            (BootState::Dmg0, [0x0100, 0xFF13, 0x00C1, 0x8403]),
+            (BootState::Dmg, [0x01B0, 0x0013, 0x00D8, 0x014D]),
+            (BootState::Mgb, [0xFFB0, 0x0013, 0x00D8, 0x014D]),
+            (BootState::Sgb, [0x0100, 0x0014, 0x0000, 0xC060]),
+            (BootState::Cgb, [0x1180, 0x0000, 0xFF56, 0x000D]),
+            (BootState::Agb, [0x1100, 0x0100, 0xFF56, 0x000D])
+        ];
+        for (boot_state, [af, bc, de, hl]) in documented {
+            cpu.set_boot_state(boot_state);
+            assert_eq!(cpu.registers.read_word(WordRegisterName::RegAF), af, "{:?}", boot_state);
+            assert_eq!(cpu.registers.read_word(WordRegisterName::RegBC), bc, "{:?}", boot_state);
+            assert_eq!(cpu.registers.read_word(WordRegisterName::RegDE), de, "{:?}", boot_state);
+            assert_eq!(cpu.registers.read_word(WordRegisterName::RegHL), hl, "{:?}", boot_state);
+            assert_eq!(cpu.registers.read_word(WordRegisterName::RegSP), 0xFFFE, "{:?}", boot_state);
+            assert_eq!(cpu.registers.read_word(WordRegisterName::RegPC), 0x0100, "{:?}", boot_state);
+        }
+    }
+
+    #[test]
+    fn state_json_after_a_known_instruction() {
+        // LD A,0x42
+        let mut memory_data = test_memory(&[0x3E, 0x42]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.run(&mut memory);
+        assert_eq!(cpu.state_json(), concat!(
+            "{\"a\":66,\"f\":176,\"b\":0,\"c\":19,\"d\":0,\"e\":216,\"h\":1,\"l\":77,\"sp\":65534,\"pc\":258,",
+            "\"flags\":{\"z\":true,\"n\":false,\"h\":true,\"c\":true},",
+            "\"ime\":false,\"halted\":false,\"stopped\":false,\"double_speed\":false,\"cycles\":2,\"instructions\":1}"
+        ));
+    }
+
+    #[test]
+    fn f_low_nibble_is_cleared_on_every_write() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_word(WordRegisterName::RegAF, 0x00FF);
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegF), 0xF0);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegAF), 0x00F0);
+        cpu.registers.write_byte(ByteRegisterName::RegF, 0x0F);
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegF), 0x00);
+    }
+
+    #[test]
+    fn ld_a16_sp_stores_sp_little_endian_in_5_cycles() {
+        // LD (0xC000),SP, then LD (0xDFFF),SP so the high byte lands on the first byte of echo RAM
+        let mut memory_data = test_memory(&[0x08, 0x00, 0xC0, 0x08, 0xFF, 0xDF]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_word(WordRegisterName::RegSP, 0xBEEF);
+        assert_eq!(cpu.run(&mut memory), 5);
+        assert_eq!(memory.read::<Byte>(0xC000), 0xEF);
+        assert_eq!(memory.read::<Byte>(0xC001), 0xBE);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegPC), 0x0103);
+        assert_eq!(cpu.run(&mut memory), 5);
+        assert_eq!(memory.read::<Byte>(0xDFFF), 0xEF);
+        assert_eq!(memory.read::<Byte>(0xE000), 0xBE);
+    }
+
+    #[test]
+    fn halt_with_ime_clear_wakes_on_the_timer_without_servicing_it() {
+        // DI; HALT; INC B; INC B
+        let mut memory_data = test_memory(&[0xF3, 0x76, 0x04, 0x04]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_byte(ByteRegisterName::RegB, 0);
+        // Timer interrupt enabled, TIMA a few counts from overflow at the fastest rate
+        memory.write(0x00 as Byte, 0xFF0F);
+        memory.write(0x04 as Byte, 0xFFFF);
+        memory.write(0xF0 as Byte, 0xFF05);
+        memory.write(0x05 as Byte, 0xFF07);
+        cpu.run(&mut memory);
+        cpu.run(&mut memory);
+        assert!(cpu.halted);
+        // The timer has to keep counting while halted for this to ever end
+        let mut halted_runs = 0;
+        while cpu.halted {
+            cpu.run(&mut memory);
+            halted_runs += 1;
+            assert!(halted_runs < 10000, "HALT never woke");
+        }
+        assert!(halted_runs > 1);
+        // The next run picks up after the HALT instead of jumping to 0x0050, and IF is left for the game to deal with
+        assert!(cpu.run(&mut memory) > 0);
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegB), 1);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegPC), 0x0103);
+        assert_eq!(memory.read::<Byte>(0xFF0F) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn inc_and_dec_sp_wrap_and_leave_flags_alone() {
+        // INC SP; DEC SP
+        let mut memory_data = test_memory(&[0x33, 0x3B]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.registers.write_word(WordRegisterName::RegSP, 0xFFFF);
+        cpu.registers.write_byte(ByteRegisterName::RegF, 0xA0);
+        cpu.run(&mut memory);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegSP), 0x0000);
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegF), 0xA0);
+        cpu.run(&mut memory);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegSP), 0xFFFF);
+        assert_eq!(cpu.registers.read_byte(ByteRegisterName::RegF), 0xA0);
+    }
+
+    #[test]
+    fn pc_wraps_past_0xffff() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu = Cpu::new(&mut memory);
+        // NOP sitting in IE
+        memory.write(0x00 as Byte, 0xFFFF);
+        cpu.registers.write_word(WordRegisterName::RegPC, 0xFFFF);
+        cpu.run(&mut memory);
+        assert_eq!(cpu.registers.read_word(WordRegisterName::RegPC), 0x0000);
+    }
+}
@@ -6,20 +6,20 @@ use crate::processor::cpu::*;
 use crate::processor::cpu::ByteRegisterName::*;
 use crate::processor::cpu::WordRegisterName::*;
 
-impl<'a> Cpu<'a> {
+impl CpuContext<'_, '_> {
     fn byte_operand(&mut self) -> Byte {
         let address = self.registers.read_word(WordRegisterName::RegPC);
-        let mut memory = self.memory.borrow_mut();
-        memory.read::<Byte>(address + 1)
+        let memory = &mut *self.memory;
+        memory.read::<Byte>(address.wrapping_add(1))
     } 
     fn word_operand(&mut self) -> Word {
         let address = self.registers.read_word(WordRegisterName::RegPC);
-        let mut memory = self.memory.borrow_mut();
-        memory.read::<Word>(address + 1)
+        let memory = &mut *self.memory;
+        memory.read::<Word>(address.wrapping_add(1))
     } 
     fn fetch (&mut self) -> Byte {
         let address = self.registers.read_word(WordRegisterName::RegPC);
-        let mut memory = self.memory.borrow_mut();
+        let memory = &mut *self.memory;
         memory.read::<Byte>(address)
     }
 
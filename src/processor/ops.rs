@@ -6,7 +6,7 @@ use crate::memory_gb::MemoryRegion;
 use crate::processor::cpu::*;
 
 
-impl<'a> Cpu<'a> {
+impl CpuContext<'_, '_> {
     pub fn ld_byte<T: WriteByte, U: ReadByte>(&mut self, dest: T, src: U) {
         let source_value = src.read_byte(self);
         dest.write_byte(self, source_value);
@@ -47,28 +47,21 @@ impl<'a> Cpu<'a> {
 
     pub fn push(&mut self, register: WordRegisterName) {
         // self.registers.sp = (self.registers.sp.from_gb_endian() - 2).to_gb_endian();
-        let new_stack_pointer = self.registers.read_word(WordRegisterName::RegSP) - 2;
+        let new_stack_pointer = self.registers.read_word(WordRegisterName::RegSP).wrapping_sub(2);
         self.registers.write_word(WordRegisterName::RegSP, new_stack_pointer);
         let address = new_stack_pointer;
         let contents = self.registers.read_word(register);
-        let mut map = self.memory.borrow_mut();
+        let map = &mut *self.memory;
         map.write::<Word>(contents, address);
         self.registers.write_word(WordRegisterName::RegSP, new_stack_pointer);
     }
 
     pub fn pop(&mut self, register: WordRegisterName) {
         let address = self.registers.read_word(WordRegisterName::RegSP);
-        let mut map = self.memory.borrow_mut();
+        let map = &mut *self.memory;
         let contents = map.read::<Word>(address);
-        match register {
-            WordRegisterName::RegAF => {
-                // The flag register must overwrite its 4 lowest bits with 0 to be compliant
-                self.registers.write_word(register, contents & 0xFFF0);
-            }
-            _ => {
-                self.registers.write_word(register, contents);
-            }
-        }
+        // Popping into AF relies on the register bank masking off the 4 lowest bits of F
+        self.registers.write_word(register, contents);
         self.registers.write_word(WordRegisterName::RegSP, address.wrapping_add(2));
     }
 
@@ -108,7 +101,7 @@ impl<'a> Cpu<'a> {
         let sp_value = self.registers.read_word(WordRegisterName::RegSP);
         let sp_upper = sp_value & 0xFF00;
         let sp_lower = sp_value & 0x00FF;
-        let abs_offset = offset.abs() as Byte;
+        let abs_offset = offset.unsigned_abs();
         let (sum, half_carry, carry) = if offset < 0 {
             // hack, use 2s complement addition for setting carry/half carry, subtract absolute value for numerical value
             let (_, _, _, true_half_carry, true_carry) = self.byte_addition(sp_lower as Byte, !(abs_offset)+1, false);
@@ -162,7 +155,8 @@ impl<'a> Cpu<'a> {
         let result = lhs.wrapping_sub(rhs).wrapping_sub(prior_carry);
         let zero = result == 0;
         let negate = true;
-        let half_carry = ((lhs & 0xf).wrapping_sub(rhs & 0xf).wrapping_sub(prior_carry) & 0x10) != 0;
+        // Borrows out of the low nibble when it can't cover the operand's low nibble plus the incoming carry, same as the full byte below
+        let half_carry = (lhs & 0xf) < (rhs & 0xf) + prior_carry;
         let carry = (lhs as u16 & 0xff) < (rhs as u16 & 0xff).wrapping_add(prior_carry as u16);
         (result as Byte, zero, negate, half_carry, carry)
     }
@@ -535,4 +529,176 @@ impl<'a> Cpu<'a> {
 
         self.registers.write_byte(ByteRegisterName::RegA, result);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_gb::MemoryMap;
+    use crate::memory_gb::tests::test_memory;
+
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Add, Adc, Sub, Sbc, And, Or, Xor, Cp,
+        Inc, Dec,
+        Rlc, Rrc, Rl, Rr, Sla, Sra, Srl, Swap,
+        Rlca, Rrca, Rla, Rra
+    }
+
+    const TWO_OPERAND_OPS: [Op; 8] = [Op::Add, Op::Adc, Op::Sub, Op::Sbc, Op::And, Op::Or, Op::Xor, Op::Cp];
+    const ONE_OPERAND_OPS: [Op; 14] = [
+        Op::Inc, Op::Dec,
+        Op::Rlc, Op::Rrc, Op::Rl, Op::Rr, Op::Sla, Op::Sra, Op::Srl, Op::Swap,
+        Op::Rlca, Op::Rrca, Op::Rla, Op::Rra
+    ];
+
+    fn flags(z: bool, n: bool, h: bool, c: bool) -> Byte {
+        ((z as Byte) << 7) | ((n as Byte) << 6) | ((h as Byte) << 5) | ((c as Byte) << 4)
+    }
+
+    // A and F after the op, worked out straight from the Pandocs rather than from anything in ops.rs.
+    // a is the accumulator (or the register being rotated), b the second operand if there is one
+    fn reference(op: Op, a: Byte, b: Byte, carry: bool) -> (Byte, Byte) {
+        let carry_in = carry as Byte;
+        match op {
+            Op::Add | Op::Adc => {
+                let carry_in = if let Op::Adc = op { carry_in } else { 0 };
+                let sum = a as u16 + b as u16 + carry_in as u16;
+                let result = sum as Byte;
+                (result, flags(result == 0, false, (a & 0xF) + (b & 0xF) + carry_in > 0xF, sum > 0xFF))
+            }
+            Op::Sub | Op::Sbc | Op::Cp => {
+                let carry_in = if let Op::Sbc = op { carry_in } else { 0 };
+                let difference = a as i16 - b as i16 - carry_in as i16;
+                let result = difference as Byte;
+                let half_borrow = ((a & 0xF) as i16) - ((b & 0xF) as i16) - (carry_in as i16) < 0;
+                let kept = if let Op::Cp = op { a } else { result };
+                (kept, flags(result == 0, true, half_borrow, difference < 0))
+            }
+            Op::And => (a & b, flags(a & b == 0, false, true, false)),
+            Op::Or => (a | b, flags(a | b == 0, false, false, false)),
+            Op::Xor => (a ^ b, flags(a ^ b == 0, false, false, false)),
+            // Carry is left alone
+            Op::Inc => {
+                let result = a.wrapping_add(1);
+                (result, flags(result == 0, false, (a & 0xF) == 0xF, carry))
+            }
+            Op::Dec => {
+                let result = a.wrapping_sub(1);
+                (result, flags(result == 0, true, (a & 0xF) == 0, carry))
+            }
+            Op::Rlc => {
+                let result = a.rotate_left(1);
+                (result, flags(result == 0, false, false, a & 0x80 != 0))
+            }
+            Op::Rrc => {
+                let result = a.rotate_right(1);
+                (result, flags(result == 0, false, false, a & 0x01 != 0))
+            }
+            Op::Rl => {
+                let result = (a << 1) | carry_in;
+                (result, flags(result == 0, false, false, a & 0x80 != 0))
+            }
+            Op::Rr => {
+                let result = (a >> 1) | (carry_in << 7);
+                (result, flags(result == 0, false, false, a & 0x01 != 0))
+            }
+            Op::Sla => {
+                let result = a << 1;
+                (result, flags(result == 0, false, false, a & 0x80 != 0))
+            }
+            Op::Sra => {
+                let result = (a >> 1) | (a & 0x80);
+                (result, flags(result == 0, false, false, a & 0x01 != 0))
+            }
+            Op::Srl => {
+                let result = a >> 1;
+                (result, flags(result == 0, false, false, a & 0x01 != 0))
+            }
+            Op::Swap => {
+                let result = a.rotate_left(4);
+                (result, flags(result == 0, false, false, false))
+            }
+            // The accumulator rotates are the CB ones with Z always cleared
+            Op::Rlca => { let (result, f) = reference(Op::Rlc, a, b, carry); (result, f & 0x7F) }
+            Op::Rrca => { let (result, f) = reference(Op::Rrc, a, b, carry); (result, f & 0x7F) }
+            Op::Rla => { let (result, f) = reference(Op::Rl, a, b, carry); (result, f & 0x7F) }
+            Op::Rra => { let (result, f) = reference(Op::Rr, a, b, carry); (result, f & 0x7F) }
+        }
+    }
+
+    // Runs the op on A, with B as the second operand, and hands back A and F
+    fn run(cpu: &mut CpuContext, op: Op, a: Byte, b: Byte, carry: bool) -> (Byte, Byte) {
+        cpu.registers.write_byte(ByteRegisterName::RegA, a);
+        cpu.registers.write_byte(ByteRegisterName::RegB, b);
+        cpu.registers.write_byte(ByteRegisterName::RegF, flags(false, false, false, carry));
+        let b = ByteRegister::new(ByteRegisterName::RegB);
+        let a = ByteRegister::new(ByteRegisterName::RegA);
+        match op {
+            Op::Add => cpu.add_byte(b, false),
+            Op::Adc => cpu.add_byte(b, true),
+            Op::Sub => cpu.sub_byte(b, false),
+            Op::Sbc => cpu.sub_byte(b, true),
+            Op::And => cpu.and(b),
+            Op::Or => cpu.or(b),
+            Op::Xor => cpu.xor(b),
+            Op::Cp => cpu.cp(b),
+            Op::Inc => cpu.inc_byte(a),
+            Op::Dec => cpu.dec_byte(a),
+            Op::Rlc => cpu.rlc(a),
+            Op::Rrc => cpu.rrc(a),
+            Op::Rl => cpu.rl(a),
+            Op::Rr => cpu.rr(a),
+            Op::Sla => cpu.sla(a),
+            Op::Sra => cpu.sra(a),
+            Op::Srl => cpu.srl(a),
+            Op::Swap => cpu.swap(a),
+            Op::Rlca => cpu.rlca(),
+            Op::Rrca => cpu.rrca(),
+            Op::Rla => cpu.rla(),
+            Op::Rra => cpu.rra()
+        }
+        (cpu.registers.read_byte(ByteRegisterName::RegA), cpu.registers.read_byte(ByteRegisterName::RegF))
+    }
+
+    fn check(ops: &[Op], operands: &[Byte]) {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu_state = Cpu::new(&mut memory);
+        let mut cpu = cpu_state.with_memory(&mut memory);
+        for &op in ops {
+            for a in 0..=0xFF {
+                for &b in operands {
+                    for carry in [false, true] {
+                        assert_eq!(run(&mut cpu, op, a, b, carry), reference(op, a, b, carry),
+                            "{:?} a={:#04x} b={:#04x} carry={} gave (A, F) on the left", op, a, b, carry);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn two_operand_ops_match_reference() {
+        check(&TWO_OPERAND_OPS, &(0..=0xFF).collect::<Vec<Byte>>());
+    }
+
+    #[test]
+    fn inc_dec_rotates_and_shifts_match_reference() {
+        check(&ONE_OPERAND_OPS, &[0x00]);
+    }
+
+    #[test]
+    fn carry_in_counts_towards_half_carry_for_adc_and_sbc() {
+        let mut memory_data = test_memory(&[]);
+        let mut memory = MemoryMap::new(&mut memory_data);
+        let mut cpu_state = Cpu::new(&mut memory);
+        let mut cpu = cpu_state.with_memory(&mut memory);
+        // 0x0F + 0x00 + 1 only carries out of bit 3 because of the carry in
+        assert_eq!(run(&mut cpu, Op::Adc, 0x0F, 0x00, true), (0x10, flags(false, false, true, false)));
+        // 0x10 - 0x00 - 1 only borrows from bit 4 because of the carry in
+        assert_eq!(run(&mut cpu, Op::Sbc, 0x10, 0x00, true), (0x0F, flags(false, true, true, false)));
+        // Without it neither one touches H
+        assert_eq!(run(&mut cpu, Op::Adc, 0x0F, 0x00, false), (0x0F, flags(false, false, false, false)));
+        assert_eq!(run(&mut cpu, Op::Sbc, 0x10, 0x00, false), (0x10, flags(false, true, false, false)));
+    }
+}
@@ -0,0 +1,164 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::memory_gb::Byte;
+
+// Where bytes written to the serial port end up. Mostly for test ROMs that report over serial, a link partner is a SerialLink
+pub trait SerialSink: Send {
+    fn write_byte(&mut self, byte: Byte);
+}
+
+// Prints serial output as text. Bytes are held until a newline so stdout isn't locked for every one of them
+#[derive(Default)]
+pub struct StdoutSink {
+    line: Vec<Byte>
+}
+
+impl StdoutSink {
+    pub fn new() -> StdoutSink {
+        StdoutSink::default()
+    }
+
+    fn flush_line(&mut self) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(String::from_utf8_lossy(&self.line).as_bytes());
+        let _ = stdout.flush();
+        self.line.clear();
+    }
+}
+
+impl SerialSink for StdoutSink {
+    fn write_byte(&mut self, byte: Byte) {
+        self.line.push(byte);
+        if byte == b'\n' {
+            self.flush_line();
+        }
+    }
+}
+
+// Whatever didn't end in a newline still gets printed
+impl Drop for StdoutSink {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.flush_line();
+        }
+    }
+}
+
+// Keeps every byte instead of printing it. Clones share the same buffer, so one handle can be kept to read back
+// what was collected by a sink that has been handed off to the memory map
+#[derive(Clone, Default)]
+pub struct CollectingSink {
+    bytes: Arc<Mutex<Vec<Byte>>>
+}
+
+impl CollectingSink {
+    pub fn new() -> CollectingSink {
+        CollectingSink::default()
+    }
+
+    pub fn bytes(&self) -> Vec<Byte> {
+        self.bytes.lock().unwrap().clone()
+    }
+
+    // Everything collected so far as text, for test ROMs that print their results
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes.lock().unwrap()).into_owned()
+    }
+}
+
+impl SerialSink for CollectingSink {
+    fn write_byte(&mut self, byte: Byte) {
+        self.bytes.lock().unwrap().push(byte);
+    }
+}
+
+// The other end of a link cable. A transfer swaps SB with the partner's, one byte each way
+pub trait SerialLink: Send {
+    // This side drives the clock. Sends a byte and waits for the partner's
+    fn exchange(&mut self, outgoing: Byte) -> Byte;
+    // The partner drives the clock. Hands back its byte if it has clocked one over, answering with ours
+    fn poll(&mut self, outgoing: Byte) -> Option<Byte>;
+}
+
+// How long the clock driving side waits on the partner before counting it as gone
+const LINK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A link cable to another emulator over TCP. The side driving the clock sends its byte and blocks until the reply,
+// the other side answers whenever a byte turns up while it has a transfer waiting.
+// Once the partner goes away every transfer reads 0xFF, the same as with nothing plugged in
+pub struct TcpLink {
+    stream: Option<TcpStream>
+}
+
+impl TcpLink {
+    pub fn new(stream: TcpStream) -> TcpLink {
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_read_timeout(Some(LINK_TIMEOUT));
+        TcpLink { stream: Some(stream) }
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> std::io::Result<TcpLink> {
+        Ok(TcpLink::new(TcpStream::connect(address)?))
+    }
+
+    // Waits for the partner to connect
+    pub fn listen<A: ToSocketAddrs>(address: A) -> std::io::Result<TcpLink> {
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        Ok(TcpLink::new(stream))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn exchange_with(stream: &mut TcpStream, outgoing: Byte) -> std::io::Result<Byte> {
+        stream.set_nonblocking(false)?;
+        stream.write_all(&[outgoing])?;
+        let mut incoming = [0];
+        stream.read_exact(&mut incoming)?;
+        Ok(incoming[0])
+    }
+
+    fn poll_with(stream: &mut TcpStream, outgoing: Byte) -> std::io::Result<Option<Byte>> {
+        stream.set_nonblocking(true)?;
+        let mut incoming = [0];
+        match stream.read(&mut incoming) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {
+                stream.set_nonblocking(false)?;
+                stream.write_all(&[outgoing])?;
+                Ok(Some(incoming[0]))
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error)
+        }
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn exchange(&mut self, outgoing: Byte) -> Byte {
+        let result = match self.stream.as_mut() {
+            Some(stream) => TcpLink::exchange_with(stream, outgoing),
+            None => return 0xFF
+        };
+        result.unwrap_or_else(|_| {
+            self.stream = None;
+            0xFF
+        })
+    }
+
+    // Without a partner nothing ever drives the clock, so the transfer just waits
+    fn poll(&mut self, outgoing: Byte) -> Option<Byte> {
+        let result = match self.stream.as_mut() {
+            Some(stream) => TcpLink::poll_with(stream, outgoing),
+            None => return None
+        };
+        result.unwrap_or_else(|_| {
+            self.stream = None;
+            None
+        })
+    }
+}
@@ -32,6 +32,12 @@ struct Divider {
     data: Word
 }
 
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
 impl Timer {
     pub fn read_divider(&mut self) -> Byte {
         self.divider.read(0xFF04)
@@ -46,8 +52,15 @@ impl Timer {
         self.control
     }
 
+    // TIMA is clocked off the falling edge of a divider bit, so clearing the divider while that bit is high
+    // counts as a falling edge and bumps TIMA on the spot
     pub fn write_divider(&mut self, value: Byte) {
-        self.divider.write(value, 0xFF04)
+        let timer_mask = self.control_mask();
+        let selected_bit_high = (self.divider.full_read() & timer_mask) > 0;
+        self.divider.write(value, 0xFF04);
+        if selected_bit_high && ((self.control & 0x4) > 0) {
+            self.increment_counter();
+        }
     }
     pub fn write_counter(&mut self, value: Byte) {
         self.counter = value
@@ -81,17 +94,22 @@ impl Timer {
         // In short, the timer counter increments on a falling edge of the divider bits
         let timer_counter_to_tick = (timer_mask & delta & pre_tick) > 0;
         if timer_counter_to_tick && ((self.control & 0x4) > 0) {
-            if self.counter == 0xFF {
-                self.overflowing = true;
-                self.counter = 0;
-            }
-            else {
-                self.counter += 1;
-            }
+            self.increment_counter();
         }
         fire_interrupt_ready_status
     }
 
+    // Overflow reloads TIMA from TMA and raises the interrupt one tick late, see tick
+    fn increment_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.overflowing = true;
+            self.counter = 0;
+        }
+        else {
+            self.counter += 1;
+        }
+    }
+
     fn control_mask(&mut self) -> Word {
         match self.control & 0x3 {
             0 => 1 << 9,
@@ -121,3 +139,36 @@ pub struct Timer {
     modulo: Byte,
     control: Byte
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writing_div_with_the_selected_bit_high_bumps_tima_once() {
+        let mut timer = Timer::new();
+        // Enabled, clocked off divider bit 3
+        timer.write_control(0x05);
+        for _ in 0..8 {
+            timer.tick();
+        }
+        assert_eq!(timer.read_counter(), 0);
+        timer.write_divider(0x12);
+        assert_eq!(timer.read_counter(), 1);
+        assert_eq!(timer.read_divider(), 0);
+        // Bit 3 is low right after the reset, so a second write does nothing
+        timer.write_divider(0x00);
+        assert_eq!(timer.read_counter(), 1);
+        for _ in 0..16 {
+            timer.tick();
+        }
+        assert_eq!(timer.read_counter(), 2);
+        // No glitch with the timer disabled
+        for _ in 0..8 {
+            timer.tick();
+        }
+        timer.write_control(0x01);
+        timer.write_divider(0x00);
+        assert_eq!(timer.read_counter(), 2);
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use crate::memory_gb::Address;
+
+// Labels from an RGBDS .sym file, looked up by address. Lines look like "01:4000 Main.loop", bank then address in hex.
+// Banks aren't told apart, since nothing outside the cart knows which ROM bank is switched in. Where banked code reuses
+// an address, the first label listed for it wins
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    labels: HashMap<Address, String>
+}
+
+impl SymbolTable {
+    // Blank lines and ; comments are skipped, anything else that doesn't parse is an error naming the line
+    pub fn parse(text: &str) -> Result<SymbolTable, String> {
+        let mut labels = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (address, label) = Self::parse_line(line).map_err(|error| format!("Line {}: {}", index + 1, error))?;
+            labels.entry(address).or_insert(label);
+        }
+        Ok(SymbolTable { labels })
+    }
+
+    fn parse_line(line: &str) -> Result<(Address, String), String> {
+        let (location, label) = line.split_once(char::is_whitespace).ok_or("expected a bank:address and a label")?;
+        let (bank, address) = location.split_once(':').ok_or_else(|| format!("{} isn't a bank:address", location))?;
+        u16::from_str_radix(bank, 16).map_err(|_| format!("{} isn't a hex bank number", bank))?;
+        let address = Address::from_str_radix(address, 16).map_err(|_| format!("{} isn't a hex address", address))?;
+        Ok((address, String::from(label.trim())))
+    }
+
+    pub fn label(&self, address: Address) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_rgbds_sym_files() {
+        let symbols = SymbolTable::parse(concat!(
+            "; File generated by rgblink\n",
+            "\n",
+            "00:0150 Main\n",
+            "00:0158 Main.loop\n",
+            "01:4000 BankedCode ; trailing comment\n",
+            "02:4000 OtherBank\n",
+            "00:c000 wCounter\n"
+        )).unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.label(0x0150), Some("Main"));
+        assert_eq!(symbols.label(0x0158), Some("Main.loop"));
+        assert_eq!(symbols.label(0x4000), Some("BankedCode"));
+        assert_eq!(symbols.label(0xC000), Some("wCounter"));
+        assert_eq!(symbols.label(0x0151), None);
+    }
+
+    #[test]
+    fn bad_lines_are_errors() {
+        assert_eq!(SymbolTable::parse("00:0150 Main\nMain").unwrap_err(), "Line 2: expected a bank:address and a label");
+        assert_eq!(SymbolTable::parse("0150 Main").unwrap_err(), "Line 1: 0150 isn't a bank:address");
+        assert_eq!(SymbolTable::parse("zz:0150 Main").unwrap_err(), "Line 1: zz isn't a hex bank number");
+        assert_eq!(SymbolTable::parse("00:01G0 Main").unwrap_err(), "Line 1: 01G0 isn't a hex address");
+    }
+}
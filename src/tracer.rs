@@ -0,0 +1,220 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::memory_gb::Address;
+use crate::memory_gb::Byte;
+use crate::memory_gb::Word;
+
+// Snapshot of the CPU taken right before an instruction executes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuState {
+    pub a: Byte,
+    pub f: Byte,
+    pub b: Byte,
+    pub c: Byte,
+    pub d: Byte,
+    pub e: Byte,
+    pub h: Byte,
+    pub l: Byte,
+    pub sp: Word,
+    pub pc: Word,
+    // The instruction about to run and whatever follows it
+    pub pc_memory: [Byte; 4],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write
+}
+
+// One byte moving over the bus on behalf of the CPU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    // Position among the instruction's accesses, counting the opcode fetch as 0. This is only the order things happened in,
+    // not the M-cycle, since internal cycles that touch nothing aren't modeled
+    pub index: u8,
+    pub kind: AccessKind,
+    pub address: Address,
+    pub value: Byte
+}
+
+// Hooks for watching the system run. Everything defaults to doing nothing so implementors only pick what they care about
+pub trait Tracer: Send {
+    fn on_instruction(&mut self, _state: &CpuState) {}
+    // Called with the ISR address whenever an interrupt gets serviced
+    fn on_interrupt(&mut self, _isr_address: Address) {}
+    // Called with the new STAT mode number (0-3) and the line it changed on
+    fn on_ppu_mode_change(&mut self, _mode: u8, _ly: Byte) {}
+    // Called after each instruction with every read and write it made, in order. Only while memory tracing is on
+    fn on_memory_accesses(&mut self, _accesses: &[MemoryAccess]) {}
+}
+
+// Prints every event to stdout. Instructions are logged in the format used by Gameboy Doctor so logs can be diffed against reference ones.
+// Only installed when asked for with --trace, since printing every instruction slows things to a crawl
+pub struct PrintTracer;
+
+impl Tracer for PrintTracer {
+    fn on_instruction(&mut self, state: &CpuState) {
+        println!("{}", StateLogLine(*state));
+    }
+
+    fn on_interrupt(&mut self, isr_address: Address) {
+        println!("INT {:04X}", isr_address);
+    }
+
+    fn on_ppu_mode_change(&mut self, mode: u8, ly: Byte) {
+        println!("PPU mode {} LY {}", mode, ly);
+    }
+}
+
+// One event seen by a CollectingTracer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent {
+    Instruction(CpuState),
+    Interrupt(Address),
+    PpuModeChange { mode: u8, ly: Byte }
+}
+
+// Keeps every event in the order it happened. Clones share the same list, so one can go to the CPU and another to the PPU
+// while the events are still read back from here
+#[derive(Clone, Default)]
+pub struct CollectingTracer {
+    events: Arc<Mutex<Vec<TraceEvent>>>
+}
+
+impl CollectingTracer {
+    pub fn new() -> CollectingTracer {
+        CollectingTracer::default()
+    }
+
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Tracer for CollectingTracer {
+    fn on_instruction(&mut self, state: &CpuState) {
+        self.events.lock().unwrap().push(TraceEvent::Instruction(*state));
+    }
+
+    fn on_interrupt(&mut self, isr_address: Address) {
+        self.events.lock().unwrap().push(TraceEvent::Interrupt(isr_address));
+    }
+
+    fn on_ppu_mode_change(&mut self, mode: u8, ly: Byte) {
+        self.events.lock().unwrap().push(TraceEvent::PpuModeChange { mode, ly });
+    }
+}
+
+// A CPU state as one line of a Gameboy Doctor log, e.g.
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateLogLine(pub CpuState);
+
+impl fmt::Display for StateLogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = &self.0;
+        write!(f, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            state.a, state.f, state.b, state.c, state.d, state.e, state.h, state.l, state.sp, state.pc,
+            state.pc_memory[0], state.pc_memory[1], state.pc_memory[2], state.pc_memory[3]
+        )
+    }
+}
+
+impl FromStr for StateLogLine {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<StateLogLine, String> {
+        let mut fields = std::collections::HashMap::new();
+        for field in line.split_whitespace() {
+            let (name, value) = field.split_once(':').ok_or(format!("Field without a value: {}", field))?;
+            fields.insert(name, value);
+        }
+        let field = |name: &str| fields.get(name).copied().ok_or(format!("Missing field {}", name));
+        let byte = |name: &str| field(name).and_then(|value| Byte::from_str_radix(value, 16).map_err(|_| format!("Bad value for {}: {}", name, value)));
+        let word = |name: &str| field(name).and_then(|value| Word::from_str_radix(value, 16).map_err(|_| format!("Bad value for {}: {}", name, value)));
+        let pc_memory = field("PCMEM")?
+            .split(',')
+            .map(|value| Byte::from_str_radix(value, 16).map_err(|_| format!("Bad value for PCMEM: {}", value)))
+            .collect::<Result<Vec<Byte>, String>>()?;
+        Ok(StateLogLine(CpuState {
+            a: byte("A")?,
+            f: byte("F")?,
+            b: byte("B")?,
+            c: byte("C")?,
+            d: byte("D")?,
+            e: byte("E")?,
+            h: byte("H")?,
+            l: byte("L")?,
+            sp: word("SP")?,
+            pc: word("PC")?,
+            pc_memory: pc_memory.try_into().map_err(|_| "PCMEM should have 4 bytes".to_string())?
+        }))
+    }
+}
+
+// The first instruction where a run stopped matching a reference log. Lines count from 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Divergence {
+    pub line: usize,
+    pub expected: StateLogLine,
+    pub actual: StateLogLine
+}
+
+// How a run is doing against a reference log so far
+#[derive(Clone, Debug, Default)]
+pub struct LogComparison {
+    pub lines_expected: usize,
+    pub lines_matched: usize,
+    pub divergence: Option<Divergence>
+}
+
+impl LogComparison {
+    // Nothing left to check, either because something diverged or every line matched
+    pub fn is_finished(&self) -> bool {
+        self.divergence.is_some() || self.lines_matched == self.lines_expected
+    }
+}
+
+// Checks every instruction against a reference log and remembers the first mismatch.
+// The results are shared so they can still be read once the comparer has been handed off to the CPU
+pub struct LogComparer {
+    expected: Vec<StateLogLine>,
+    results: Arc<Mutex<LogComparison>>
+}
+
+impl LogComparer {
+    // Blank lines are skipped, anything else that doesn't parse is an error naming the line
+    pub fn new(log: &str) -> Result<LogComparer, String> {
+        let expected = log.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| line.parse::<StateLogLine>().map_err(|error| format!("Line {}: {}", index + 1, error)))
+            .collect::<Result<Vec<StateLogLine>, String>>()?;
+        let results = LogComparison { lines_expected: expected.len(), ..LogComparison::default() };
+        Ok(LogComparer { expected, results: Arc::new(Mutex::new(results)) })
+    }
+
+    pub fn results(&self) -> Arc<Mutex<LogComparison>> {
+        self.results.clone()
+    }
+}
+
+impl Tracer for LogComparer {
+    fn on_instruction(&mut self, state: &CpuState) {
+        let mut results = self.results.lock().unwrap();
+        if results.is_finished() {
+            return
+        }
+        let expected = self.expected[results.lines_matched];
+        let actual = StateLogLine(*state);
+        if expected == actual {
+            results.lines_matched += 1;
+        }
+        else {
+            results.divergence = Some(Divergence { line: results.lines_matched + 1, expected, actual });
+        }
+    }
+}